@@ -0,0 +1,67 @@
+# Velocity's shell integration for bash. It marks where prompts and commands start and end
+# (OSC 133), and tells Velocity the working directory (OSC 7).
+#
+# Velocity runs bash with --rcfile pointing here, so we start by reading the startup files bash
+# would have read instead.
+if [[ -n "$VELOCITY_BASH_LOGIN" ]]; then
+  # bash ignores --rcfile in a login shell, so Velocity starts an ordinary interactive shell and
+  # we do what a login shell would have done
+  unset VELOCITY_BASH_LOGIN
+  if [[ -r /etc/profile ]]; then
+    . /etc/profile
+  fi
+  for _velocity_profile in ~/.bash_profile ~/.bash_login ~/.profile; do
+    if [[ -r "$_velocity_profile" ]]; then
+      . "$_velocity_profile"
+      break
+    fi
+  done
+  unset _velocity_profile
+elif [[ -r ~/.bashrc ]]; then
+  . ~/.bashrc
+fi
+
+if [[ $- == *i* && -z "$_velocity_integration_loaded" ]]; then
+  _velocity_integration_loaded=1
+  _velocity_command_running=
+
+  _velocity_report_cwd() {
+    # Percent-encoded a byte at a time, like a file:// URL should be
+    local LC_ALL=C encoded= c i
+    for (( i = 0; i < ${#PWD}; i++ )); do
+      c="${PWD:i:1}"
+      case "$c" in
+        [-/._~A-Za-z0-9]) encoded+="$c" ;;
+        *) printf -v c '%%%02X' "'$c"; encoded+="$c" ;;
+      esac
+    done
+    printf '\e]7;file://%s%s\a' "$HOSTNAME" "$encoded"
+  }
+
+  _velocity_prompt_command() {
+    local exit_status=$?
+    if [[ -n "$_velocity_command_running" ]]; then
+      printf '\e]133;D;%s\a' "$exit_status"
+      _velocity_command_running=
+    fi
+    _velocity_report_cwd
+    printf '\e]133;A\a'
+    # Checked every time, in case something after us has changed $PS1
+    if [[ "$PS1" != *'\e]133;B\a'* ]]; then
+      PS1="$PS1"'\[\e]133;B\a\]'
+    fi
+    # So that the rest of $PROMPT_COMMAND still sees the command's exit status
+    return "$exit_status"
+  }
+
+  # bash has no preexec hook, but it expands $PS0 just before running a command. Looking up an
+  # element of an empty array is just a way of setting a variable there without printing it.
+  PS0='${_velocity_no_such_array[_velocity_command_running=1]}\e]133;C\a'"$PS0"
+
+  if (( BASH_VERSINFO[0] > 5 || (BASH_VERSINFO[0] == 5 && BASH_VERSINFO[1] >= 1) )); then
+    # Since 5.1, $PROMPT_COMMAND can be an array of commands
+    PROMPT_COMMAND=(_velocity_prompt_command "${PROMPT_COMMAND[@]}")
+  else
+    PROMPT_COMMAND="_velocity_prompt_command${PROMPT_COMMAND:+; $PROMPT_COMMAND}"
+  fi
+fi
@@ -0,0 +1,41 @@
+# Velocity's shell integration for fish. It marks where prompts and commands start and end
+# (OSC 133), and tells Velocity the working directory (OSC 7).
+#
+# Velocity adds our directory to $XDG_DATA_DIRS so that fish finds this file, so first we put
+# that back the way it was.
+if set -q VELOCITY_FISH_XDG_DATA_DIRS
+    set -gx XDG_DATA_DIRS $VELOCITY_FISH_XDG_DATA_DIRS
+    set -e VELOCITY_FISH_XDG_DATA_DIRS
+else
+    set -e XDG_DATA_DIRS
+end
+
+status is-interactive; or exit
+set -q __velocity_integration_loaded; and exit
+set -g __velocity_integration_loaded 1
+
+function __velocity_report_cwd --on-variable PWD
+    printf '\e]7;file://%s%s\a' $hostname (string escape --style=url -- $PWD)
+end
+
+function __velocity_mark_prompt_start --on-event fish_prompt
+    printf '\e]133;A\a'
+    # config.fish runs after us, so we wait until now to wrap the user's prompt
+    if not functions -q __velocity_original_fish_prompt
+        functions -c fish_prompt __velocity_original_fish_prompt
+        function fish_prompt
+            __velocity_original_fish_prompt
+            printf '\e]133;B\a'
+        end
+    end
+end
+
+function __velocity_mark_command_start --on-event fish_preexec
+    printf '\e]133;C\a'
+end
+
+function __velocity_mark_command_end --on-event fish_postexec
+    printf '\e]133;D;%s\a' $status
+end
+
+__velocity_report_cwd
@@ -0,0 +1,194 @@
+// Records sessions in asciicast v2 format, which asciinema (and its web player) can replay.
+// The file is one JSON header line, then one JSON array per line for each event:
+//   {"version": 2, "width": 80, "height": 25, "timestamp": 1700000000, "env": {...}}
+//   [0.248, "o", "hello\r\n"]
+// See https://docs.asciinema.org/manual/asciicast/v2/
+use crate::json::{quote, JsonValue};
+use crate::terminfo::term_name;
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+pub struct AsciicastRecorder {
+    file: BufWriter<File>,
+    started_at: Instant,
+    // Output can be split in the middle of a UTF-8 character, but each event has to be a valid
+    // JSON string. We hold on to the start of the character until the rest of it arrives.
+    incomplete_output: Vec<u8>,
+}
+
+impl AsciicastRecorder {
+    pub fn new(path: &Path, cols: usize, rows: usize) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        let shell = env::var("SHELL").unwrap_or_default();
+        writeln!(
+            file,
+            "{{\"version\": 2, \"width\": {}, \"height\": {}, \"timestamp\": {}, \"env\": {{\"SHELL\": {}, \"TERM\": {}}}}}",
+            cols,
+            rows,
+            unix_timestamp(),
+            quote(&shell),
+            quote(term_name())
+        )?;
+        file.flush()?;
+
+        Ok(AsciicastRecorder {
+            file,
+            started_at: Instant::now(),
+            incomplete_output: vec![],
+        })
+    }
+
+    // Something the child printed
+    pub fn output(&mut self, data: &[u8]) -> io::Result<()> {
+        self.incomplete_output.extend_from_slice(data);
+        let text = take_complete_utf8(&mut self.incomplete_output);
+        if text.is_empty() {
+            return Ok(());
+        }
+        self.write_event("o", &text)
+    }
+
+    // Something the user typed (or pasted)
+    pub fn input(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write_event("i", &String::from_utf8_lossy(data))
+    }
+
+    pub fn resized(&mut self, cols: usize, rows: usize) -> io::Result<()> {
+        self.write_event("r", &format!("{}x{}", cols, rows))
+    }
+
+    fn write_event(&mut self, code: &str, data: &str) -> io::Result<()> {
+        let time = self.started_at.elapsed().as_secs_f64();
+        writeln!(self.file, "[{:.6}, \"{}\", {}]", time, code, quote(data))?;
+        // We flush every time so that the recording is still useful if we crash, which is
+        // probably why someone's recording in the first place
+        self.file.flush()
+    }
+}
+
+// A recording that's been read back in
+pub struct AsciicastRecording {
+    pub cols: usize,
+    pub rows: usize,
+    // If the recording asks for pauses to be shortened, this is the longest one should be
+    pub idle_time_limit: Option<f64>,
+    pub events: Vec<AsciicastEvent>,
+}
+
+pub struct AsciicastEvent {
+    // In seconds since the start of the recording
+    pub time: f64,
+    // "o" for output, "i" for input, "r" for a resize or "m" for a marker
+    pub code: String,
+    pub data: String,
+}
+
+impl AsciicastRecording {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let invalid = |line_number: usize, message: String| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{}:{}: {}", path.display(), line_number, message),
+            )
+        };
+
+        let contents = fs::read_to_string(path)?;
+        let mut lines = contents.lines().enumerate();
+
+        let (_, header_line) = lines
+            .next()
+            .ok_or_else(|| invalid(1, "The file is empty".to_string()))?;
+        let header = JsonValue::parse(header_line).map_err(|err| invalid(1, err))?;
+        if header.get("version").and_then(|v| v.as_f64()) != Some(2.0) {
+            return Err(invalid(
+                1,
+                "Only asciicast version 2 is supported".to_string(),
+            ));
+        }
+        let size = |key: &str| header.get(key).and_then(|v| v.as_f64()).map(|v| v as usize);
+        let (Some(cols), Some(rows)) = (size("width"), size("height")) else {
+            return Err(invalid(1, "The header has no width or height".to_string()));
+        };
+
+        let mut events = vec![];
+        for (i, line) in lines {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event = JsonValue::parse(line).map_err(|err| invalid(i + 1, err))?;
+            let event = match event.as_array() {
+                Some([time, code, data]) => AsciicastEvent {
+                    time: time.as_f64().unwrap_or(0.),
+                    code: code.as_str().unwrap_or_default().to_string(),
+                    data: data.as_str().unwrap_or_default().to_string(),
+                },
+                _ => {
+                    return Err(invalid(
+                        i + 1,
+                        "Events should be [time, code, data]".to_string(),
+                    ))
+                }
+            };
+            events.push(event);
+        }
+
+        Ok(AsciicastRecording {
+            cols,
+            rows,
+            idle_time_limit: header.get("idle_time_limit").and_then(|v| v.as_f64()),
+            events,
+        })
+    }
+}
+
+// Where a recording goes if the user didn't say, eg. ~/velocity-1700000000.cast
+pub fn default_recording_path() -> PathBuf {
+    let directory = env::var("HOME").unwrap_or(".".to_string());
+    PathBuf::from(directory).join(format!("velocity-{}.cast", unix_timestamp()))
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Decodes as much of the buffer as we can and leaves an unfinished character at the end in it.
+// Bytes that can never be valid UTF-8 become U+FFFD, just like when we draw them.
+fn take_complete_utf8(buffer: &mut Vec<u8>) -> String {
+    let mut text = String::new();
+    let mut remaining = &buffer[..];
+    loop {
+        match std::str::from_utf8(remaining) {
+            Ok(valid) => {
+                text.push_str(valid);
+                remaining = &[];
+                break;
+            }
+            Err(err) => {
+                let (valid, rest) = remaining.split_at(err.valid_up_to());
+                text.push_str(std::str::from_utf8(valid).unwrap());
+                match err.error_len() {
+                    Some(invalid_len) => {
+                        text.push(char::REPLACEMENT_CHARACTER);
+                        remaining = &rest[invalid_len..];
+                    }
+                    // The character is cut off. The rest of it will come with the next output.
+                    None => {
+                        remaining = rest;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    *buffer = remaining.to_vec();
+    text
+}
@@ -0,0 +1,260 @@
+// Broadcast groups, for typing the same thing into lots of shells at once (eg. one for each
+// server you look after). Whatever you type into one member of a group is sent to every other
+// member too.
+//
+// Windows are separate processes, so the registry of who's in which group is a directory that
+// they all share (see runtime_directory.rs for where that is without $XDG_RUNTIME_DIR):
+//   $XDG_RUNTIME_DIR/velocity-broadcast/GROUP/MEMBER
+// Each member's file holds the path of its control socket (see control.rs), which is how input
+// gets to it, and whether it's been excluded. An excluded member stays in the group, but doesn't
+// send or receive anything until it's included again, eg. so you can fix up one server on its
+// own.
+use crate::control::{ControlCommand, ControlReply};
+use crate::json::JsonValue;
+use crate::runtime_directory::{create_private_directory, runtime_directory};
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, BufReader, ErrorKind, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{channel, Sender},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// What the frontends join when they aren't given a name
+pub const DEFAULT_BROADCAST_GROUP: &str = "default";
+
+// Counting the other members means reading all of their files, which is too slow to do every
+// time the title is drawn
+const MEMBER_COUNT_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+static NEXT_MEMBER_ID: AtomicUsize = AtomicUsize::new(1);
+
+pub struct BroadcastMembership {
+    group: String,
+    control_socket: PathBuf,
+    // Our file in the group's directory
+    member_path: PathBuf,
+    excluded: bool,
+    // Input to send to the rest of the group. A thread does the sending, so that a member that's
+    // slow to answer can't hold up typing.
+    sender: Sender<Vec<u8>>,
+    receiving_member_count: Cell<usize>,
+    receiving_member_count_updated: Cell<Instant>,
+}
+
+impl BroadcastMembership {
+    // Joins (or creates) a group. control_socket is where the other members should send us
+    // their input.
+    pub fn join(group: &str, control_socket: &Path) -> io::Result<Self> {
+        if group.is_empty() || group.starts_with('.') || group.contains('/') {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("'{}' can't be the name of a broadcast group", group),
+            ));
+        }
+        // Anyone who can add themselves to a group gets to see what we type, so like the
+        // sockets, the registry is only for us
+        let registry_directory = runtime_directory()?.join("velocity-broadcast");
+        create_private_directory(&registry_directory)?;
+        let group_directory = registry_directory.join(group);
+        create_private_directory(&group_directory)?;
+
+        let member_name = format!(
+            "{}-{}",
+            process::id(),
+            NEXT_MEMBER_ID.fetch_add(1, Ordering::Relaxed)
+        );
+        let member_path = group_directory.join(member_name);
+
+        let (sender, receiver) = channel::<Vec<u8>>();
+        let thread_group_directory = group_directory.clone();
+        let thread_member_path = member_path.clone();
+        thread::spawn(move || {
+            let mut connections = HashMap::new();
+            for input in receiver {
+                send_to_group(
+                    &thread_group_directory,
+                    &thread_member_path,
+                    &input,
+                    &mut connections,
+                );
+            }
+        });
+
+        let membership = BroadcastMembership {
+            group: group.to_string(),
+            control_socket: control_socket.to_path_buf(),
+            member_path,
+            excluded: false,
+            sender,
+            receiving_member_count: Cell::new(0),
+            receiving_member_count_updated: Cell::new(Instant::now()),
+        };
+        membership.write_member_file()?;
+        membership.update_receiving_member_count();
+        Ok(membership)
+    }
+
+    pub fn group(&self) -> &str {
+        &self.group
+    }
+
+    pub fn is_excluded(&self) -> bool {
+        self.excluded
+    }
+
+    pub fn set_excluded(&mut self, excluded: bool) {
+        self.excluded = excluded;
+        if let Err(err) = self.write_member_file() {
+            println!("Failed to update our broadcast group membership: {}", err);
+        }
+    }
+
+    // Sends input that was typed into us to the rest of the group
+    pub fn send(&self, data: &[u8]) {
+        if self.excluded {
+            return;
+        }
+        let _ = self.sender.send(data.to_vec());
+    }
+
+    // How many other members would get what we type, for showing in the title. It can be up to
+    // MEMBER_COUNT_REFRESH_INTERVAL out of date.
+    pub fn receiving_member_count(&self) -> usize {
+        if self.receiving_member_count_updated.get().elapsed() >= MEMBER_COUNT_REFRESH_INTERVAL {
+            self.update_receiving_member_count();
+        }
+        self.receiving_member_count.get()
+    }
+
+    fn update_receiving_member_count(&self) {
+        let group_directory = self.member_path.parent().unwrap();
+        let count = receiving_members(group_directory, &self.member_path).len();
+        self.receiving_member_count.set(count);
+        self.receiving_member_count_updated.set(Instant::now());
+    }
+
+    fn write_member_file(&self) -> io::Result<()> {
+        let state = if self.excluded {
+            "excluded"
+        } else {
+            "included"
+        };
+        let contents = format!("{}\n{}\n", self.control_socket.display(), state);
+        // Written to the side and renamed into place, so other members never see half of it
+        let temporary_path = self.member_path.with_extension("tmp");
+        fs::write(&temporary_path, contents)?;
+        fs::rename(&temporary_path, &self.member_path)
+    }
+}
+
+impl Drop for BroadcastMembership {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.member_path);
+        // The last one out tidies up. This fails (harmlessly) if anyone is left.
+        if let Some(group_directory) = self.member_path.parent() {
+            let _ = fs::remove_dir(group_directory);
+        }
+    }
+}
+
+// The control sockets of everyone in the group except us and anyone who is excluded
+fn receiving_members(group_directory: &Path, our_path: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(group_directory) else {
+        return vec![];
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path != our_path && path.extension().is_none())
+        .filter_map(|path| {
+            let contents = fs::read_to_string(path).ok()?;
+            let mut lines = contents.lines();
+            let control_socket = lines.next()?;
+            match lines.next() {
+                Some("included") => Some(PathBuf::from(control_socket)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+// Runs on the membership's thread. We keep a connection open to each member rather than
+// connecting for every keystroke.
+fn send_to_group(
+    group_directory: &Path,
+    our_path: &Path,
+    input: &[u8],
+    connections: &mut HashMap<PathBuf, UnixStream>,
+) {
+    let command = ControlCommand::BroadcastInput(input.to_vec());
+    let line = format!("{}\n", command.to_json());
+
+    let members = receiving_members(group_directory, our_path);
+    connections.retain(|control_socket, _| members.contains(control_socket));
+    for control_socket in members {
+        if !connections.contains_key(&control_socket) {
+            match connect(&control_socket) {
+                Ok(stream) => {
+                    connections.insert(control_socket.clone(), stream);
+                }
+                // Nothing is listening, so it went away without leaving the group (eg. it
+                // crashed). We leave the group for it.
+                Err(_) => {
+                    remove_member_with_socket(group_directory, &control_socket);
+                    continue;
+                }
+            }
+        }
+        let stream = connections.get_mut(&control_socket).unwrap();
+        if stream.write_all(line.as_bytes()).is_err() {
+            connections.remove(&control_socket);
+        }
+    }
+}
+
+fn remove_member_with_socket(group_directory: &Path, control_socket: &Path) {
+    let Ok(entries) = fs::read_dir(group_directory) else {
+        return;
+    };
+    for path in entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+    {
+        let contents = fs::read_to_string(&path).unwrap_or_default();
+        if contents.lines().next() == Some(&*control_socket.to_string_lossy()) {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn connect(control_socket: &Path) -> io::Result<UnixStream> {
+    let stream = UnixStream::connect(control_socket)?;
+    // The member answers every command. We don't need to know what it said, but we do have to
+    // read it, or its replies would eventually fill the socket up.
+    let reader = stream.try_clone()?;
+    thread::spawn(move || {
+        for line in BufReader::new(reader).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if let Ok(json) = JsonValue::parse(&line) {
+                if let ControlReply::Error(message) = ControlReply::from_json(&json) {
+                    println!(
+                        "A broadcast group member didn't take our input: {}",
+                        message
+                    );
+                }
+            }
+        }
+    });
+    Ok(stream)
+}
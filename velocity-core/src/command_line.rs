@@ -0,0 +1,515 @@
+// The command line options shared by every frontend, eg.
+//   velocity --working-directory ~/src --title Logs -e tail -f server.log
+// We parse these by hand rather than pulling in a crate for it.
+use crate::control::{default_listen_socket_path, ControlServer};
+use crate::local_echo::LocalEchoMode;
+use crate::server_protocol::{default_socket_path, AttachRequest};
+use crate::session_log::{default_log_directory, SessionLogConfig, SessionLogMode};
+use crate::shell_integration::is_disabled_by_env;
+use crate::shell_layer::{
+    client::ClientShellLayer,
+    replay::ReplayShellLayer,
+    serial::{FlowControl, Parity, SerialConfig, SerialShellLayer},
+    spawn_config::SpawnConfig,
+    CellPixelSize,
+};
+use std::{env, io, path::PathBuf, time::Duration};
+
+pub const USAGE: &str = "Usage: velocity [OPTIONS] [-e COMMAND [ARGS...]]
+
+Options:
+  -e, --command COMMAND [ARGS...]   Run COMMAND instead of your shell. Everything after it is
+                                    passed to COMMAND as arguments.
+  --working-directory DIR           Start in DIR instead of your home directory
+  --cols COLUMNS                    The width of the window, in characters
+  --rows ROWS                       The height of the window, in characters
+  -T, --title TITLE                 The window's title
+  --hold                            Keep the window open after the command exits
+  --font PATH                       The font file to draw text with
+  --no-shell-integration            Don't get your shell to mark prompts and report its working
+                                    directory. You can also set $VELOCITY_SHELL_INTEGRATION=off.
+  --local-echo MODE                 Draw what you type before the program echoes it, which helps
+                                    over slow connections. auto (the default) only does when
+                                    echo is slow, and the others are always and off.
+  --record PATH                     Record the session to an asciicast file. You can also set
+                                    $VELOCITY_RECORD, or toggle recording with Ctrl+Shift+R.
+  -h, --help                        Show this message
+
+Logging the session:
+  --log                             Save a transcript of the session in ~/velocity-logs
+  --log-dir DIR                     Save it in DIR instead. You can also set $VELOCITY_LOG_DIR.
+  --log-format FORMAT               text (the default) is what was on the screen, written as it
+                                    scrolls off. raw is exactly what the program printed.
+  --log-timestamps                  Start every line in the log with the time
+  --log-max-size SIZE               Carry on in a new file once the log is this big, eg. 10M
+  --log-name TEMPLATE               The log's filename, where {date}, {time}, {pid} and {tty} are
+                                    filled in. Defaults to velocity-{date}-{time}-{pid}.log
+
+Replaying a recording:
+  --replay PATH                     Play back an asciicast file, or a typescript from script(1),
+                                    instead of running anything. Space pauses, . steps while
+                                    paused, and + and - change the speed.
+  --replay-timing PATH              The timing file for a typescript (from script -t or -T)
+  --speed MULTIPLIER                Eg. 2 plays twice as fast as it was recorded
+  --max-idle SECONDS                Shorten any pauses longer than this
+  --paused                          Start paused, ready to step through
+
+Connecting to a serial port:
+  --serial DEVICE                   Talk to DEVICE (eg. /dev/ttyUSB0) instead of running anything
+  --baud RATE                       Defaults to 115200
+  --serial-format FORMAT            Data bits, parity (N, E or O) and stop bits. Defaults to 8N1.
+  --flow-control TYPE               none (the default), hardware (RTS/CTS) or software (XON/XOFF)
+
+Scripting the window (see velocity-ctl):
+  --listen PATH                     Listen for velocity-ctl on PATH instead of
+                                    $XDG_RUNTIME_DIR/velocity-PID.sock. Programs in the window
+                                    are told where with $VELOCITY_LISTEN_SOCKET.
+  --no-listen                       Don't let anything control the window
+  --broadcast-group NAME            Join the broadcast group NAME, so that what you type goes to
+                                    every window in it. Ctrl+Shift+B joins or leaves a group
+                                    (called default, unless this says otherwise), and Ctrl+Shift+X
+                                    stops this window sending or receiving for a while.
+
+Attaching to a session in velocity-server:
+  --attach NAME                     Open the session called NAME, starting it (with -e and
+                                    --working-directory, if you gave them) if it doesn't exist.
+                                    It keeps running after the window is closed.
+  --read-only                       Watch the session without being able to type into it
+  --server-socket PATH              Where velocity-server is listening, if it isn't the default";
+
+#[derive(Default)]
+pub struct CommandLineOptions {
+    pub command: Option<Vec<String>>,
+    pub working_directory: Option<PathBuf>,
+    pub cols: Option<usize>,
+    pub rows: Option<usize>,
+    pub title: Option<String>,
+    pub hold: bool,
+    pub font: Option<PathBuf>,
+    pub local_echo: LocalEchoMode,
+    pub no_shell_integration: bool,
+    // Where to record the session to from the start, in asciicast format
+    pub record: Option<PathBuf>,
+    pub replay: Option<PathBuf>,
+    pub replay_timing: Option<PathBuf>,
+    pub replay_speed: Option<f64>,
+    pub replay_max_idle: Option<f64>,
+    pub replay_paused: bool,
+    pub serial: Option<PathBuf>,
+    pub serial_config: SerialConfig,
+    // Where to save a transcript of the session, if anywhere
+    pub log_directory: Option<PathBuf>,
+    pub log_mode: Option<SessionLogMode>,
+    pub log_timestamps: bool,
+    pub log_max_size: Option<u64>,
+    pub log_name: Option<String>,
+    // Where the control socket goes, if not the default (see default_listen_socket_path)
+    pub listen_socket: Option<PathBuf>,
+    pub no_listen: bool,
+    // The broadcast group to join at the start (see broadcast.rs)
+    pub broadcast_group: Option<String>,
+    // The name of a velocity-server session to attach to
+    pub attach: Option<String>,
+    pub read_only: bool,
+    pub server_socket: Option<PathBuf>,
+    // The frontend should print USAGE and exit
+    pub show_help: bool,
+}
+
+impl CommandLineOptions {
+    // Call with env::args().skip(1). Errors are messages meant for the user.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut options = CommandLineOptions::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            // Both "--title Logs" and "--title=Logs" work
+            let (name, mut inline_value) = match arg.split_once('=') {
+                Some((name, value)) if name.starts_with("--") => {
+                    (name.to_string(), Some(value.to_string()))
+                }
+                _ => (arg.clone(), None),
+            };
+
+            if name == "-e" || name == "--command" {
+                // Like xterm, this swallows the rest of the command line
+                let mut command = vec![take_value(&name, &mut inline_value, &mut args)?];
+                command.extend(args);
+                options.command = Some(command);
+                break;
+            }
+
+            let mut value = || take_value(&name, &mut inline_value, &mut args);
+            match name.as_str() {
+                "--working-directory" => options.working_directory = Some(value()?.into()),
+                "--cols" => options.cols = Some(parse_size(&name, &value()?)?),
+                "--rows" => options.rows = Some(parse_size(&name, &value()?)?),
+                "-T" | "--title" => options.title = Some(value()?),
+                "--hold" => options.hold = true,
+                "--font" => options.font = Some(value()?.into()),
+                "--no-shell-integration" => options.no_shell_integration = true,
+                "--local-echo" => {
+                    options.local_echo = match value()?.as_str() {
+                        "auto" => LocalEchoMode::Auto,
+                        "always" | "on" => LocalEchoMode::Always,
+                        "off" => LocalEchoMode::Off,
+                        other => return Err(format!("Unknown local echo mode '{}'", other)),
+                    }
+                }
+                "--record" => options.record = Some(value()?.into()),
+                "--replay" => options.replay = Some(value()?.into()),
+                "--replay-timing" => options.replay_timing = Some(value()?.into()),
+                "--speed" => options.replay_speed = Some(parse_positive_number(&name, &value()?)?),
+                "--max-idle" => {
+                    options.replay_max_idle = Some(parse_positive_number(&name, &value()?)?)
+                }
+                "--paused" => options.replay_paused = true,
+                "--serial" => options.serial = Some(value()?.into()),
+                "--baud" => {
+                    let baud_rate = value()?;
+                    options.serial_config.baud_rate = baud_rate
+                        .parse()
+                        .map_err(|_| format!("'{}' isn't a baud rate", baud_rate))?;
+                }
+                "--serial-format" => parse_serial_format(&value()?, &mut options.serial_config)?,
+                "--flow-control" => {
+                    options.serial_config.flow_control = match value()?.as_str() {
+                        "none" => FlowControl::None,
+                        "hardware" | "rtscts" => FlowControl::Hardware,
+                        "software" | "xonxoff" => FlowControl::Software,
+                        other => return Err(format!("Unknown flow control '{}'", other)),
+                    }
+                }
+                "--log" => options.log_directory = Some(default_log_directory()),
+                "--log-dir" => options.log_directory = Some(value()?.into()),
+                "--log-format" => {
+                    options.log_mode = match value()?.as_str() {
+                        "text" | "plain" => Some(SessionLogMode::PlainText),
+                        "raw" => Some(SessionLogMode::Raw),
+                        other => return Err(format!("Unknown log format '{}'", other)),
+                    }
+                }
+                "--log-timestamps" => options.log_timestamps = true,
+                "--log-max-size" => options.log_max_size = Some(parse_byte_size(&name, &value()?)?),
+                "--log-name" => options.log_name = Some(value()?),
+                "--listen" => options.listen_socket = Some(value()?.into()),
+                "--no-listen" => options.no_listen = true,
+                "--broadcast-group" => options.broadcast_group = Some(value()?),
+                "--attach" => options.attach = Some(value()?),
+                "--read-only" => options.read_only = true,
+                "--server-socket" => options.server_socket = Some(value()?.into()),
+                "-h" | "--help" => options.show_help = true,
+                _ => return Err(format!("Unknown option '{}'", arg)),
+            }
+        }
+
+        if options.record.is_none() {
+            options.record = env::var_os("VELOCITY_RECORD").map(PathBuf::from);
+        }
+        if is_disabled_by_env() {
+            options.no_shell_integration = true;
+        }
+        if options.log_directory.is_none() {
+            options.log_directory = env::var_os("VELOCITY_LOG_DIR").map(PathBuf::from);
+        }
+
+        Ok(options)
+    }
+
+    // What the window is called, before the frontend adds what it's up to (see
+    // TtyState::window_title)
+    pub fn window_title(&self) -> String {
+        match (&self.title, &self.attach) {
+            (Some(title), _) => title.clone(),
+            (None, Some(session_name)) => format!("Velocity ({})", session_name),
+            (None, None) => "Velocity".to_string(),
+        }
+    }
+
+    // How the shell layer should launch the child
+    pub fn spawn_config(&self) -> SpawnConfig {
+        SpawnConfig {
+            command: self.command.clone(),
+            working_directory: self.working_directory.clone(),
+            shell_integration: !self.no_shell_integration,
+            ..SpawnConfig::new()
+        }
+    }
+
+    // Starts listening for velocity-ctl, unless we were asked not to
+    pub fn control_server(&self) -> Option<io::Result<ControlServer>> {
+        if self.no_listen {
+            return None;
+        }
+        let path = match &self.listen_socket {
+            Some(path) => path.clone(),
+            None => match default_listen_socket_path() {
+                Ok(path) => path,
+                Err(err) => return Some(Err(err)),
+            },
+        };
+        Some(ControlServer::start(&path))
+    }
+
+    // How to log the session, if we were asked to
+    pub fn session_log_config(&self) -> Option<SessionLogConfig> {
+        let mut config = SessionLogConfig::new(self.log_directory.clone()?);
+        if let Some(mode) = self.log_mode {
+            config.mode = mode;
+        }
+        if let Some(name) = &self.log_name {
+            config.filename_template = name.clone();
+        }
+        config.timestamps = self.log_timestamps;
+        config.max_file_size = self.log_max_size;
+        Some(config)
+    }
+
+    // The layer that plays back --replay, if we were given one. The frontend uses this instead of
+    // get_shell_layer().
+    pub fn replay_shell_layer(&self) -> Option<io::Result<ReplayShellLayer>> {
+        let path = self.replay.as_ref()?;
+        let layer = ReplayShellLayer::open(path, self.replay_timing.as_deref()).map(|mut layer| {
+            if let Some(speed) = self.replay_speed {
+                layer = layer.speed(speed);
+            }
+            if let Some(max_idle) = self.replay_max_idle {
+                layer = layer.max_idle(Duration::from_secs_f64(max_idle));
+            }
+            if self.replay_paused {
+                layer = layer.start_paused();
+            }
+            layer
+        });
+        Some(layer)
+    }
+
+    // The layer for --serial, if we were given a device
+    pub fn serial_shell_layer(&self) -> Option<io::Result<SerialShellLayer>> {
+        let path = self.serial.as_ref()?;
+        Some(SerialShellLayer::new(path, self.serial_config))
+    }
+
+    // The layer for --attach, if we were given a session. This needs the window's size, in case
+    // the session has to be started (or resized to fit us).
+    pub fn client_shell_layer(
+        &self,
+        rows: usize,
+        cols: usize,
+        cell_pixel_size: CellPixelSize,
+    ) -> Option<io::Result<ClientShellLayer>> {
+        let session_name = self.attach.clone()?;
+        let socket_path = match &self.server_socket {
+            Some(socket_path) => socket_path.clone(),
+            None => match default_socket_path() {
+                Ok(socket_path) => socket_path,
+                Err(err) => return Some(Err(err)),
+            },
+        };
+        let request = AttachRequest {
+            session_name,
+            read_only: self.read_only,
+            rows,
+            cols,
+            cell_pixel_size,
+            spawn_config: self.spawn_config(),
+        };
+        Some(ClientShellLayer::attach(&socket_path, request))
+    }
+}
+
+// The value is either after the = or the next argument
+fn take_value(
+    name: &str,
+    inline_value: &mut Option<String>,
+    args: &mut impl Iterator<Item = String>,
+) -> Result<String, String> {
+    inline_value
+        .take()
+        .or_else(|| args.next())
+        .ok_or_else(|| format!("{} needs a value", name))
+}
+
+fn parse_positive_number(name: &str, value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(number) if number > 0. && number.is_finite() => Ok(number),
+        _ => Err(format!(
+            "{} should be a positive number, not '{}'",
+            name, value
+        )),
+    }
+}
+
+// Eg. "8N1" or "7E2"
+fn parse_serial_format(format: &str, config: &mut SerialConfig) -> Result<(), String> {
+    let invalid = || format!("'{}' should look like 8N1", format);
+    let [data_bits, parity, stop_bits] = format.as_bytes() else {
+        return Err(invalid());
+    };
+
+    config.data_bits = match data_bits {
+        b'5'..=b'8' => data_bits - b'0',
+        _ => return Err(invalid()),
+    };
+    config.parity = match parity.to_ascii_uppercase() {
+        b'N' => Parity::None,
+        b'E' => Parity::Even,
+        b'O' => Parity::Odd,
+        _ => return Err(invalid()),
+    };
+    config.stop_bits = match stop_bits {
+        b'1' | b'2' => stop_bits - b'0',
+        _ => return Err(invalid()),
+    };
+    Ok(())
+}
+
+// A number of bytes, optionally in K, M or G (which are powers of 1024)
+fn parse_byte_size(name: &str, value: &str) -> Result<u64, String> {
+    let invalid = || {
+        format!(
+            "{} should be a size like 500K or 10M, not '{}'",
+            name, value
+        )
+    };
+    let upper = value.to_ascii_uppercase();
+    let upper = upper.trim_end_matches('B');
+    let (number, multiplier) = match upper.chars().last() {
+        Some('K') => (&upper[..upper.len() - 1], 1024),
+        Some('M') => (&upper[..upper.len() - 1], 1024 * 1024),
+        Some('G') => (&upper[..upper.len() - 1], 1024 * 1024 * 1024),
+        _ => (upper, 1),
+    };
+    // Too big to count is just as invalid as not a number
+    match number.parse::<u64>() {
+        Ok(number) if number > 0 => number.checked_mul(multiplier).ok_or_else(invalid),
+        _ => Err(invalid()),
+    }
+}
+
+// The kernel (and velocity-server) keep the size in a u16, so anything bigger would be cut short
+fn parse_size(name: &str, value: &str) -> Result<usize, String> {
+    match value.parse::<u16>() {
+        Ok(size) if size > 0 => Ok(size as usize),
+        _ => Err(format!(
+            "{} should be a number from 1 to {}, not '{}'",
+            name,
+            u16::MAX,
+            value
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<CommandLineOptions, String> {
+        CommandLineOptions::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn no_arguments() {
+        let options = parse(&[]).unwrap();
+        assert_eq!(options.command, None);
+        assert_eq!(options.cols, None);
+        assert!(!options.hold);
+        assert!(!options.show_help);
+    }
+
+    #[test]
+    fn values_can_be_separate_or_after_an_equals_sign() {
+        let options = parse(&["--title", "Logs", "--working-directory=/tmp"]).unwrap();
+        assert_eq!(options.title.as_deref(), Some("Logs"));
+        assert_eq!(options.working_directory, Some(PathBuf::from("/tmp")));
+        // Only the first = splits it
+        let options = parse(&["--title=a=b"]).unwrap();
+        assert_eq!(options.title.as_deref(), Some("a=b"));
+    }
+
+    #[test]
+    fn command_takes_the_rest_of_the_arguments() {
+        let options = parse(&["--hold", "-e", "tail", "-f", "--title", "x.log"]).unwrap();
+        assert!(options.hold);
+        assert_eq!(
+            options.command,
+            Some(vec![
+                "tail".to_string(),
+                "-f".to_string(),
+                "--title".to_string(),
+                "x.log".to_string()
+            ])
+        );
+        assert_eq!(options.title, None);
+        assert!(parse(&["-e"]).is_err());
+    }
+
+    #[test]
+    fn sizes() {
+        let options = parse(&["--cols", "132", "--rows=43"]).unwrap();
+        assert_eq!((options.cols, options.rows), (Some(132), Some(43)));
+        assert_eq!(parse(&["--cols", "65535"]).unwrap().cols, Some(65535));
+        for size in ["0", "-1", "65536", "70000", "80x24", ""] {
+            assert!(parse(&["--cols", size]).is_err(), "{}", size);
+            assert!(parse(&["--rows", size]).is_err(), "{}", size);
+        }
+    }
+
+    #[test]
+    fn mistakes() {
+        assert!(parse(&["--nonsense"]).is_err());
+        assert!(parse(&["--title"]).is_err());
+        assert!(parse(&["--local-echo", "sometimes"]).is_err());
+        assert!(parse(&["--log-format", "html"]).is_err());
+        assert!(parse(&["--flow-control", "carrier-pigeon"]).is_err());
+        assert!(parse(&["--speed", "0"]).is_err());
+        assert!(parse(&["--speed", "inf"]).is_err());
+        assert!(parse(&["--baud", "fast"]).is_err());
+    }
+
+    #[test]
+    fn modes() {
+        let options = parse(&["--local-echo", "always", "--log-format", "raw"]).unwrap();
+        assert_eq!(options.local_echo, LocalEchoMode::Always);
+        assert_eq!(options.log_mode, Some(SessionLogMode::Raw));
+        let options = parse(&["--flow-control", "rtscts", "--baud", "9600"]).unwrap();
+        assert_eq!(options.serial_config.flow_control, FlowControl::Hardware);
+        assert_eq!(options.serial_config.baud_rate, 9600);
+    }
+
+    #[test]
+    fn byte_sizes() {
+        let size = |value: &str| parse_byte_size("--log-max-size", value);
+        assert_eq!(size("500"), Ok(500));
+        assert_eq!(size("500K"), Ok(500 * 1024));
+        assert_eq!(size("10m"), Ok(10 * 1024 * 1024));
+        assert_eq!(size("10MB"), Ok(10 * 1024 * 1024));
+        assert_eq!(size("2G"), Ok(2 * 1024 * 1024 * 1024));
+        for value in ["", "0", "0K", "K", "-1M", "1.5M", "10T", "20000000000G"] {
+            assert!(size(value).is_err(), "{}", value);
+        }
+        assert_eq!(
+            parse(&["--log-max-size", "1k"]).unwrap().log_max_size,
+            Some(1024)
+        );
+    }
+
+    #[test]
+    fn serial_formats() {
+        let mut config = SerialConfig::new();
+        parse_serial_format("7e2", &mut config).unwrap();
+        assert_eq!(config.data_bits, 7);
+        assert_eq!(config.parity, Parity::Even);
+        assert_eq!(config.stop_bits, 2);
+        parse_serial_format("5O1", &mut config).unwrap();
+        assert_eq!((config.data_bits, config.parity), (5, Parity::Odd));
+
+        for format in ["", "8N", "8N12", "9N1", "4N1", "8X1", "8N3"] {
+            assert!(
+                parse_serial_format(format, &mut config).is_err(),
+                "{}",
+                format
+            );
+        }
+    }
+}
@@ -12,6 +12,17 @@ pub const FD_POLL_TIMEOUT_MS: c_int = 1000 / TARGET_FRAMERATE as c_int - RENDER_
 // Essentially how many character chunks we're confident we can draw in one go without
 // stalling.
 pub const FD_BUFFER_SIZE_BYTES: usize = 4096;
+// What we tell the child program we are with $TERM when our own terminfo entry isn't installed
+// and we can't install it (see terminfo.rs).
+// NOTE: We only support 16 colours, but on the distro I tested with (Pop!_OS)
+//   16 colour support is not configured. It only recognises 256color as ANSI.
+// TODO: Eventually support xterm-256color on macOS
+#[cfg(target_os = "macos")]
+pub const FALLBACK_TERM: &str = "xterm-16color";
+#[cfg(not(target_os = "macos"))]
+pub const FALLBACK_TERM: &str = "xterm-256color";
+// When a window is closed, how long we give the shell to exit after SIGHUP before we SIGKILL it
+pub const SHELL_EXIT_GRACE_PERIOD_MS: u64 = 500;
 
 pub mod special_characters {
     // Question mark in a black diamond
@@ -44,5 +55,7 @@ pub mod special_characters {
         pub const NEXT_LINE: char = 'E';
         // Also known as REVERSE INDEX or RI
         pub const SCROLL_UP: char = 'M';
+        // Also known as RIS
+        pub const RESET_TO_INITIAL_STATE: char = 'c';
     }
 }
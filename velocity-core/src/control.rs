@@ -0,0 +1,370 @@
+// Lets scripts drive a running window, eg.
+//   velocity-ctl send-text 'make\r'
+//   velocity-ctl get-text
+// Each window listens on its own Unix socket, and tells the programs it runs where that is with
+// $VELOCITY_LISTEN_SOCKET. Clients send one JSON object per line, like
+//   {"command": "send-text", "text": "make\r"}
+// and get one back per line, like {"ok": true} or {"ok": false, "error": "..."}.
+use crate::asciicast::default_recording_path;
+use crate::json::{quote, JsonValue};
+use crate::runtime_directory::{bind_private_socket, runtime_directory};
+use crate::tty::TtyState;
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, ErrorKind, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    process,
+    sync::mpsc::{channel, Receiver, Sender},
+    thread,
+};
+
+pub const LISTEN_SOCKET_ENV_VAR: &str = "VELOCITY_LISTEN_SOCKET";
+
+pub enum ControlCommand {
+    // Type this into the terminal, as though the user had
+    SendText(String),
+    // What's on the screen, or with scrollback, everything we still have
+    GetText { scrollback: bool },
+    GetCursor,
+    Resize { rows: usize, cols: usize },
+    SetTitle(String),
+    // Without a path, we pick one like Ctrl+Shift+R does
+    StartRecording(Option<PathBuf>),
+    StopRecording,
+    JoinBroadcastGroup(String),
+    LeaveBroadcastGroup,
+    // Excluded members stay in their group, but don't send or receive anything
+    SetBroadcastExcluded(bool),
+    // Something typed into another member of our broadcast group (see broadcast.rs). It's bytes
+    // rather than text, because that's what keys like Alt+x send.
+    BroadcastInput(Vec<u8>),
+}
+
+pub enum ControlReply {
+    Done,
+    Text(String),
+    // Zero-based, from the top left of the screen
+    Cursor { x: isize, y: isize, visible: bool },
+    Error(String),
+}
+
+impl ControlCommand {
+    pub fn to_json(&self) -> String {
+        match self {
+            ControlCommand::SendText(text) => {
+                format!("{{\"command\":\"send-text\",\"text\":{}}}", quote(text))
+            }
+            ControlCommand::GetText { scrollback } => {
+                let extent = if *scrollback { "scrollback" } else { "screen" };
+                format!("{{\"command\":\"get-text\",\"extent\":\"{}\"}}", extent)
+            }
+            ControlCommand::GetCursor => "{\"command\":\"get-cursor\"}".to_string(),
+            ControlCommand::Resize { rows, cols } => format!(
+                "{{\"command\":\"resize\",\"rows\":{},\"cols\":{}}}",
+                rows, cols
+            ),
+            ControlCommand::SetTitle(title) => {
+                format!("{{\"command\":\"set-title\",\"title\":{}}}", quote(title))
+            }
+            ControlCommand::StartRecording(Some(path)) => format!(
+                "{{\"command\":\"start-recording\",\"path\":{}}}",
+                quote(&path.to_string_lossy())
+            ),
+            ControlCommand::StartRecording(None) => "{\"command\":\"start-recording\"}".to_string(),
+            ControlCommand::StopRecording => "{\"command\":\"stop-recording\"}".to_string(),
+            ControlCommand::JoinBroadcastGroup(group) => {
+                format!(
+                    "{{\"command\":\"broadcast-join\",\"group\":{}}}",
+                    quote(group)
+                )
+            }
+            ControlCommand::LeaveBroadcastGroup => "{\"command\":\"broadcast-leave\"}".to_string(),
+            ControlCommand::SetBroadcastExcluded(true) => {
+                "{\"command\":\"broadcast-exclude\"}".to_string()
+            }
+            ControlCommand::SetBroadcastExcluded(false) => {
+                "{\"command\":\"broadcast-include\"}".to_string()
+            }
+            ControlCommand::BroadcastInput(data) => {
+                format!(
+                    "{{\"command\":\"broadcast-input\",\"data\":\"{}\"}}",
+                    hex_encode(data)
+                )
+            }
+        }
+    }
+
+    pub fn from_json(json: &JsonValue) -> Result<Self, String> {
+        let string = |key: &str| {
+            json.get(key)
+                .and_then(|value| value.as_str())
+                .map(|value| value.to_string())
+                .ok_or_else(|| format!("\"{}\" should be a string", key))
+        };
+        let size = |key: &str| match json.get(key).and_then(|value| value.as_f64()) {
+            Some(size) if size >= 1. && size <= u16::MAX as f64 => Ok(size as usize),
+            _ => Err(format!("\"{}\" should be a positive number", key)),
+        };
+
+        let command = json
+            .get("command")
+            .and_then(|command| command.as_str())
+            .ok_or("Requests need a \"command\"")?;
+        Ok(match command {
+            "send-text" => ControlCommand::SendText(string("text")?),
+            "get-text" => match json.get("extent").and_then(|extent| extent.as_str()) {
+                None | Some("screen") => ControlCommand::GetText { scrollback: false },
+                Some("scrollback") => ControlCommand::GetText { scrollback: true },
+                Some(other) => return Err(format!("Unknown extent '{}'", other)),
+            },
+            "get-cursor" => ControlCommand::GetCursor,
+            "resize" => ControlCommand::Resize {
+                rows: size("rows")?,
+                cols: size("cols")?,
+            },
+            "set-title" => ControlCommand::SetTitle(string("title")?),
+            "start-recording" => {
+                ControlCommand::StartRecording(string("path").ok().map(PathBuf::from))
+            }
+            "stop-recording" => ControlCommand::StopRecording,
+            "broadcast-join" => ControlCommand::JoinBroadcastGroup(string("group")?),
+            "broadcast-leave" => ControlCommand::LeaveBroadcastGroup,
+            "broadcast-exclude" => ControlCommand::SetBroadcastExcluded(true),
+            "broadcast-include" => ControlCommand::SetBroadcastExcluded(false),
+            "broadcast-input" => ControlCommand::BroadcastInput(
+                hex_decode(&string("data")?).ok_or("\"data\" should be hex")?,
+            ),
+            other => return Err(format!("Unknown command '{}'", other)),
+        })
+    }
+
+    // Carries out the commands that only need the terminal. The frontend does the ones that need
+    // the window (resize and set-title) or its control socket (broadcast-join) itself.
+    pub fn apply_to_tty(&self, tty: &mut TtyState) -> ControlReply {
+        match self {
+            ControlCommand::SendText(text) => {
+                tty.write(text.as_bytes());
+                ControlReply::Done
+            }
+            ControlCommand::GetText { scrollback: false } => ControlReply::Text(tty.screen_text()),
+            ControlCommand::GetText { scrollback: true } => {
+                ControlReply::Text(tty.scrollback_text())
+            }
+            ControlCommand::GetCursor => ControlReply::Cursor {
+                x: tty.cursor_pos.x,
+                y: tty.cursor_pos.y,
+                visible: tty.cursor_visible,
+            },
+            ControlCommand::StartRecording(path) => {
+                let path = path.clone().unwrap_or_else(default_recording_path);
+                tty.start_recording_and_report(&path)
+            }
+            ControlCommand::StopRecording => {
+                tty.stop_recording();
+                ControlReply::Done
+            }
+            ControlCommand::LeaveBroadcastGroup => {
+                tty.leave_broadcast_group();
+                ControlReply::Done
+            }
+            ControlCommand::SetBroadcastExcluded(excluded) => {
+                if tty.set_broadcast_excluded(*excluded) {
+                    ControlReply::Done
+                } else {
+                    ControlReply::Error("This window isn't in a broadcast group".to_string())
+                }
+            }
+            ControlCommand::BroadcastInput(data) => {
+                tty.receive_broadcast_input(data);
+                ControlReply::Done
+            }
+            ControlCommand::Resize { .. }
+            | ControlCommand::SetTitle(_)
+            | ControlCommand::JoinBroadcastGroup(_) => {
+                ControlReply::Error("This window can't do that".to_string())
+            }
+        }
+    }
+}
+
+impl ControlReply {
+    pub fn to_json(&self) -> String {
+        match self {
+            ControlReply::Done => "{\"ok\":true}".to_string(),
+            ControlReply::Text(text) => format!("{{\"ok\":true,\"text\":{}}}", quote(text)),
+            ControlReply::Cursor { x, y, visible } => format!(
+                "{{\"ok\":true,\"x\":{},\"y\":{},\"visible\":{}}}",
+                x, y, visible
+            ),
+            ControlReply::Error(message) => {
+                format!("{{\"ok\":false,\"error\":{}}}", quote(message))
+            }
+        }
+    }
+
+    pub fn from_json(json: &JsonValue) -> Self {
+        if json.get("ok") != Some(&JsonValue::Bool(true)) {
+            let message = json.get("error").and_then(|error| error.as_str());
+            return ControlReply::Error(message.unwrap_or("The window didn't say why").to_string());
+        }
+        if let Some(text) = json.get("text").and_then(|text| text.as_str()) {
+            return ControlReply::Text(text.to_string());
+        }
+        let x = json.get("x").and_then(|x| x.as_f64());
+        let y = json.get("y").and_then(|y| y.as_f64());
+        if let (Some(x), Some(y)) = (x, y) {
+            return ControlReply::Cursor {
+                x: x as isize,
+                y: y as isize,
+                visible: json.get("visible") != Some(&JsonValue::Bool(false)),
+            };
+        }
+        ControlReply::Done
+    }
+}
+
+// A command from a client, waiting for the frontend to carry it out and reply
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    reply_sender: Sender<ControlReply>,
+}
+
+impl ControlRequest {
+    pub fn reply(self, reply: ControlReply) {
+        // The client might have hung up without waiting, which is fine
+        let _ = self.reply_sender.send(reply);
+    }
+}
+
+// Listens for clients on a background thread. Their commands are queued up for the frontend,
+// which picks them up with next_request() once per frame, because only it can touch the
+// terminal and the window.
+pub struct ControlServer {
+    path: PathBuf,
+    requests: Receiver<ControlRequest>,
+}
+
+impl ControlServer {
+    pub fn start(path: &Path) -> io::Result<Self> {
+        // The default path has our pid in it, so a socket already there was left behind by a
+        // window that has since gone away
+        let listener = bind_private_socket(path)?;
+
+        let (sender, requests) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let sender = sender.clone();
+                        thread::spawn(move || handle_client(stream, sender));
+                    }
+                    Err(err) => println!("Failed to accept a control client: {}", err),
+                }
+            }
+        });
+
+        Ok(ControlServer {
+            path: path.to_path_buf(),
+            requests,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Doesn't block. None means nobody is waiting for anything.
+    pub fn next_request(&self) -> Option<ControlRequest> {
+        self.requests.try_recv().ok()
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn handle_client(stream: UnixStream, sender: Sender<ControlRequest>) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(err) => {
+            println!("Failed to clone a control client's socket: {}", err);
+            return;
+        }
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let reply = match JsonValue::parse(&line).and_then(|json| ControlCommand::from_json(&json))
+        {
+            Ok(command) => {
+                let (reply_sender, reply_receiver) = channel();
+                let request = ControlRequest {
+                    command,
+                    reply_sender,
+                };
+                // If the window has gone, there's nothing left to control
+                if sender.send(request).is_err() {
+                    return;
+                }
+                match reply_receiver.recv() {
+                    Ok(reply) => reply,
+                    Err(_) => return,
+                }
+            }
+            Err(message) => ControlReply::Error(message),
+        };
+
+        if writeln!(writer, "{}", reply.to_json()).is_err() {
+            return;
+        }
+    }
+}
+
+// Where a window listens unless it's told otherwise. There's one socket per window, so the
+// pid is in the name. Like the server's, it's somewhere only we can get into (see
+// runtime_directory.rs).
+pub fn default_listen_socket_path() -> io::Result<PathBuf> {
+    Ok(runtime_directory()?.join(format!("velocity-{}.sock", process::id())))
+}
+
+// JSON strings can't hold arbitrary bytes, so broadcast input is sent as hex
+fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// None if it isn't hex, including if there's half a byte left over at the end
+fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// For clients like velocity-ctl. Sends one command and waits for the window's reply.
+pub fn send_control_command(
+    socket_path: &Path,
+    command: &ControlCommand,
+) -> io::Result<ControlReply> {
+    let mut stream = UnixStream::connect(socket_path)?;
+    writeln!(stream, "{}", command.to_json())?;
+
+    let mut line = String::new();
+    if BufReader::new(&stream).read_line(&mut line)? == 0 {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "The window hung up without replying",
+        ));
+    }
+    let json =
+        JsonValue::parse(&line).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+    Ok(ControlReply::from_json(&json))
+}
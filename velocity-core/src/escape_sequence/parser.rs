@@ -2,7 +2,16 @@ use std::num::IntErrorKind;
 
 use crate::constants::special_characters::*;
 
-use super::sequence::{CharacterSet, EscapeSequence, SGRCode, SetCursorPositionArgs};
+use super::sequence::{
+    CharacterSet, EscapeSequence, SGRCode, SetCursorPositionArgs, SetScrollingRegionArgs,
+    ShellIntegrationMark,
+};
+
+// The private modes that switch to the alternate screen. See PrivateEnableAlternateScreen.
+const ALTERNATE_SCREEN_MODES: [&str; 3] = ["?1049", "?1047", "?47"];
+// OSC strings can be as long as a program likes. We stop keeping them past this, but still wait
+// for the end, so that the rest doesn't end up on the screen.
+const MAX_OSC_LENGTH: usize = 4096;
 
 #[derive(PartialEq, Debug)]
 enum SequenceType {
@@ -51,6 +60,9 @@ impl EscapeSequenceParser {
                         EscapeSequence::MoveCursorDownScrollingIfNecessary,
                     ));
                 }
+                special_case_introducer::RESET_TO_INITIAL_STATE => {
+                    return SequenceFinished::Yes(Some(EscapeSequence::FullReset));
+                }
                 special_case_introducer::NEXT_LINE => {
                     return SequenceFinished::Yes(Some(
                         EscapeSequence::MoveCursorNextLineScrollingIfNecessary,
@@ -68,6 +80,7 @@ impl EscapeSequenceParser {
         match self.sequence_type {
             SequenceType::CSI => self.parse_csi_character(c),
             SequenceType::DesignateG0Charset => self.parse_g0_designate_charset_character(c),
+            SequenceType::OSC => self.parse_osc_character(c),
             // We haven't implement parsing for anything else yet
             _ => {
                 println!(
@@ -98,6 +111,53 @@ impl EscapeSequenceParser {
         SequenceFinished::Yes(Some(EscapeSequence::DesignateG0CharacterSet(char_set)))
     }
 
+    // OSC strings end with a BEL, or with String Terminator (ESC \\)
+    fn parse_osc_character(&mut self, c: char) -> SequenceFinished {
+        let after_escape = self.intermediate_chars.last() == Some(&ESCAPE);
+        if c == BELL || after_escape {
+            // Anything other than a \ after the ESC is a broken sequence, which we give up on
+            if c == BELL || c == '\\' {
+                return SequenceFinished::Yes(self.parse_osc());
+            }
+            return SequenceFinished::Yes(None);
+        }
+        if c == ESCAPE {
+            self.intermediate_chars.push(c);
+        } else if self.parameter_chars.len() < MAX_OSC_LENGTH {
+            self.parameter_chars.push(c);
+        }
+        SequenceFinished::No
+    }
+
+    fn parse_osc(&self) -> Option<EscapeSequence> {
+        let osc_string: String = self.parameter_chars.iter().collect();
+        let (command, argument) = osc_string.split_once(';').unwrap_or((&osc_string, ""));
+        match command {
+            "7" => Some(EscapeSequence::ReportWorkingDirectory(argument.to_string())),
+            "133" => {
+                // There can be extra options after the exit status, eg. "D;0;aid=..."
+                let mut fields = argument.split(';');
+                let mark = match fields.next() {
+                    Some("A") => ShellIntegrationMark::PromptStart,
+                    Some("B") => ShellIntegrationMark::CommandStart,
+                    Some("C") => ShellIntegrationMark::CommandExecuted,
+                    Some("D") => ShellIntegrationMark::CommandFinished(
+                        fields.next().and_then(|status| status.parse().ok()),
+                    ),
+                    _ => {
+                        println!("Ignoring unknown shell integration mark '{}'", argument);
+                        return None;
+                    }
+                };
+                Some(EscapeSequence::ShellIntegrationMark(mark))
+            }
+            _ => {
+                println!("Ignoring unsupported OSC '{}'", command);
+                None
+            }
+        }
+    }
+
     fn parse_csi_character(&mut self, c: char) -> SequenceFinished {
         match c as usize {
             0x30..=0x3F => self.parameter_chars.push(c),
@@ -144,9 +204,27 @@ impl EscapeSequenceParser {
             'H' => self.parse_csi_set_cursor_position(),
             'J' => self.parse_csi_erase_in_display(),
             'K' => self.parse_csi_erase_in_line(),
+            'L' => Some(EscapeSequence::InsertLines(
+                self.parse_csi_single_number_parameter(),
+            )),
+            'M' => Some(EscapeSequence::DeleteLines(
+                self.parse_csi_single_number_parameter(),
+            )),
+            'S' => Some(EscapeSequence::ScrollUp(
+                self.parse_csi_single_number_parameter(),
+            )),
+            'T' => Some(EscapeSequence::ScrollDown(
+                self.parse_csi_single_number_parameter(),
+            )),
             'P' => Some(EscapeSequence::DeleteCharacters(
                 self.parse_csi_single_number_parameter(),
             )),
+            '@' => Some(EscapeSequence::InsertCharacters(
+                self.parse_csi_single_number_parameter(),
+            )),
+            'X' => Some(EscapeSequence::EraseCharacters(
+                self.parse_csi_single_number_parameter(),
+            )),
             'b' => Some(EscapeSequence::RepeatPreviousCharacter(
                 self.parse_csi_single_number_parameter(),
             )),
@@ -157,7 +235,10 @@ impl EscapeSequenceParser {
             'h' => self.parse_csi_set_mode(),
             'l' => self.parse_csi_reset_mode(),
             'm' => self.parse_csi_select_graphic_rendition(),
-            'c' => Some(EscapeSequence::FullReset),
+            'n' => self.parse_csi_device_status_report(),
+            'r' => self.parse_csi_set_scrolling_region(),
+            'c' => self.parse_csi_device_attributes(),
+            'q' => self.parse_csi_terminal_version(),
             _ => {
                 let inter_string: String = self.intermediate_chars.iter().collect();
                 let param_string: String = self.parameter_chars.iter().collect();
@@ -218,6 +299,22 @@ impl EscapeSequenceParser {
         }))
     }
 
+    fn parse_csi_set_scrolling_region(&mut self) -> Option<EscapeSequence> {
+        let param_string: String = self.parameter_chars.iter().collect();
+        let mut split = param_string.split(';');
+        // Missing (or broken) ones are the same as 0, which is the edge of the screen
+        let mut next = || {
+            split
+                .next()
+                .and_then(|param| param.parse::<usize>().ok())
+                .unwrap_or(0)
+        };
+        Some(EscapeSequence::SetScrollingRegion(SetScrollingRegionArgs {
+            top: next(),
+            bottom: next(),
+        }))
+    }
+
     fn parse_csi_private_sequence_final_byte(&mut self, c: char) -> Option<EscapeSequence> {
         let p_str: String = self.parameter_chars.iter().collect();
         match c {
@@ -228,12 +325,21 @@ impl EscapeSequenceParser {
             _ if p_str == "?2004" && c == 'l' => {
                 Some(EscapeSequence::PrivateDisableBracketedPasteMode)
             }
+            _ if ALTERNATE_SCREEN_MODES.contains(&p_str.as_str()) && c == 'h' => {
+                Some(EscapeSequence::PrivateEnableAlternateScreen)
+            }
+            _ if ALTERNATE_SCREEN_MODES.contains(&p_str.as_str()) && c == 'l' => {
+                Some(EscapeSequence::PrivateDisableAlternateScreen)
+            }
             _ if p_str == "?7" && c == 'h' => Some(EscapeSequence::EnableAutoWrapMode),
             _ if p_str == "?7" && c == 'l' => Some(EscapeSequence::DisableAutoWrapMode),
             _ if p_str == "?1" && c == 'h' => Some(EscapeSequence::SwitchToApplicationCursorKeys),
             _ if p_str == "?1" && c == 'l' => Some(EscapeSequence::SwitchToNormalCursorKeys),
             _ if p_str == "?25" && c == 'h' => Some(EscapeSequence::ShowCursor),
             _ if p_str == "?25" && c == 'l' => Some(EscapeSequence::HideCursor),
+            _ if p_str == "?6" && c == 'h' => Some(EscapeSequence::EnableOriginMode),
+            _ if p_str == "?6" && c == 'l' => Some(EscapeSequence::DisableOriginMode),
+            _ if p_str == "?6" && c == 'n' => Some(EscapeSequence::PrivateReportCursorPosition),
             _ => {
                 println!("Ignoring unknown CSI private sequence '{}', '{}'", p_str, c);
                 None
@@ -241,6 +347,46 @@ impl EscapeSequenceParser {
         }
     }
 
+    fn parse_csi_device_status_report(&mut self) -> Option<EscapeSequence> {
+        let param_str: String = self.parameter_chars.iter().collect();
+        match param_str.as_str() {
+            "5" => Some(EscapeSequence::DeviceStatusReport),
+            "6" => Some(EscapeSequence::ReportCursorPosition),
+            _ => {
+                println!("Ignoring unknown device status report '{}'", param_str);
+                None
+            }
+        }
+    }
+
+    // The parameter is optional, and always 0
+    fn parse_csi_device_attributes(&mut self) -> Option<EscapeSequence> {
+        let param_str: String = self.parameter_chars.iter().collect();
+        match param_str.as_str() {
+            "" | "0" => Some(EscapeSequence::RequestPrimaryDeviceAttributes),
+            ">" | ">0" => Some(EscapeSequence::RequestSecondaryDeviceAttributes),
+            "=" | "=0" => Some(EscapeSequence::RequestTertiaryDeviceAttributes),
+            _ => {
+                println!("Ignoring unknown device attributes request '{}'", param_str);
+                None
+            }
+        }
+    }
+
+    fn parse_csi_terminal_version(&mut self) -> Option<EscapeSequence> {
+        let param_str: String = self.parameter_chars.iter().collect();
+        if !self.intermediate_chars.is_empty() || !(param_str == ">" || param_str == ">0") {
+            // Eg. ESC[ q, which sets the cursor style
+            let inter_string: String = self.intermediate_chars.iter().collect();
+            println!(
+                "Ignoring CSI '[{}{}q' due to unknown final byte",
+                inter_string, param_str
+            );
+            return None;
+        }
+        Some(EscapeSequence::RequestTerminalVersion)
+    }
+
     fn parse_csi_set_mode(&mut self) -> Option<EscapeSequence> {
         let mode_type = self.parse_csi_set_or_reset_mode_parameter();
         let maybe_set_mode_enum = num::FromPrimitive::from_usize(mode_type);
@@ -27,12 +27,20 @@ pub enum EscapeSequence {
     PrivateEnableBracketedPasteMode, // ESC[?2004h
     // Turns the above off (obviously)
     PrivateDisableBracketedPasteMode, // ESC[?2004l
+    // Full-screen programs (like vim) switch to the alternate screen, and back when they exit.
+    // ?47 and ?1047 are older versions of the same thing.
+    PrivateEnableAlternateScreen,  // ESC[?1049h
+    PrivateDisableAlternateScreen, // ESC[?1049l
+    // (DECOM) Cursor positions count from the top of the scrolling region
+    EnableOriginMode, // ESC[?6h
+    // ...and from the top of the screen again
+    DisableOriginMode, // ESC[?6l
     // Turns on Auto-Wrap mode
     EnableAutoWrapMode,
     // Turns off Auto-Wrap mode
     DisableAutoWrapMode,
     // VT-100 Full Reset (RIS)
-    FullReset, // ESC[c
+    FullReset, // ESC c
     // DECCKM - for less/more
     SwitchToApplicationCursorKeys, // ESC[?1h
     // DECCKM - for shells
@@ -47,8 +55,12 @@ pub enum EscapeSequence {
     SetMode(SetOrResetModeType), // ESC[...h
     // Resets character insertion mode N
     ResetMode(SetOrResetModeType), // ESC[...l
-    // Delete N characters left(!) of the cursor, shifting the line buffer
+    // Deletes N characters at and right of the cursor, pulling the rest of the line left (DCH)
     DeleteCharacters(isize), // ESC[...P
+    // Inserts N blanks at the cursor, pushing the rest of the line right (ICH)
+    InsertCharacters(isize), // ESC[...@
+    // Blanks out N characters from the cursor, without moving anything (ECH)
+    EraseCharacters(isize), // ESC[...X
     // From the VT200, shows the cursor, obviously
     ShowCursor, // ESC[?25h
     // Opposite of ShowCursor, surprisingly
@@ -59,6 +71,34 @@ pub enum EscapeSequence {
     RepeatPreviousCharacter(isize), // ESC[...b
     // Puts the cursor at [1, N] (1-indexed)
     SetLinePositionAbsolute(isize), // ESC[...d
+    // Inserts N blank lines at the cursor, pushing the ones below it down (IL)
+    InsertLines(isize), // ESC[...L
+    // Deletes N lines at the cursor, pulling the ones below it up (DL)
+    DeleteLines(isize), // ESC[...M
+    // Scrolls the scrolling region up by N lines, without moving the cursor (SU)
+    ScrollUp(isize), // ESC[...S
+    // ...and down (SD)
+    ScrollDown(isize), // ESC[...T
+    // (DECSTBM) Only the lines between these scroll. Used by things like vim and tmux.
+    SetScrollingRegion(SetScrollingRegionArgs), // ESC[...r
+    // Asks whether we're OK. We always are.
+    DeviceStatusReport, // ESC[5n
+    // Asks where the cursor is (CPR). Used by things like readline and resize(1).
+    ReportCursorPosition, // ESC[6n
+    // DEC's version, which has the page number on the end too
+    PrivateReportCursorPosition, // ESC[?6n
+    // Asks what sort of terminal we are (DA1)
+    RequestPrimaryDeviceAttributes, // ESC[c
+    // Asks for our terminal type and version number (DA2)
+    RequestSecondaryDeviceAttributes, // ESC[>c
+    // Asks for our unit ID (DA3)
+    RequestTertiaryDeviceAttributes, // ESC[=c
+    // Asks for our name and version (XTVERSION)
+    RequestTerminalVersion, // ESC[>q
+    // The shell telling us its working directory, as a file:// URL
+    ReportWorkingDirectory(String), // ESC]7;...BEL
+    // Shell integration marks, for where prompts and commands start and end
+    ShellIntegrationMark(ShellIntegrationMark), // ESC]133;...BEL
 }
 
 #[derive(Debug)]
@@ -67,6 +107,13 @@ pub struct SetCursorPositionArgs {
     pub y: usize,
 }
 
+// The first and last rows of the region (1-indexed). 0 means the edge of the screen.
+#[derive(Debug)]
+pub struct SetScrollingRegionArgs {
+    pub top: usize,
+    pub bottom: usize,
+}
+
 // NOTE: Cursor position does not change (outside of DOS)
 #[derive(FromPrimitive, Debug, PartialEq)]
 pub enum EraseInDisplayType {
@@ -113,6 +160,17 @@ pub enum TerminalColour {
     BrightWhite = 17,
 }
 
+// From FinalTerm, which came up with OSC 133. Most terminals (and our shell integration
+// scripts) use it now.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShellIntegrationMark {
+    PromptStart,     // A
+    CommandStart,    // B (where the user starts typing)
+    CommandExecuted, // C
+    // The command's exit status, if the shell told us
+    CommandFinished(Option<i32>), // D
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CharacterSet {
     UnitedStatesASCII,
@@ -0,0 +1,364 @@
+// Just enough JSON for the file formats and protocols we speak (eg. asciicast). It's small
+// enough that it isn't worth a dependency.
+use std::{iter::Peekable, str::Chars};
+
+// Each level of nesting is a level of recursion, so a line of [[[[... could otherwise run us out
+// of stack. Nothing we read comes close to this.
+const MAX_DEPTH: usize = 128;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    // Kept in the order they were written. Objects are small, so looking keys up by scanning
+    // is fine.
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    pub fn parse(text: &str) -> Result<JsonValue, String> {
+        let mut chars = text.chars().peekable();
+        let value = parse_value(&mut chars, 0)?;
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            None => Ok(value),
+            Some(c) => Err(format!("Unexpected '{}' after the end of the JSON", c)),
+        }
+    }
+
+    // The value of a key in an object
+    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            JsonValue::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            JsonValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[JsonValue]> {
+        match self {
+            JsonValue::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+// Quotes and escapes a string for JSON
+pub fn quote(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len() + 2);
+    escaped.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            // Everything else below space, and DEL, has to be written as a code point. That
+            // includes ESC, which is most of what a terminal recording is made of.
+            c if (c as u32) < 0x20 || c == '\u{7f}' => {
+                escaped.push_str(&format!("\\u{:04x}", c as u32))
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+type Input<'a> = Peekable<Chars<'a>>;
+
+fn skip_whitespace(chars: &mut Input) {
+    while chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+}
+
+fn expect(chars: &mut Input, expected: char) -> Result<(), String> {
+    match chars.next() {
+        Some(c) if c == expected => Ok(()),
+        Some(c) => Err(format!("Expected '{}' but found '{}'", expected, c)),
+        None => Err(format!("Expected '{}' but the JSON ended", expected)),
+    }
+}
+
+fn expect_word(chars: &mut Input, word: &str) -> Result<(), String> {
+    for c in word.chars() {
+        expect(chars, c)?;
+    }
+    Ok(())
+}
+
+// depth is how many arrays and objects we're already inside
+fn parse_value(chars: &mut Input, depth: usize) -> Result<JsonValue, String> {
+    skip_whitespace(chars);
+    match chars.peek() {
+        Some('{' | '[') if depth >= MAX_DEPTH => Err(format!(
+            "The JSON is nested more than {} levels deep",
+            MAX_DEPTH
+        )),
+        Some('{') => parse_object(chars, depth + 1),
+        Some('[') => parse_array(chars, depth + 1),
+        Some('"') => Ok(JsonValue::String(parse_string(chars)?)),
+        Some('t') => expect_word(chars, "true").map(|_| JsonValue::Bool(true)),
+        Some('f') => expect_word(chars, "false").map(|_| JsonValue::Bool(false)),
+        Some('n') => expect_word(chars, "null").map(|_| JsonValue::Null),
+        Some(c) if *c == '-' || c.is_ascii_digit() => parse_number(chars),
+        Some(c) => Err(format!("Unexpected '{}'", c)),
+        None => Err("The JSON ended early".to_string()),
+    }
+}
+
+fn parse_object(chars: &mut Input, depth: usize) -> Result<JsonValue, String> {
+    expect(chars, '{')?;
+    let mut entries = vec![];
+    skip_whitespace(chars);
+    if chars.next_if_eq(&'}').is_some() {
+        return Ok(JsonValue::Object(entries));
+    }
+    loop {
+        skip_whitespace(chars);
+        let key = parse_string(chars)?;
+        skip_whitespace(chars);
+        expect(chars, ':')?;
+        entries.push((key, parse_value(chars, depth)?));
+        skip_whitespace(chars);
+        match chars.next() {
+            Some(',') => continue,
+            Some('}') => return Ok(JsonValue::Object(entries)),
+            _ => return Err("Expected ',' or '}' in an object".to_string()),
+        }
+    }
+}
+
+fn parse_array(chars: &mut Input, depth: usize) -> Result<JsonValue, String> {
+    expect(chars, '[')?;
+    let mut items = vec![];
+    skip_whitespace(chars);
+    if chars.next_if_eq(&']').is_some() {
+        return Ok(JsonValue::Array(items));
+    }
+    loop {
+        items.push(parse_value(chars, depth)?);
+        skip_whitespace(chars);
+        match chars.next() {
+            Some(',') => continue,
+            Some(']') => return Ok(JsonValue::Array(items)),
+            _ => return Err("Expected ',' or ']' in an array".to_string()),
+        }
+    }
+}
+
+fn parse_number(chars: &mut Input) -> Result<JsonValue, String> {
+    let mut text = String::new();
+    while let Some(c) = chars.next_if(|c| c.is_ascii_digit() || "+-.eE".contains(*c)) {
+        text.push(c);
+    }
+    text.parse::<f64>()
+        .map(JsonValue::Number)
+        .map_err(|_| format!("'{}' isn't a number", text))
+}
+
+fn parse_string(chars: &mut Input) -> Result<String, String> {
+    expect(chars, '"')?;
+    let mut string = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(string),
+            Some('\\') => match chars.next() {
+                Some('"') => string.push('"'),
+                Some('\\') => string.push('\\'),
+                Some('/') => string.push('/'),
+                Some('b') => string.push('\u{8}'),
+                Some('f') => string.push('\u{c}'),
+                Some('n') => string.push('\n'),
+                Some('r') => string.push('\r'),
+                Some('t') => string.push('\t'),
+                Some('u') => string.push(parse_unicode_escape(chars)?),
+                _ => return Err("Invalid escape in a string".to_string()),
+            },
+            Some(c) => string.push(c),
+            None => return Err("A string was never closed".to_string()),
+        }
+    }
+}
+
+// The bit after \u. Characters outside the BMP (eg. emoji) are written as two of these, a
+// UTF-16 surrogate pair.
+fn parse_unicode_escape(chars: &mut Input) -> Result<char, String> {
+    let first = parse_hex4(chars)?;
+    if !(0xD800..0xDC00).contains(&first) {
+        return Ok(char::from_u32(first).unwrap_or(char::REPLACEMENT_CHARACTER));
+    }
+
+    // We only take the next escape if it's the other half. Otherwise, it's left for the caller,
+    // and this half on its own is replaced.
+    let mut lookahead = chars.clone();
+    if lookahead.next() != Some('\\') || lookahead.next() != Some('u') {
+        return Ok(char::REPLACEMENT_CHARACTER);
+    }
+    let second = parse_hex4(&mut lookahead)?;
+    if !(0xDC00..0xE000).contains(&second) {
+        return Ok(char::REPLACEMENT_CHARACTER);
+    }
+    *chars = lookahead;
+    let code_point = 0x10000 + ((first - 0xD800) << 10) + (second - 0xDC00);
+    Ok(char::from_u32(code_point).unwrap_or(char::REPLACEMENT_CHARACTER))
+}
+
+fn parse_hex4(chars: &mut Input) -> Result<u32, String> {
+    let hex: String = chars.by_ref().take(4).collect();
+    // from_str_radix() would also take a sign, eg. +123
+    if hex.len() != 4 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("'{}' isn't a valid \\u escape", hex));
+    }
+    u32::from_str_radix(&hex, 16).map_err(|_| format!("'{}' isn't a valid \\u escape", hex))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(text: &str) -> JsonValue {
+        JsonValue::String(text.to_string())
+    }
+
+    #[test]
+    fn escapes() {
+        let parsed = JsonValue::parse(r#""\" \\ \/ \b \f \n \r \t""#).unwrap();
+        assert_eq!(parsed, string("\" \\ / \u{8} \u{c} \n \r \t"));
+        assert_eq!(
+            JsonValue::parse(r#""\u001b[0m""#).unwrap(),
+            string("\x1b[0m")
+        );
+        assert_eq!(JsonValue::parse(r#""é""#).unwrap(), string("é"));
+        assert!(JsonValue::parse(r#""\x""#).is_err());
+    }
+
+    #[test]
+    fn unicode_escapes_have_to_be_four_hex_digits() {
+        for text in [
+            r#""\u+123""#,
+            r#""\u-123""#,
+            r#""\u12g4""#,
+            r#""\u12""#,
+            r#""\u 123""#,
+        ] {
+            assert!(JsonValue::parse(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn surrogate_pairs() {
+        assert_eq!(JsonValue::parse(r#""😀""#).unwrap(), string("😀"));
+        // Half a pair on its own is replaced, without eating whatever comes after it
+        assert_eq!(
+            JsonValue::parse(r#""\ud83d!""#).unwrap(),
+            string("\u{fffd}!")
+        );
+        assert_eq!(
+            JsonValue::parse(r#""\ud83d\n""#).unwrap(),
+            string("\u{fffd}\n")
+        );
+        assert_eq!(
+            JsonValue::parse(r#""\ud83dA""#).unwrap(),
+            string("\u{fffd}A")
+        );
+        assert_eq!(JsonValue::parse(r#""\ude00""#).unwrap(), string("\u{fffd}"));
+    }
+
+    #[test]
+    fn quote_round_trips() {
+        let text = "\"quoted\" \\ \x1b[31mred\x1b[0m\r\n\t\u{7f} 😀";
+        assert_eq!(JsonValue::parse(&quote(text)).unwrap(), string(text));
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(JsonValue::parse("0").unwrap(), JsonValue::Number(0.0));
+        assert_eq!(JsonValue::parse("-12").unwrap(), JsonValue::Number(-12.0));
+        assert_eq!(JsonValue::parse("1.5").unwrap(), JsonValue::Number(1.5));
+        assert_eq!(JsonValue::parse("2e3").unwrap(), JsonValue::Number(2000.0));
+        assert_eq!(JsonValue::parse("1.5E-1").unwrap(), JsonValue::Number(0.15));
+        for text in ["-", "1-2", "1e", "1.2.3", "+1"] {
+            assert!(JsonValue::parse(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn an_asciicast_event() {
+        let parsed = JsonValue::parse(r#"[0.25, "o", "hi\r\n"]"#).unwrap();
+        let items = parsed.as_array().unwrap();
+        assert_eq!(items[0].as_f64(), Some(0.25));
+        assert_eq!(items[1].as_str(), Some("o"));
+        assert_eq!(items[2].as_str(), Some("hi\r\n"));
+    }
+
+    #[test]
+    fn objects_keep_their_order() {
+        let parsed = JsonValue::parse(r#" { "b": true, "a": [null, {}], "c": [] } "#).unwrap();
+        assert_eq!(
+            parsed,
+            JsonValue::Object(vec![
+                ("b".to_string(), JsonValue::Bool(true)),
+                (
+                    "a".to_string(),
+                    JsonValue::Array(vec![JsonValue::Null, JsonValue::Object(vec![])])
+                ),
+                ("c".to_string(), JsonValue::Array(vec![])),
+            ])
+        );
+        assert_eq!(parsed.get("b"), Some(&JsonValue::Bool(true)));
+        assert_eq!(parsed.get("missing"), None);
+    }
+
+    #[test]
+    fn nesting_limit() {
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(JsonValue::parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(JsonValue::parse(&nested(MAX_DEPTH + 1)).is_err());
+        let objects = format!(
+            "{}{}",
+            r#"{"a":"#.repeat(MAX_DEPTH + 1),
+            "}".repeat(MAX_DEPTH + 1)
+        );
+        assert!(JsonValue::parse(&objects).is_err());
+        // Deep enough to run out of stack if we recursed all the way down
+        assert!(JsonValue::parse(&"[".repeat(1_000_000)).is_err());
+    }
+
+    #[test]
+    fn malformed_input() {
+        for text in [
+            "",
+            "   ",
+            "[1, 2",
+            "[1 2]",
+            "[1,]",
+            "{\"a\" 1}",
+            "{\"a\": 1,}",
+            "{a: 1}",
+            "\"never closed",
+            "tru",
+            "nul",
+            "[] []",
+            "}",
+        ] {
+            assert!(JsonValue::parse(text).is_err(), "{:?}", text);
+        }
+    }
+}
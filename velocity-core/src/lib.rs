@@ -3,8 +3,19 @@ extern crate nix;
 #[macro_use]
 extern crate num_derive;
 
+pub mod asciicast;
+pub mod broadcast;
+pub mod command_line;
 pub mod constants;
+pub mod control;
 pub mod escape_sequence;
+pub mod json;
+pub mod local_echo;
+pub mod runtime_directory;
+pub mod server_protocol;
+pub mod session_log;
+pub mod shell_integration;
 pub mod shell_layer;
+pub mod terminfo;
 pub mod text_styles;
 pub mod tty;
@@ -0,0 +1,174 @@
+// Predictive local echo, like mosh does. Over a slow connection (eg. SSH to the other side of
+// the world), what you type only appears once it has been to the server and back. Instead, we
+// guess that printable keystrokes will be echoed where the cursor is, and draw them straight
+// away, underlined until the real echo arrives and confirms them. A wrong guess (eg. the program
+// printed something else there) throws all our guesses away, and the real screen shows through.
+//
+// Guesses are only drawn once the program has echoed one of the keystrokes since the last
+// non-printable one (eg. Enter). That way, typing a password at a prompt that doesn't echo never
+// shows up on the screen.
+use crate::tty::{CursorPosition, ScrollbackBufferType};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+// A guess that hasn't been confirmed by then is wrong, probably because the program isn't
+// echoing
+const PREDICTION_TIMEOUT: Duration = Duration::from_secs(3);
+// In Auto mode, we only draw guesses that have been waiting this long. A local shell echoes well
+// within it, so they never flicker on screen.
+const SLOW_ECHO_THRESHOLD: Duration = Duration::from_millis(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LocalEchoMode {
+    Off,
+    // Only when echo is slow, like mosh's "adaptive" mode
+    #[default]
+    Auto,
+    Always,
+}
+
+struct Prediction {
+    // Which line of the scrollback buffer it's on
+    line: usize,
+    x: usize,
+    char: char,
+    made_at: Instant,
+}
+
+pub(crate) struct LocalEcho {
+    mode: LocalEchoMode,
+    predictions: VecDeque<Prediction>,
+    // Whether the program has echoed something we predicted since the last non-printable
+    // keystroke. Until it has, we don't draw anything.
+    echo_confirmed: bool,
+}
+
+impl LocalEcho {
+    pub fn new() -> Self {
+        LocalEcho {
+            mode: LocalEchoMode::default(),
+            predictions: VecDeque::new(),
+            echo_confirmed: false,
+        }
+    }
+
+    pub fn mode(&self) -> LocalEchoMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: LocalEchoMode) {
+        self.mode = mode;
+        self.reset();
+    }
+
+    // Forgets every guess, eg. because the screen has changed under them
+    pub fn reset(&mut self) {
+        self.predictions.clear();
+        self.echo_confirmed = false;
+    }
+
+    // Called with what the user typed, before it's sent. line and x are where the real cursor is.
+    pub fn typed(&mut self, data: &[u8], line: usize, x: usize, cols: usize) {
+        if self.mode == LocalEchoMode::Off {
+            return;
+        }
+
+        // Backspace takes back the last guess, if it hasn't been echoed yet
+        if data == [0x7f] || data == [0x08] {
+            if self.predictions.pop_back().is_none() {
+                self.reset();
+            }
+            return;
+        }
+
+        let Ok(text) = std::str::from_utf8(data) else {
+            self.reset();
+            return;
+        };
+        if text.is_empty() || text.chars().any(|c| c.is_control()) {
+            // Enter, escape sequences, ^C and so on. We can't guess what those will do.
+            self.reset();
+            return;
+        }
+
+        let (line, mut x) = match self.predictions.back() {
+            Some(last) => (last.line, last.x + 1),
+            None => (line, x),
+        };
+        for c in text.chars() {
+            // We don't guess at wrapping, so we stop before the last column
+            if x + 1 >= cols {
+                return;
+            }
+            self.predictions.push_back(Prediction {
+                line,
+                x,
+                char: c,
+                made_at: Instant::now(),
+            });
+            x += 1;
+        }
+    }
+
+    // Checks our guesses against the real screen, after output has been applied. A guess is
+    // right if the cursor has gone past it and the real character there is the one we guessed.
+    pub fn check(&mut self, buffer: &ScrollbackBufferType, cursor_line: usize, cursor_x: usize) {
+        while let Some(prediction) = self.predictions.front() {
+            let cursor_has_passed = cursor_line > prediction.line
+                || (cursor_line == prediction.line && cursor_x > prediction.x);
+            if !cursor_has_passed {
+                if prediction.made_at.elapsed() > PREDICTION_TIMEOUT {
+                    self.reset();
+                }
+                return;
+            }
+
+            let real_char = buffer
+                .get(prediction.line)
+                .and_then(|line| line.get(prediction.x))
+                .map(|decorated_char| decorated_char.char);
+            if real_char != Some(prediction.char) {
+                self.reset();
+                return;
+            }
+            self.predictions.pop_front();
+            self.echo_confirmed = true;
+        }
+    }
+
+    // The guesses that should be drawn on a line of the scrollback buffer, as (x, char)
+    pub fn visible_predictions(&self, line: usize) -> impl Iterator<Item = (usize, char)> + '_ {
+        self.predictions
+            .iter()
+            .filter(move |prediction| prediction.line == line && self.is_visible(prediction))
+            .map(|prediction| (prediction.x, prediction.char))
+    }
+
+    // Where the cursor would be if our guesses were right, if any are being drawn
+    pub fn predicted_cursor(&self, scrollback_start: usize) -> Option<CursorPosition> {
+        let last = self
+            .predictions
+            .iter()
+            .rev()
+            .find(|prediction| self.is_visible(prediction))?;
+        if last.line < scrollback_start {
+            return None;
+        }
+        Some(CursorPosition {
+            x: last.x as isize + 1,
+            y: (last.line - scrollback_start) as isize,
+        })
+    }
+
+    fn is_visible(&self, prediction: &Prediction) -> bool {
+        match self.mode {
+            LocalEchoMode::Off => false,
+            LocalEchoMode::Always => self.echo_confirmed,
+            LocalEchoMode::Auto => {
+                self.echo_confirmed && prediction.made_at.elapsed() >= SLOW_ECHO_THRESHOLD
+            }
+        }
+    }
+}
@@ -0,0 +1,128 @@
+// Where our sockets and the broadcast registry live. Anyone who can get at those can see what's
+// typed into (or type into) our shells, so they have to be somewhere only we can get to.
+use nix::unistd::getuid;
+use std::{
+    env,
+    fs::{self, DirBuilder, Permissions},
+    io::{self, ErrorKind},
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+};
+
+// $XDG_RUNTIME_DIR is made for this, and only we can read it. Without it, we fall back to a
+// directory in /tmp. Its name is easy to guess, so someone else could have made it first,
+// which is why we check it's really ours before we use it.
+pub fn runtime_directory() -> io::Result<PathBuf> {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(directory) if !directory.is_empty() => Ok(PathBuf::from(directory)),
+        _ => {
+            let directory = PathBuf::from(format!("/tmp/velocity-{}", getuid()));
+            create_private_directory(&directory)?;
+            Ok(directory)
+        }
+    }
+}
+
+// Creates the directory (and its parents) if it isn't there. Either way, it has to belong to us,
+// with nobody else allowed in. mode() does nothing to a directory that already exists.
+pub fn create_private_directory(path: &Path) -> io::Result<()> {
+    DirBuilder::new().recursive(true).mode(0o700).create(path)?;
+
+    // Not following symlinks, because a link could point anywhere
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() || metadata.uid() != getuid().as_raw() || metadata.mode() & 0o777 != 0o700
+    {
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "{} should be a directory that only we can use (mode 700), but it isn't",
+                path.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Listens on a socket that only we can connect to, because anyone who can connect can type into
+// our shells. We chmod it after binding rather than changing the umask, which is shared by every
+// thread and would also apply to whatever they were creating at the time. That leaves a moment
+// when the socket has the usual permissions, so the default paths are all in
+// runtime_directory(), where nobody else can get to it anyway.
+pub fn bind_private_socket(path: &Path) -> io::Result<UnixListener> {
+    remove_stale_socket(path)?;
+    let listener = UnixListener::bind(path)?;
+    if let Err(err) = fs::set_permissions(path, Permissions::from_mode(0o600)) {
+        let _ = fs::remove_file(path);
+        return Err(err);
+    }
+    Ok(listener)
+}
+
+// A socket left behind by something that crashed would stop us from binding. We only remove it
+// if nobody answers, so that we can't steal a running program's address, and only if it really
+// is a socket, so that a path given by mistake can't delete someone's file.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} is already there, and isn't a socket", path.display()),
+        ));
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            ErrorKind::AddrInUse,
+            format!("Something is already listening on {}", path.display()),
+        ));
+    }
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_sockets() {
+        let directory =
+            env::temp_dir().join(format!("velocity-test-socket-{}", std::process::id()));
+        create_private_directory(&directory).unwrap();
+        let path = directory.join("test.sock");
+
+        let listener = bind_private_socket(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().mode() & 0o777, 0o600);
+        // Someone's listening, so it isn't stale
+        let err = bind_private_socket(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+
+        // Now it is
+        drop(listener);
+        bind_private_socket(&path).unwrap();
+
+        // Anything that isn't a socket is left alone
+        let file = directory.join("not-a-socket");
+        fs::write(&file, "precious").unwrap();
+        let err = bind_private_socket(&file).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "precious");
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn directories_others_can_get_into_are_refused() {
+        let directory = env::temp_dir().join(format!("velocity-test-open-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::set_permissions(&directory, Permissions::from_mode(0o755)).unwrap();
+        let err = create_private_directory(&directory).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,353 @@
+// How velocity-server and the windows attached to it (see ClientShellLayer) talk over the
+// server's Unix socket. Every message is a frame: one byte saying what kind of message it is,
+// the length of the rest as a big-endian u32, and then the rest.
+//
+// A client starts by sending Attach. The server answers with Attached (followed by Output that
+// repaints the screen as it is now) or Error, and from then on it's Input and Resize one way
+// and Output the other, until the session's program exits.
+use crate::runtime_directory::runtime_directory;
+use crate::shell_layer::{spawn_config::SpawnConfig, CellPixelSize, ChildExitStatus};
+use std::{
+    env,
+    io::{self, ErrorKind, Read, Write},
+    path::PathBuf,
+};
+
+// Anything bigger is a bug (or not one of our clients), rather than something to allocate for
+const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+const FRAME_HEADER_LENGTH: usize = 5;
+
+pub enum ClientMessage {
+    Attach(AttachRequest),
+    // Something the user typed
+    Input(Vec<u8>),
+    // The window changed size
+    Resize {
+        rows: usize,
+        cols: usize,
+        cell_pixel_size: CellPixelSize,
+    },
+}
+
+pub struct AttachRequest {
+    // Sessions are created the first time someone attaches to their name
+    pub session_name: String,
+    // Read-only clients can watch, but their typing and resizing is ignored. They can't create
+    // sessions.
+    pub read_only: bool,
+    pub rows: usize,
+    pub cols: usize,
+    pub cell_pixel_size: CellPixelSize,
+    // How to start the session's program, if it doesn't exist yet
+    pub spawn_config: SpawnConfig,
+}
+
+pub enum ServerMessage {
+    Attached,
+    // Something the session's program printed
+    Output(Vec<u8>),
+    // The session's program has finished, so the session is over
+    Exited(ChildExitStatus),
+    // We couldn't attach, eg. a read-only client asked for a session that doesn't exist
+    Error(String),
+}
+
+impl ClientMessage {
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut payload = Payload::new();
+        let kind = match self {
+            ClientMessage::Attach(request) => {
+                payload.put_str(&request.session_name);
+                payload.put_u8(request.read_only as u8);
+                payload.put_size(request.rows, request.cols, request.cell_pixel_size);
+                let spawn_config = &request.spawn_config;
+                payload.put_u8(spawn_config.login_shell as u8);
+                match &spawn_config.command {
+                    Some(command) => {
+                        payload.put_u8(1);
+                        payload.put_u32(command.len() as u32);
+                        for arg in command {
+                            payload.put_str(arg);
+                        }
+                    }
+                    None => payload.put_u8(0),
+                }
+                match &spawn_config.working_directory {
+                    Some(directory) => {
+                        payload.put_u8(1);
+                        payload.put_str(&directory.to_string_lossy());
+                    }
+                    None => payload.put_u8(0),
+                }
+                payload.put_u8(spawn_config.shell_integration as u8);
+                b'A'
+            }
+            ClientMessage::Input(data) => {
+                payload.put_bytes(data);
+                b'I'
+            }
+            ClientMessage::Resize {
+                rows,
+                cols,
+                cell_pixel_size,
+            } => {
+                payload.put_size(*rows, *cols, *cell_pixel_size);
+                b'R'
+            }
+        };
+        write_frame(writer, kind, &payload.0)
+    }
+
+    // Blocks until a whole message arrives. None means the client hung up.
+    pub fn read_from(reader: &mut impl Read) -> io::Result<Option<Self>> {
+        let Some((kind, payload)) = read_frame(reader)? else {
+            return Ok(None);
+        };
+        let mut payload = PayloadReader(&payload);
+        let message = match kind {
+            b'A' => {
+                let session_name = payload.get_str()?;
+                let read_only = payload.get_u8()? != 0;
+                let (rows, cols, cell_pixel_size) = payload.get_size()?;
+                let login_shell = payload.get_u8()? != 0;
+                let command = match payload.get_u8()? {
+                    0 => None,
+                    _ => {
+                        let length = payload.get_u32()?;
+                        let mut command = vec![];
+                        for _ in 0..length {
+                            command.push(payload.get_str()?);
+                        }
+                        Some(command)
+                    }
+                };
+                let working_directory = match payload.get_u8()? {
+                    0 => None,
+                    _ => Some(PathBuf::from(payload.get_str()?)),
+                };
+                let shell_integration = payload.get_u8()? != 0;
+                ClientMessage::Attach(AttachRequest {
+                    session_name,
+                    read_only,
+                    rows,
+                    cols,
+                    cell_pixel_size,
+                    spawn_config: SpawnConfig {
+                        login_shell,
+                        command,
+                        working_directory,
+                        // The session outlives the window that started it, so it doesn't get
+                        // that window's control socket
+                        listen_socket: None,
+                        shell_integration,
+                    },
+                })
+            }
+            b'I' => ClientMessage::Input(payload.0.to_vec()),
+            b'R' => {
+                let (rows, cols, cell_pixel_size) = payload.get_size()?;
+                ClientMessage::Resize {
+                    rows,
+                    cols,
+                    cell_pixel_size,
+                }
+            }
+            _ => return Err(invalid_data(format!("Unknown client message '{}'", kind))),
+        };
+        Ok(Some(message))
+    }
+}
+
+impl ServerMessage {
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut payload = Payload::new();
+        let kind = match self {
+            ServerMessage::Attached => b'a',
+            ServerMessage::Output(data) => {
+                payload.put_bytes(data);
+                b'o'
+            }
+            ServerMessage::Exited(status) => {
+                let (how, value) = match status {
+                    ChildExitStatus::Exited(code) => (0, *code),
+                    ChildExitStatus::Signalled(signal) => (1, *signal),
+                    ChildExitStatus::Unknown => (2, 0),
+                };
+                payload.put_u8(how);
+                payload.put_u32(value as u32);
+                b'x'
+            }
+            ServerMessage::Error(message) => {
+                payload.put_str(message);
+                b'e'
+            }
+        };
+        write_frame(writer, kind, &payload.0)
+    }
+
+    // Blocks until a whole message arrives. None means the server hung up.
+    pub fn read_from(reader: &mut impl Read) -> io::Result<Option<Self>> {
+        match read_frame(reader)? {
+            Some((kind, payload)) => Ok(Some(Self::decode(kind, &payload)?)),
+            None => Ok(None),
+        }
+    }
+
+    // For clients that read whatever has arrived without blocking. Takes the first message off
+    // the front of the buffer, if all of it is there.
+    pub fn take_from_buffer(buffer: &mut Vec<u8>) -> io::Result<Option<Self>> {
+        if buffer.len() < FRAME_HEADER_LENGTH {
+            return Ok(None);
+        }
+        let length = frame_length(&buffer[1..FRAME_HEADER_LENGTH])?;
+        if buffer.len() < FRAME_HEADER_LENGTH + length {
+            return Ok(None);
+        }
+        let frame: Vec<u8> = buffer.drain(..FRAME_HEADER_LENGTH + length).collect();
+        Ok(Some(Self::decode(frame[0], &frame[FRAME_HEADER_LENGTH..])?))
+    }
+
+    fn decode(kind: u8, payload: &[u8]) -> io::Result<Self> {
+        let mut payload = PayloadReader(payload);
+        Ok(match kind {
+            b'a' => ServerMessage::Attached,
+            b'o' => ServerMessage::Output(payload.0.to_vec()),
+            b'x' => {
+                let how = payload.get_u8()?;
+                let value = payload.get_u32()? as i32;
+                ServerMessage::Exited(match how {
+                    0 => ChildExitStatus::Exited(value),
+                    1 => ChildExitStatus::Signalled(value),
+                    _ => ChildExitStatus::Unknown,
+                })
+            }
+            b'e' => ServerMessage::Error(payload.get_str()?),
+            _ => return Err(invalid_data(format!("Unknown server message '{}'", kind))),
+        })
+    }
+}
+
+// Where the server listens unless it's told otherwise. It's in a directory only we can get into
+// (see runtime_directory.rs), which matters because anyone who can connect can type into your
+// shells, and anyone who listens there first gets everything that clients type.
+pub fn default_socket_path() -> io::Result<PathBuf> {
+    if let Some(path) = env::var_os("VELOCITY_SERVER_SOCKET") {
+        return Ok(PathBuf::from(path));
+    }
+    Ok(runtime_directory()?.join("velocity-server.sock"))
+}
+
+fn write_frame(writer: &mut impl Write, kind: u8, payload: &[u8]) -> io::Result<()> {
+    // One write per message, so that messages from different threads can't interleave
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LENGTH + payload.len());
+    frame.push(kind);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+fn read_frame(reader: &mut impl Read) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut header = [0; FRAME_HEADER_LENGTH];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let mut payload = vec![0; frame_length(&header[1..])?];
+    reader.read_exact(&mut payload)?;
+    Ok(Some((header[0], payload)))
+}
+
+fn frame_length(bytes: &[u8]) -> io::Result<usize> {
+    let length = u32::from_be_bytes(bytes.try_into().unwrap()) as usize;
+    if length > MAX_FRAME_LENGTH {
+        return Err(invalid_data(format!(
+            "A {} byte message is too big",
+            length
+        )));
+    }
+    Ok(length)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+struct Payload(Vec<u8>);
+
+impl Payload {
+    fn new() -> Self {
+        Payload(vec![])
+    }
+
+    fn put_u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn put_u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn put_u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    // Only for the last thing in a message, because it isn't length-prefixed
+    fn put_bytes(&mut self, data: &[u8]) {
+        self.0.extend_from_slice(data);
+    }
+
+    fn put_str(&mut self, text: &str) {
+        self.put_u32(text.len() as u32);
+        self.0.extend_from_slice(text.as_bytes());
+    }
+
+    fn put_size(&mut self, rows: usize, cols: usize, cell_pixel_size: CellPixelSize) {
+        self.put_u16(rows as u16);
+        self.put_u16(cols as u16);
+        self.put_u16(cell_pixel_size.width);
+        self.put_u16(cell_pixel_size.height);
+    }
+}
+
+struct PayloadReader<'a>(&'a [u8]);
+
+impl PayloadReader<'_> {
+    fn take(&mut self, count: usize) -> io::Result<&[u8]> {
+        if self.0.len() < count {
+            return Err(invalid_data("A message was cut short".to_string()));
+        }
+        let (taken, rest) = self.0.split_at(count);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn get_u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn get_u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn get_u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn get_str(&mut self) -> io::Result<String> {
+        let length = self.get_u32()? as usize;
+        let bytes = self.take(length)?;
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    fn get_size(&mut self) -> io::Result<(usize, usize, CellPixelSize)> {
+        let rows = self.get_u16()? as usize;
+        let cols = self.get_u16()? as usize;
+        let cell_pixel_size = CellPixelSize {
+            width: self.get_u16()?,
+            height: self.get_u16()?,
+        };
+        Ok((rows, cols, cell_pixel_size))
+    }
+}
@@ -0,0 +1,233 @@
+// Writes a transcript of the session to disk, eg. so that there's a record of what was done on
+// a server for an audit. Unlike an asciicast recording (see asciicast.rs), it's meant to be read
+// with less or grep rather than replayed.
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    mem,
+    path::{Path, PathBuf},
+    ptr,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionLogMode {
+    // Exactly the bytes the program printed, escape sequences and all. `cat` it into a terminal
+    // to see it again.
+    Raw,
+    // The text on each line, written once the line has scrolled off the top of the screen (and
+    // the rest of the screen when the session ends). There are no escape sequences in it.
+    PlainText,
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionLogConfig {
+    pub mode: SessionLogMode,
+    pub directory: PathBuf,
+    // The log's name in the directory. These are filled in:
+    //   {date}  eg. 2023-06-01
+    //   {time}  eg. 134502
+    //   {pid}   the shell's process ID
+    //   {tty}   the shell's terminal device, eg. pts-3
+    pub filename_template: String,
+    // Starts every line with the local time, eg. [2023-06-01 13:45:02]
+    pub timestamps: bool,
+    // When the log gets this big, we carry on in a new file. The first is velocity-....log, and
+    // the next is velocity-....1.log, and so on.
+    pub max_file_size: Option<u64>,
+}
+
+impl SessionLogConfig {
+    pub fn new(directory: PathBuf) -> Self {
+        SessionLogConfig {
+            mode: SessionLogMode::PlainText,
+            directory,
+            filename_template: "velocity-{date}-{time}-{pid}.log".to_string(),
+            timestamps: false,
+            max_file_size: None,
+        }
+    }
+}
+
+pub struct SessionLogger {
+    config: SessionLogConfig,
+    // Where the first file went. Rotated files are named after it.
+    first_path: PathBuf,
+    path: PathBuf,
+    file: File,
+    file_size: u64,
+    rotations: usize,
+    // Whether the next byte we write starts a line, so needs a timestamp
+    at_line_start: bool,
+}
+
+impl SessionLogger {
+    pub fn new(
+        config: SessionLogConfig,
+        shell_pid: Option<i32>,
+        tty_path: Option<&Path>,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(&config.directory)?;
+        let filename = fill_in_template(&config.filename_template, shell_pid, tty_path);
+        let path = config.directory.join(filename);
+        let (file, file_size) = open_for_appending(&path)?;
+
+        Ok(SessionLogger {
+            config,
+            first_path: path.clone(),
+            path,
+            file,
+            file_size,
+            rotations: 0,
+            at_line_start: true,
+        })
+    }
+
+    pub fn mode(&self) -> SessionLogMode {
+        self.config.mode
+    }
+
+    // The file we're writing to at the moment
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Something the program printed. Only raw logs want this.
+    pub fn output(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.config.mode != SessionLogMode::Raw {
+            return Ok(());
+        }
+        self.write(data)
+    }
+
+    // A line of text that's done with, eg. because it scrolled off the screen. Only plain text
+    // logs want this.
+    pub fn line(&mut self, text: &str) -> io::Result<()> {
+        if self.config.mode != SessionLogMode::PlainText {
+            return Ok(());
+        }
+        self.write(format!("{}\n", text).as_bytes())
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if let Some(max_file_size) = self.config.max_file_size {
+            // We only rotate between lines, so that no line is split across two files
+            if self.file_size >= max_file_size && self.at_line_start {
+                self.rotate()?;
+            }
+        }
+
+        let mut to_write = Vec::with_capacity(data.len());
+        if !self.config.timestamps {
+            to_write.extend_from_slice(data);
+        } else {
+            let timestamp = format!("[{}] ", LocalTime::now().date_and_time());
+            for b in data {
+                if self.at_line_start {
+                    to_write.extend_from_slice(timestamp.as_bytes());
+                }
+                to_write.push(*b);
+                self.at_line_start = *b == b'\n';
+            }
+        }
+        if let Some(last) = data.last() {
+            self.at_line_start = *last == b'\n';
+        }
+
+        // Written straight away (File isn't buffered), so that the log is complete even if we
+        // crash
+        self.file.write_all(&to_write)?;
+        self.file_size += to_write.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.rotations += 1;
+        let path = numbered_path(&self.first_path, self.rotations);
+        let (file, file_size) = open_for_appending(&path)?;
+        println!("Session log is now {}", path.display());
+        self.path = path;
+        self.file = file;
+        self.file_size = file_size;
+        Ok(())
+    }
+}
+
+// Where logs go if the user didn't say, eg. ~/velocity-logs
+pub fn default_log_directory() -> PathBuf {
+    let directory = env::var("HOME").unwrap_or(".".to_string());
+    PathBuf::from(directory).join("velocity-logs")
+}
+
+fn open_for_appending(path: &Path) -> io::Result<(File, u64)> {
+    // Appending means that a template without {time} in it (eg. one log per day) still works
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let file_size = file.metadata()?.len();
+    Ok((file, file_size))
+}
+
+// Eg. velocity.log and 2 becomes velocity.2.log
+fn numbered_path(path: &Path, number: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let filename = match path.extension() {
+        Some(extension) => format!("{}.{}.{}", stem, number, extension.to_string_lossy()),
+        None => format!("{}.{}", stem, number),
+    };
+    path.with_file_name(filename)
+}
+
+fn fill_in_template(template: &str, shell_pid: Option<i32>, tty_path: Option<&Path>) -> String {
+    let now = LocalTime::now();
+    let pid = shell_pid.map(|pid| pid.to_string());
+    // /dev/pts/3 becomes pts-3, because slashes would make directories
+    let tty = tty_path.map(|path| {
+        let path = path.to_string_lossy();
+        path.trim_start_matches("/dev/").replace('/', "-")
+    });
+
+    template
+        .replace("{date}", &now.date())
+        .replace("{time}", &now.time())
+        .replace("{pid}", pid.as_deref().unwrap_or("unknown"))
+        .replace("{tty}", tty.as_deref().unwrap_or("notty"))
+}
+
+// The standard library doesn't know about time zones, so we ask libc what the time is here
+struct LocalTime(libc::tm);
+
+impl LocalTime {
+    fn now() -> Self {
+        unsafe {
+            let now = libc::time(ptr::null_mut());
+            let mut tm: libc::tm = mem::zeroed();
+            libc::localtime_r(&now, &mut tm);
+            LocalTime(tm)
+        }
+    }
+
+    fn date(&self) -> String {
+        format!(
+            "{:04}-{:02}-{:02}",
+            self.0.tm_year + 1900,
+            self.0.tm_mon + 1,
+            self.0.tm_mday
+        )
+    }
+
+    fn time(&self) -> String {
+        format!(
+            "{:02}{:02}{:02}",
+            self.0.tm_hour, self.0.tm_min, self.0.tm_sec
+        )
+    }
+
+    fn date_and_time(&self) -> String {
+        format!(
+            "{} {:02}:{:02}:{:02}",
+            self.date(),
+            self.0.tm_hour,
+            self.0.tm_min,
+            self.0.tm_sec
+        )
+    }
+}
@@ -0,0 +1,151 @@
+// Shell integration, which gets zsh, bash and fish to mark where prompts and commands start and
+// end (OSC 133) and tell us their working directory (OSC 7), without the user having to change
+// their own startup files.
+//
+// The scripts are bundled into the binary and written out to
+//   $XDG_DATA_HOME/velocity/shell-integration
+// Then, when we spawn one of those shells, we get it to read them as it starts:
+//   zsh  - $ZDOTDIR points at our .zshenv, which puts $ZDOTDIR back and reads the user's files
+//   bash - --rcfile, which reads the user's .bashrc (or their profile, for a login shell)
+//   fish - we add our directory to $XDG_DATA_DIRS, which fish searches for vendor_conf.d
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+// Setting this to "off" (eg. in a launcher for one particular setup) is the same as
+// --no-shell-integration
+pub const SHELL_INTEGRATION_ENV_VAR: &str = "VELOCITY_SHELL_INTEGRATION";
+
+// (Path in the installed directory, contents)
+const SCRIPTS: [(&str, &str); 4] = [
+    (
+        "zsh/.zshenv",
+        include_str!("../shell-integration/zsh/.zshenv"),
+    ),
+    (
+        "zsh/velocity-integration.zsh",
+        include_str!("../shell-integration/zsh/velocity-integration.zsh"),
+    ),
+    (
+        "bash/velocity.bash",
+        include_str!("../shell-integration/bash/velocity.bash"),
+    ),
+    (
+        "fish/vendor_conf.d/velocity-integration.fish",
+        include_str!("../shell-integration/fish/vendor_conf.d/velocity-integration.fish"),
+    ),
+];
+
+// Where fish looks when $XDG_DATA_DIRS isn't set
+const DEFAULT_XDG_DATA_DIRS: &str = "/usr/local/share:/usr/share";
+
+// How to start a particular shell so that it reads our script
+pub struct ShellIntegration {
+    // Go after argv[0]
+    pub args: Vec<String>,
+    // On top of the usual environment, as "KEY=value"
+    pub env_vars: Vec<String>,
+    // bash can't read our script as a login shell. It has to be started as a normal one, and the
+    // script does what a login shell would have done.
+    pub emulates_login_shell: bool,
+}
+
+// Whether the user has turned shell integration off in the environment
+pub fn is_disabled_by_env() -> bool {
+    matches!(
+        env::var(SHELL_INTEGRATION_ENV_VAR).as_deref(),
+        Ok("off" | "0" | "no" | "false")
+    )
+}
+
+// None if we don't have a script for this shell (or couldn't install them)
+pub fn shell_integration(shell_path: &str, login_shell: bool) -> Option<ShellIntegration> {
+    let name = Path::new(shell_path).file_name()?.to_string_lossy();
+    let name = name.trim_start_matches('-');
+    let directory = installed_directory()?;
+
+    match name {
+        "zsh" => {
+            let mut env_vars = vec![format!("ZDOTDIR={}", directory.join("zsh").display())];
+            // Our .zshenv puts it back
+            if let Ok(zdotdir) = env::var("ZDOTDIR") {
+                env_vars.push(format!("VELOCITY_ZSH_ZDOTDIR={}", zdotdir));
+            }
+            Some(ShellIntegration {
+                args: vec![],
+                env_vars,
+                emulates_login_shell: false,
+            })
+        }
+        "bash" => {
+            let mut env_vars = vec![];
+            if login_shell {
+                env_vars.push("VELOCITY_BASH_LOGIN=1".to_string());
+            }
+            Some(ShellIntegration {
+                args: vec![
+                    "--rcfile".to_string(),
+                    directory.join("bash/velocity.bash").display().to_string(),
+                ],
+                env_vars,
+                emulates_login_shell: login_shell,
+            })
+        }
+        "fish" => {
+            let original = env::var("XDG_DATA_DIRS").ok();
+            let data_dirs = original.as_deref().unwrap_or(DEFAULT_XDG_DATA_DIRS);
+            let mut env_vars = vec![format!(
+                "XDG_DATA_DIRS={}:{}",
+                directory.join("fish").display(),
+                data_dirs
+            )];
+            // Our script puts it back
+            if let Some(original) = original {
+                env_vars.push(format!("VELOCITY_FISH_XDG_DATA_DIRS={}", original));
+            }
+            Some(ShellIntegration {
+                args: vec![],
+                env_vars,
+                emulates_login_shell: false,
+            })
+        }
+        _ => None,
+    }
+}
+
+// Writes the scripts out, if they aren't already. We only do this once per run.
+fn installed_directory() -> Option<&'static Path> {
+    static DIRECTORY: OnceLock<Option<PathBuf>> = OnceLock::new();
+    DIRECTORY
+        .get_or_init(|| {
+            let directory = data_directory()?.join("velocity").join("shell-integration");
+            for (name, contents) in SCRIPTS {
+                let path = directory.join(name);
+                // Left alone if it's up to date, so we aren't rewriting it for every window
+                if fs::read_to_string(&path).ok().as_deref() == Some(contents) {
+                    continue;
+                }
+                let written = fs::create_dir_all(path.parent().unwrap())
+                    .and_then(|_| fs::write(&path, contents));
+                if let Err(err) = written {
+                    println!(
+                        "Couldn't install shell integration to {}: {}",
+                        path.display(),
+                        err
+                    );
+                    return None;
+                }
+            }
+            Some(directory)
+        })
+        .as_deref()
+}
+
+fn data_directory() -> Option<PathBuf> {
+    match env::var_os("XDG_DATA_HOME") {
+        Some(directory) if !directory.is_empty() => Some(PathBuf::from(directory)),
+        _ => env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("share")),
+    }
+}
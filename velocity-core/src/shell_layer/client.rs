@@ -0,0 +1,214 @@
+use super::{CellPixelSize, ShellLayer, ShellLayerEvent};
+use crate::constants::*;
+use crate::server_protocol::{AttachRequest, ClientMessage, ServerMessage};
+
+use nix::poll::{poll, PollFd, PollFlags};
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind, Read},
+    os::{fd::AsRawFd, unix::net::UnixStream},
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+// A ShellLayer for a session that lives in velocity-server rather than in this process. The
+// shell survives the window being closed (or crashing), and you can attach to it again later,
+// from as many windows as you like.
+pub struct ClientShellLayer {
+    stream: UnixStream,
+    read_only: bool,
+    // Bytes from the server that aren't a whole message yet
+    incoming: Vec<u8>,
+    // Output we've received that hasn't been read() yet
+    pending: VecDeque<u8>,
+    events: VecDeque<ShellLayerEvent>,
+    // The session is over, or the server has gone away. Either way, nothing more is coming.
+    finished: bool,
+    exited: bool,
+}
+
+impl ShellLayer for ClientShellLayer {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            if self.exited {
+                return Ok(0);
+            }
+            if self.finished {
+                // Wait like we would have in poll() so that we don't spin between frames
+                thread::sleep(Duration::from_millis(FD_POLL_TIMEOUT_MS as u64));
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            self.receive(FD_POLL_TIMEOUT_MS);
+            if self.pending.is_empty() {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+        }
+
+        let count = self.pending.len().min(buffer.len());
+        for (i, b) in self.pending.drain(..count).enumerate() {
+            buffer[i] = b;
+        }
+        Ok(count)
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        // Viewers can look, but not touch
+        if self.read_only || self.finished {
+            return;
+        }
+        self.send(ClientMessage::Input(data.to_vec()));
+    }
+
+    // The session's own TtyState in velocity-server has already answered
+    fn write_response(&mut self, _data: &[u8]) {}
+
+    fn resized(&mut self, new_rows: usize, new_cols: usize, cell_pixel_size: CellPixelSize) {
+        // The session stays the size that its writers want
+        if self.read_only || self.finished {
+            return;
+        }
+        self.send(ClientMessage::Resize {
+            rows: new_rows,
+            cols: new_cols,
+            cell_pixel_size,
+        });
+    }
+
+    fn next_event(&mut self) -> Option<ShellLayerEvent> {
+        self.events.pop_front()
+    }
+
+    fn has_data_ready(&mut self) -> bool {
+        if self.pending.is_empty() && !self.finished {
+            self.receive(0);
+        }
+        !self.pending.is_empty()
+    }
+
+    fn wait_for_data(&mut self, timeout: Duration) -> bool {
+        if !self.pending.is_empty() {
+            return true;
+        }
+        if self.finished {
+            // Nothing more is coming, but frontends that use us to pace their frames (eg. with
+            // --hold) would spin if we didn't wait
+            thread::sleep(timeout);
+            return false;
+        }
+        self.receive(timeout.as_millis() as i32);
+        !self.pending.is_empty()
+    }
+}
+
+impl ClientShellLayer {
+    // Connects to the server and joins the session, which is created if it doesn't exist yet.
+    // The first output is a repaint of whatever is on the session's screen right now.
+    pub fn attach(socket_path: &Path, request: AttachRequest) -> io::Result<Self> {
+        let mut stream = UnixStream::connect(socket_path).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!(
+                    "Couldn't connect to velocity-server at {} (is it running?): {}",
+                    socket_path.display(),
+                    err
+                ),
+            )
+        })?;
+        let read_only = request.read_only;
+        ClientMessage::Attach(request).write_to(&mut stream)?;
+
+        match ServerMessage::read_from(&mut stream)? {
+            Some(ServerMessage::Attached) => {}
+            Some(ServerMessage::Error(message)) => return Err(io::Error::other(message)),
+            Some(_) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "The server didn't answer our attach request",
+                ))
+            }
+            None => return Err(ErrorKind::UnexpectedEof.into()),
+        }
+
+        Ok(ClientShellLayer {
+            stream,
+            read_only,
+            incoming: vec![],
+            pending: VecDeque::new(),
+            events: VecDeque::new(),
+            finished: false,
+            exited: false,
+        })
+    }
+
+    // Waits up to timeout_ms for the server to send something, and takes in everything it has
+    fn receive(&mut self, timeout_ms: i32) {
+        let mut poll_fds = [PollFd::new(self.stream.as_raw_fd(), PollFlags::POLLIN)];
+        match poll(&mut poll_fds, timeout_ms) {
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(err) => {
+                println!("Failed to poll the server connection: {}", err);
+                return;
+            }
+        }
+
+        let mut buffer = [0; FD_BUFFER_SIZE_BYTES];
+        match self.stream.read(&mut buffer) {
+            Ok(0) => {
+                println!("velocity-server hung up");
+                self.lost_server();
+                return;
+            }
+            Ok(n) => self.incoming.extend_from_slice(&buffer[..n]),
+            Err(err) if err.kind() == ErrorKind::Interrupted => return,
+            Err(err) => {
+                println!("Failed to read from velocity-server: {}", err);
+                self.lost_server();
+                return;
+            }
+        }
+
+        loop {
+            match ServerMessage::take_from_buffer(&mut self.incoming) {
+                Ok(Some(ServerMessage::Output(data))) => self.pending.extend(data),
+                Ok(Some(ServerMessage::Exited(status))) => {
+                    self.finished = true;
+                    self.exited = true;
+                    self.events
+                        .push_back(ShellLayerEvent::ChildExited { status });
+                }
+                Ok(Some(ServerMessage::Error(message))) => {
+                    println!("velocity-server says: {}", message)
+                }
+                Ok(Some(ServerMessage::Attached)) => {}
+                Ok(None) => break,
+                Err(err) => {
+                    println!(
+                        "velocity-server sent something we don't understand: {}",
+                        err
+                    );
+                    self.lost_server();
+                    break;
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, message: ClientMessage) {
+        if let Err(err) = message.write_to(&mut self.stream) {
+            println!("Failed to send to velocity-server: {}", err);
+            self.lost_server();
+        }
+    }
+
+    // Unlike the session exiting, the session might still be alive without us (eg. if only our
+    // connection broke), so we don't close the window
+    fn lost_server(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        self.events.push_back(ShellLayerEvent::Disconnected);
+    }
+}
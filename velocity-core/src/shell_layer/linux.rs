@@ -1,101 +1,401 @@
 // Written for Pop!_OS. May not work elsewhere
-use super::ShellLayer;
+use super::spawn_config::{
+    child_env_vars, exec_candidates, working_directory_candidates, ExecFailurePipe, PreparedExec,
+    SpawnConfig,
+};
+use super::{pty_winsize, CellPixelSize, ChildExitStatus, ShellLayer, ShellLayerEvent};
 use crate::constants::*;
 
-use libc::{c_void, execle, winsize};
 use nix::{
+    errno::Errno,
+    fcntl::{fcntl, FcntlArg, OFlag},
     poll::{poll, PollFd, PollFlags},
     pty::{openpty, OpenptyResult},
-    unistd::{close, dup2, fork, setsid, ForkResult},
+    sys::signal::{kill, killpg, Signal},
+    sys::termios::{tcgetattr, LocalFlags},
+    sys::wait::{waitpid, WaitPidFlag, WaitStatus},
+    unistd::{close, dup2, fork, setsid, tcgetpgrp, ttyname, write, ForkResult, Pid},
 };
 use std::{
-    env,
-    ffi::CString,
-    fs::File,
-    io::{Read, Write},
-    os::fd::FromRawFd,
-    ptr,
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, ErrorKind, Read},
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd},
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
 };
 
 mod ioctl {
     // This macro generates a function definition - it doesn't actually *do* the ioctl
     ioctl_none_bad!(tiocsctty, libc::TIOCSCTTY);
+    // This is for letting the kernel TTY driver know that we've reisized the GUI window
+    ioctl_write_ptr_bad!(tiocswinsz, libc::TIOCSWINSZ, libc::winsize);
 }
 
 pub struct LinuxShellLayer {
     pty_result: OpenptyResult,
     fd_drained: bool,
     master_fd_file: Option<File>,
+    child_pid: Pid,
+    // Eg. /dev/pts/3, if we could find out
+    tty_path: Option<PathBuf>,
+    // Set once the slave side of the pty has been closed by everyone on the other end
+    hung_up: bool,
+    exit_status: Option<ChildExitStatus>,
+    exit_reported: bool,
+    // See set_nonblocking()
+    nonblocking: bool,
+    // Input that the child wasn't ready for yet. We send it as the fd becomes writable.
+    write_queue: VecDeque<u8>,
 }
 
 impl ShellLayer for LinuxShellLayer {
-    fn read(&mut self, buffer: &mut [u8; FD_BUFFER_SIZE_BYTES], written: &mut usize) {
-        // TODO: We can also poll() for POLLNVAL to check that the file descriptor is still open.
-        //   We need to do this to check if the child process died (eg. shell did 'exit')
-        //   https://stackoverflow.com/a/12340730/7674702
-        let poll_fd = PollFd::new(self.pty_result.master, PollFlags::POLLIN);
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.hung_up {
+            // There's nothing left to read, we're just waiting for the child to be reapable.
+            // Sleep for the poll timeout so that we don't spin the CPU between frames.
+            self.try_reap_child();
+            if self.exit_status.is_none() && !self.nonblocking {
+                thread::sleep(Duration::from_millis(FD_POLL_TIMEOUT_MS as u64));
+            }
+            return Ok(0);
+        }
+
+        // We might have room to send some queued up input since last time
+        self.flush_pending_writes();
 
-        if self.fd_drained {
+        // In non-blocking mode, whoever is calling us has already polled the fd themselves
+        if self.fd_drained && !self.nonblocking {
             // Poll until the fd is ready to read again
             // This allows us to keep drawing frames and maintain a responsive
             // window even though reading the file is blocked.
-            let poll_result = poll(&mut [poll_fd], FD_POLL_TIMEOUT_MS).unwrap();
+            // If we have input queued up, we also want to wake up when we can send it.
+            let mut poll_flags = PollFlags::POLLIN;
+            if !self.write_queue.is_empty() {
+                poll_flags |= PollFlags::POLLOUT;
+            }
+            let poll_fd = PollFd::new(self.pty_result.master, poll_flags);
+            let mut poll_fds = [poll_fd];
+            let poll_result = poll(&mut poll_fds, FD_POLL_TIMEOUT_MS)?;
             if poll_result == 0 {
                 // We timed out, now is the time to draw a frame
-                // We didn't actually read any data, so we'll send a zero
-                *written = 0;
-                return;
+                return Err(ErrorKind::WouldBlock.into());
             }
-            if poll_result == -1 {
-                panic!("Poll failed");
+
+            // When every process has closed the slave side (eg. the shell did 'exit'), the
+            // kernel tells us with POLLHUP. There may still be some output left to read
+            // though, in which case POLLIN is set as well.
+            let revents = poll_fds[0].revents().unwrap_or(PollFlags::empty());
+            if !revents.contains(PollFlags::POLLIN)
+                && revents.intersects(PollFlags::POLLHUP | PollFlags::POLLNVAL | PollFlags::POLLERR)
+            {
+                self.handle_hang_up();
+                return Ok(0);
             }
-        }
 
-        let read_count = self.get_master_file().read(buffer).unwrap();
+            if revents.contains(PollFlags::POLLOUT) {
+                self.flush_pending_writes();
+            }
+            if !revents.contains(PollFlags::POLLIN) {
+                // We only woke up to write, there's nothing to read yet
+                return Err(ErrorKind::WouldBlock.into());
+            }
+        }
 
-        // TODO: Should we be pooling these up and sending them when the fd is drained?
-        //   Currently, we'll be framerate limited when a program is printing more than 4K chars
-        //   per 16ms, which is not a low threshold.
-        *written = read_count;
+        let read_count = match self.get_master_file().read(buffer) {
+            Ok(0) => {
+                self.handle_hang_up();
+                return Ok(0);
+            }
+            Ok(n) => n,
+            // Linux gives us EIO when reading a pty whose slave side has been closed
+            Err(err) if err.raw_os_error() == Some(libc::EIO) => {
+                self.handle_hang_up();
+                return Ok(0);
+            }
+            // The fd is always O_NONBLOCK, so we can get this if there's nothing there after all
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                self.fd_drained = true;
+                return Err(err);
+            }
+            Err(err) => return Err(err),
+        };
 
-        self.fd_drained = read_count != FD_BUFFER_SIZE_BYTES;
+        self.fd_drained = read_count != buffer.len();
+        Ok(read_count)
     }
 
     fn write(&mut self, data: &[u8]) {
-        self.get_master_file().write_all(data).unwrap();
+        // Nobody is listening any more. Writing would just give us EIO.
+        if self.hung_up {
+            return;
+        }
+        // We never block on writing. If the child isn't reading (eg. we've pasted loads of text
+        // into it) we'd freeze the whole window, or even deadlock if it's waiting for us to read
+        // its output. So whatever doesn't fit now is sent later.
+        self.write_queue.extend(data);
+        self.flush_pending_writes();
+    }
+
+    fn flush_pending_writes(&mut self) {
+        while !self.write_queue.is_empty() {
+            let queued = self.write_queue.make_contiguous();
+            match write(self.pty_result.master, queued) {
+                Ok(written) => drop(self.write_queue.drain(..written)),
+                Err(Errno::EAGAIN) => return,
+                Err(Errno::EINTR) => continue,
+                Err(err) => {
+                    // Usually EIO because the child has gone. Either way, this input is lost.
+                    println!("Failed to write to the shell: {}", err);
+                    self.write_queue.clear();
+                    return;
+                }
+            }
+        }
+    }
+
+    fn pending_write_bytes(&self) -> usize {
+        self.write_queue.len()
+    }
+
+    fn resized(&mut self, new_rows: usize, new_cols: usize, cell_pixel_size: CellPixelSize) {
+        let winsize = pty_winsize(new_rows, new_cols, cell_pixel_size);
+        if let Err(err) = unsafe { ioctl::tiocswinsz(self.pty_result.master, &winsize) } {
+            println!("Failed to tell the kernel about our new size: {}", err);
+            return;
+        }
+
+        // The kernel should send SIGWINCH to the foreground process group when the size
+        // changes, but we send it ourselves too to be sure. Programs just redraw again.
+        if let Ok(foreground_group) = tcgetpgrp(self.pty_result.master) {
+            let _ = killpg(foreground_group, Signal::SIGWINCH);
+        }
+    }
+
+    fn next_event(&mut self) -> Option<ShellLayerEvent> {
+        if self.exit_reported {
+            return None;
+        }
+        let status = self.exit_status?;
+        self.exit_reported = true;
+        Some(ShellLayerEvent::ChildExited { status })
+    }
+
+    fn try_clone_reader(&mut self) -> Option<File> {
+        // This dup()s the master fd, so the reader has its own handle to the same pty.
+        // NOTE: It shares our O_NONBLOCK flag.
+        self.get_master_file().try_clone().ok()
+    }
+
+    fn signal_foreground_process_group(&mut self, signal: Signal) -> nix::Result<()> {
+        let foreground_group = tcgetpgrp(self.pty_result.master)?;
+        killpg(foreground_group, signal)
+    }
+
+    fn shell_pid(&self) -> Option<i32> {
+        Some(self.child_pid.as_raw())
+    }
+
+    fn tty_path(&self) -> Option<PathBuf> {
+        self.tty_path.clone()
+    }
+
+    fn is_echo_disabled(&self) -> bool {
+        // The master side reports the settings the program made on the slave side
+        let Ok(termios) = tcgetattr(self.pty_result.master) else {
+            return false;
+        };
+        // Programs that read keys one at a time (like shells with line editing, or ssh) turn
+        // echo off too, but they echo for themselves
+        let local_flags = termios.local_flags;
+        local_flags.contains(LocalFlags::ICANON) && !local_flags.contains(LocalFlags::ECHO)
+    }
+
+    fn foreground_process_name(&self) -> Option<String> {
+        let comm = fs::read_to_string(self.foreground_process_proc_path("comm")?).ok()?;
+        Some(comm.trim_end().to_string())
+    }
+
+    fn foreground_process_argv(&self) -> Option<Vec<String>> {
+        // The arguments are separated (and terminated) by NULs
+        let cmdline = fs::read(self.foreground_process_proc_path("cmdline")?).ok()?;
+        Some(
+            cmdline
+                .split(|b| *b == 0)
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| String::from_utf8_lossy(arg).to_string())
+                .collect(),
+        )
+    }
+
+    fn foreground_process_cwd(&self) -> Option<PathBuf> {
+        fs::read_link(self.foreground_process_proc_path("cwd")?).ok()
+    }
+}
+
+// Closing the window shouldn't leave the shell (or anything it started) running
+impl Drop for LinuxShellLayer {
+    fn drop(&mut self) {
+        // We have to ask this before closing the master, because then the pty is gone
+        let foreground_group = tcgetpgrp(self.pty_result.master).ok();
+
+        match self.master_fd_file.take() {
+            // The File owns the fd by now, so it closes it for us
+            Some(master_fd_file) => drop(master_fd_file),
+            None => {
+                let _ = close(self.pty_result.master);
+            }
+        }
+
+        self.try_reap_child();
+        if self.exit_status.is_some() {
+            return;
+        }
+
+        // Closing the master normally makes the kernel send SIGHUP to the shell and the
+        // foreground job. That doesn't happen if someone else still has a copy of it open (eg. a
+        // ThreadedShellLayer's reader thread), so we send it ourselves. The shell passes it on to
+        // its background jobs. Stopped jobs can't act on it until they're continued.
+        let _ = killpg(self.child_pid, Signal::SIGHUP);
+        let _ = killpg(self.child_pid, Signal::SIGCONT);
+        if let Some(foreground_group) = foreground_group {
+            if foreground_group != self.child_pid {
+                let _ = killpg(foreground_group, Signal::SIGHUP);
+                let _ = killpg(foreground_group, Signal::SIGCONT);
+            }
+        }
+
+        let deadline = Instant::now() + Duration::from_millis(SHELL_EXIT_GRACE_PERIOD_MS);
+        while Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+            self.try_reap_child();
+            if self.exit_status.is_some() {
+                return;
+            }
+        }
+
+        println!("The shell didn't exit when we hung up, so we're killing it");
+        let _ = kill(self.child_pid, Signal::SIGKILL);
+        let _ = waitpid(self.child_pid, None);
+    }
+}
+
+// The master side of the pty. It's readable whenever the child has printed something.
+impl AsRawFd for LinuxShellLayer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.pty_result.master
     }
+}
 
-    fn resized(&mut self, new_rows: usize, new_cols: usize) {
-        println!("Window resizing on Linux is as-yet unsupported.");
+impl AsFd for LinuxShellLayer {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        // The fd stays open for as long as we exist, so this borrow can't outlive it
+        unsafe { BorrowedFd::borrow_raw(self.pty_result.master) }
     }
 }
 
 impl LinuxShellLayer {
-    pub fn new(rows: usize, cols: usize) -> Self {
-        let winsize = winsize {
-            // TODO: We should just report exactly how big we are, but we don't support
-            //   stomping, so if we do that ZSH breaks.
-            ws_col: cols as u16,
-            ws_row: rows as u16,
-            // TODO: Is this important?
-            ws_xpixel: 100,
-            ws_ypixel: 100,
-        };
-        // NOTE: This result does NOT implement Drop, so the file descriptors must be
-        //   closed manually (or realistically, we will leak them).
+    pub fn new(
+        rows: usize,
+        cols: usize,
+        cell_pixel_size: CellPixelSize,
+        spawn_config: &SpawnConfig,
+    ) -> Self {
+        let winsize = pty_winsize(rows, cols, cell_pixel_size);
+        // NOTE: This result does NOT implement Drop. We close the master ourselves in drop()
+        //   and the slave just below.
         let pty_result = openpty(&winsize, None).expect("openpty() failed");
 
-        let layer = LinuxShellLayer {
+        let exec_failure_pipe = ExecFailurePipe::new();
+        let child_pid = unsafe {
+            Self::fork_and_become_shell_as_child_process(
+                &pty_result,
+                spawn_config,
+                &exec_failure_pipe,
+            )
+        };
+        // From now on we're in the parent process. The child uses exec to become the shell and
+        // never reaches this point.
+
+        // Only the child needs the slave side. If we kept it open, we'd never find out when the
+        // child closes it, so we'd never notice that the shell has exited.
+        let tty_path = ttyname(pty_result.slave).ok();
+        close(pty_result.slave).unwrap();
+
+        // The child has already printed why into the pty, and will exit with 127. We carry on as
+        // normal so that the message shows up in the window (eg. someone typo'd "velocity -e").
+        if let Err(errno) = exec_failure_pipe.wait_for_exec() {
+            println!(
+                "Failed to launch {}: {}",
+                spawn_config.program_description(),
+                errno.desc()
+            );
+        }
+
+        // So that writing never blocks (see write()). When we want to wait for output, we poll.
+        let flags = OFlag::from_bits_truncate(fcntl(pty_result.master, FcntlArg::F_GETFL).unwrap());
+        fcntl(
+            pty_result.master,
+            FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK),
+        )
+        .unwrap();
+
+        LinuxShellLayer {
             pty_result,
             fd_drained: true,
             master_fd_file: None,
-        };
+            child_pid,
+            tty_path,
+            hung_up: false,
+            exit_status: None,
+            exit_reported: false,
+            nonblocking: false,
+            write_queue: VecDeque::new(),
+        }
+    }
 
-        unsafe { layer.fork_and_become_shell_as_child_process() }
-        // From now on we're in the parent process. The child uses exec to become the shell and
-        // never reaches this point.
+    // For embedding in an existing event loop (eg. epoll or mio). Register this layer's fd for
+    // readability (and writability while pending_write_bytes() isn't zero), then call
+    // TtyState::process_available() when it fires. read() stops waiting in poll() itself and
+    // gives back a WouldBlock error when there's nothing to read.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    // The kernel knows which process group is in the foreground of our pty. Its leader's ID is
+    // the same as the group's, so that's the process we describe.
+    fn foreground_process_proc_path(&self, file_name: &str) -> Option<PathBuf> {
+        if self.hung_up {
+            return None;
+        }
+        let foreground_group = tcgetpgrp(self.pty_result.master).ok()?;
+        Some(PathBuf::from(format!(
+            "/proc/{}/{}",
+            foreground_group, file_name
+        )))
+    }
+
+    fn handle_hang_up(&mut self) {
+        self.hung_up = true;
+        self.try_reap_child();
+    }
 
-        layer
+    // Collects the child's exit status if it has finished. This also stops it hanging around as
+    // a zombie process.
+    fn try_reap_child(&mut self) {
+        if self.exit_status.is_some() {
+            return;
+        }
+        self.exit_status = match waitpid(self.child_pid, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::Exited(_, code)) => Some(ChildExitStatus::Exited(code)),
+            Ok(WaitStatus::Signaled(_, signal, _)) => {
+                Some(ChildExitStatus::Signalled(signal as i32))
+            }
+            // Someone else reaped it, so we'll never know how it went
+            Err(Errno::ECHILD) => Some(ChildExitStatus::Unknown),
+            _ => None,
+        };
     }
 
     fn get_master_file(&mut self) -> &mut File {
@@ -105,7 +405,20 @@ impl LinuxShellLayer {
         self.master_fd_file.as_mut().unwrap()
     }
 
-    unsafe fn fork_and_become_shell_as_child_process(&self) {
+    unsafe fn fork_and_become_shell_as_child_process(
+        pty_result: &OpenptyResult,
+        spawn_config: &SpawnConfig,
+        exec_failure_pipe: &ExecFailurePipe,
+    ) -> Pid {
+        // We work all of this out before forking. The child can't allocate (see PreparedExec),
+        // and the environment might mean running tic.
+        let prepared_exec = PreparedExec::new(
+            exec_candidates(spawn_config, spawn_config.login_shell),
+            child_env_vars(spawn_config),
+            working_directory_candidates(spawn_config),
+            &spawn_config.program_description(),
+        );
+
         let fork_result = fork();
 
         // Fork split our program in two, and now we check who we are
@@ -113,15 +426,17 @@ impl LinuxShellLayer {
         //  disassembler in the USS Enterprise had broken down)
         match fork_result {
             // We are the parent in this instance, so we want nothing to do with this function
-            Ok(ForkResult::Parent { .. }) => return,
+            Ok(ForkResult::Parent { child }) => return child,
             // We're the child, happy days
             Ok(ForkResult::Child) => {}
             // Oh crumbs.
             Err(_) => panic!("Process failed to fork"),
         }
 
+        exec_failure_pipe.close_read_end();
+
         // The child process doesn't need a reference to the master PTY file.
-        close(self.pty_result.master).unwrap();
+        close(pty_result.master).unwrap();
 
         // Put the child process in a different process group to the parent.
         // This is required for the shell program to spawn sub-processes and keep track of the
@@ -130,7 +445,7 @@ impl LinuxShellLayer {
 
         // Set the slave file to the "controlling terminal" of this process.
         // Slightly black-box magic to me.
-        let pty_slave = self.pty_result.slave;
+        let pty_slave = pty_result.slave;
         ioctl::tiocsctty(pty_slave).unwrap();
 
         // Set basic io file descriptors for this process to read/write from the slave file
@@ -141,53 +456,8 @@ impl LinuxShellLayer {
         // We no longer need this pointer to our slave fd (it's pointed to at 0, 1 and 2)
         close(pty_slave).unwrap();
 
-        // TODO: Find out which shell the user has set, rather than hardcoding zsh
-        //   xterm does this by reading /etc/passwd, and executing /bin/sh if that doesn't work.
-        let shell_path = CString::new("/usr/bin/zsh").unwrap();
-        // Pass --login, which should set up extra env like $PATH
-        // TODO: Offer the user the chance to choose whether they want a login shell or not.
-        let shell_login_flag = CString::new("--login").unwrap();
-
-        // Change to the user's home directory before spawning the shell program
-        env::set_current_dir(env::var("HOME").unwrap_or("/".to_string())).unwrap();
-
-        let mut env_vars = vec![];
-
-        // First, we copy all of our own environment variables to this new process.
-        // This will give it the important default ones like PATH etc.
-        for var in env::vars() {
-            // Skip these, we'll set our own
-            if var.0 == "TERM" || var.0 == "TERMPROGRAM" {
-                continue;
-            }
-            env_vars.push(CString::new(format!("{}={}", var.0, var.1)).unwrap())
-        }
-
-        // This is very important, otherwise the shell won't talk to us properly
-        // NOTE: We only support 16 colours, but on the distro I tested with (Pop!_OS)
-        //   16 colour support is not configured. It only recognises 256color as ANSI.
-        env_vars.push(CString::new("TERM=xterm-256color").unwrap());
-        // This is just showing off :)
-        env_vars.push(CString::new("TERM_PROGRAM=Velocity").unwrap());
-
-        let mut c_env_vars: Vec<*const i8> = env_vars.iter().map(|s| s.as_ptr()).collect();
-        // NULL-terminated
-        c_env_vars.push(ptr::null());
-
-        // The way the variadic arguments work here are:
-        // 1 - The executable path
-        // 2 through N - argv items
-        // N+1 - NULL terminal for argv array
-        // N+2 - Pointer to an array of environment variables
-        // What it's doing is replacing our executable with the image at shell_path. So we the child
-        // process *become* the shell program (maintaining the modifications we made to stdio above).
-        // TODO: Panic if this is -1
-        let _exec_result = execle(
-            shell_path.as_ptr(),
-            shell_path.clone().as_ptr(),
-            shell_login_flag.as_ptr(),
-            ptr::null() as *const c_void,
-            c_env_vars.as_slice().as_ptr(),
-        );
+        // We try each candidate in turn, so someone with a stale $SHELL (eg. zsh isn't installed
+        // on this machine) still gets a working window.
+        prepared_exec.exec(exec_failure_pipe);
     }
 }
@@ -1,26 +1,179 @@
-use crate::constants::*;
+use nix::errno::Errno;
+use spawn_config::SpawnConfig;
+use std::{fmt, fs::File, io, path::PathBuf, time::Duration};
 
+pub mod client;
 #[cfg(target_os = "linux")]
-mod linux;
+pub mod linux;
 #[cfg(target_os = "macos")]
-mod mac_os;
+pub mod mac_os;
+pub mod replay;
+pub mod scripted;
+pub mod serial;
+pub mod spawn_config;
+pub mod threaded;
+
+// So that frontends can name signals without depending on nix themselves
+pub use nix::sys::signal::Signal;
 
 // This represents an abstraction of getting bytes to and from the shell program,
 // for each different platform (eg macOS vs Linux).
 pub trait ShellLayer {
-    // This callback is called when we get bytes from the child process, OR if it has been long
-    // enough since we last rendered a frame (a timeout is hit)
-    fn read(&mut self, buffer: &mut [u8; FD_BUFFER_SIZE_BYTES], written: &mut usize);
+    // This is called to get bytes from the child process. It returns how many it put into the
+    // buffer, or a WouldBlock error if there weren't any before it has been long enough since we
+    // last rendered a frame (a timeout is hit). Ok(0) means the child has hung up, so there will
+    // never be any more.
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize>;
     // This is called by our GUI layer when the user hits a keyboard key
     fn write(&mut self, data: &[u8]);
+    // Sends TtyState's answer to something the program asked the terminal, eg. where the cursor
+    // is. Layers where someone else answers (eg. velocity-server, for an attached window) or
+    // there's nobody to answer (eg. a replay) drop them.
+    fn write_response(&mut self, data: &[u8]) {
+        self.write(data);
+    }
     // This is called when the GUI window is resized. It's called with the number of rows and
-    // collumns that fit into the new width and height of the window.
-    fn resized(&mut self, new_rows: usize, new_cols: usize);
+    // collumns that fit into the new width and height of the window, and how big each one is.
+    fn resized(&mut self, new_rows: usize, new_cols: usize, cell_pixel_size: CellPixelSize);
+    // Called after read() to find out about things that happened to the session other than
+    // bytes arriving, like the child process exiting. Returns None when there's nothing new.
+    fn next_event(&mut self) -> Option<ShellLayerEvent>;
+
+    // The rest of these only matter for layers that can avoid blocking in read(), so they have
+    // sensible defaults for those that can't.
+
+    // Whether read() has more bytes for us straight away, without having to wait.
+    fn has_data_ready(&mut self) -> bool {
+        false
+    }
+    // Blocks until there's something to read() or the timeout passes. Returns whether there's
+    // something to read. Layers that wait inside read() itself don't need to wait here.
+    fn wait_for_data(&mut self, _timeout: Duration) -> bool {
+        true
+    }
+    // A second handle to the fd the child's bytes come from, so that they can be read on
+    // another thread (see ThreadedShellLayer). None if that isn't possible for this layer.
+    fn try_clone_reader(&mut self) -> Option<File> {
+        None
+    }
+    // Tries to send input that write() had to queue up because the child wasn't ready for it.
+    // Layers that never queue don't need to do anything.
+    fn flush_pending_writes(&mut self) {}
+    // How many bytes of input are queued up waiting to be sent. Frontends can use this to show
+    // that a big paste is still in progress.
+    fn pending_write_bytes(&self) -> usize {
+        0
+    }
+
+    // Sends a signal (eg. SIGINT, SIGTERM or SIGCONT) to whatever is running in the foreground.
+    // Layers without a process behind them don't support this.
+    fn signal_foreground_process_group(&mut self, _signal: Signal) -> nix::Result<()> {
+        Err(Errno::ENOTSUP)
+    }
+
+    // These tell frontends about what's running, eg. for titles like "vim — ~/src/project".
+    // They're all None if the layer doesn't know.
+
+    // The process ID of the shell (or whatever program we launched)
+    fn shell_pid(&self) -> Option<i32> {
+        None
+    }
+    // The path of the terminal device the shell is attached to, eg. /dev/pts/3
+    fn tty_path(&self) -> Option<PathBuf> {
+        None
+    }
+    // Whether the program has turned echo off while still reading whole lines, which is what
+    // password prompts do
+    fn is_echo_disabled(&self) -> bool {
+        false
+    }
+    // The name of the program currently in the foreground, eg. "vim"
+    fn foreground_process_name(&self) -> Option<String> {
+        None
+    }
+    // The full command line of the program currently in the foreground
+    fn foreground_process_argv(&self) -> Option<Vec<String>> {
+        None
+    }
+    // The working directory of the program currently in the foreground
+    fn foreground_process_cwd(&self) -> Option<PathBuf> {
+        None
+    }
+}
+
+// How big one character cell is on screen, in pixels. Most programs don't care, but some (eg.
+// ones that draw images into the terminal) use it to work out how big the window is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CellPixelSize {
+    pub width: u16,
+    pub height: u16,
+}
+
+// The kernel's idea of how big the terminal is. It's what programs see via TIOCGWINSZ.
+fn pty_winsize(rows: usize, cols: usize, cell_pixel_size: CellPixelSize) -> libc::winsize {
+    libc::winsize {
+        ws_row: rows as u16,
+        ws_col: cols as u16,
+        ws_xpixel: (cols as u16).saturating_mul(cell_pixel_size.width),
+        ws_ypixel: (rows as u16).saturating_mul(cell_pixel_size.height),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShellLayerEvent {
+    // The program on the other end of the pty has finished. Nothing more will be read.
+    ChildExited { status: ChildExitStatus },
+    // The other end wants the terminal to be a different size, eg. a recording that was resized
+    // part way through
+    SizeChanged { rows: usize, cols: usize },
+    // The device on the other end has gone away (eg. a serial adapter was unplugged). Unlike
+    // ChildExited, it might come back.
+    Disconnected,
+    // ...and now it has
+    Reconnected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChildExitStatus {
+    // It called exit() with this code
+    Exited(i32),
+    // It was killed by this signal number
+    Signalled(i32),
+    // It's gone, but something else collected its exit status before we could
+    Unknown,
+}
+
+// Frontends print this into the window when the user wants to keep it open after the exit
+impl fmt::Display for ChildExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChildExitStatus::Exited(code) => write!(f, "[process exited with code {}]", code),
+            ChildExitStatus::Signalled(signal) => {
+                write!(f, "[process killed by signal {}]", signal)
+            }
+            ChildExitStatus::Unknown => write!(f, "[process exited]"),
+        }
+    }
 }
 
-pub fn get_shell_layer(rows: usize, cols: usize) -> Box<dyn ShellLayer> {
+pub fn get_shell_layer(
+    rows: usize,
+    cols: usize,
+    cell_pixel_size: CellPixelSize,
+    spawn_config: &SpawnConfig,
+) -> Box<dyn ShellLayer> {
     #[cfg(target_os = "macos")]
-    return Box::new(mac_os::MacOsShellLayer::new(rows, cols));
+    return Box::new(mac_os::MacOsShellLayer::new(
+        rows,
+        cols,
+        cell_pixel_size,
+        spawn_config,
+    ));
     #[cfg(target_os = "linux")]
-    return Box::new(linux::LinuxShellLayer::new(rows, cols));
+    return Box::new(linux::LinuxShellLayer::new(
+        rows,
+        cols,
+        cell_pixel_size,
+        spawn_config,
+    ));
 }
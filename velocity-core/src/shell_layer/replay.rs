@@ -0,0 +1,377 @@
+use super::{CellPixelSize, ShellLayer, ShellLayerEvent};
+use crate::asciicast::AsciicastRecording;
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, ErrorKind, Read},
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+// A ShellLayer that plays a recorded session back with its original timing, so that you can
+// watch (and step through) exactly what a program printed. Handy for reproducing rendering bugs.
+//
+// There's nobody to type to, so keys control the playback instead:
+//   space  pause or resume
+//   .      while paused, play the next chunk
+//   + -    double or halve the speed
+pub struct ReplayShellLayer {
+    chunks: Vec<ReplayChunk>,
+    // When each chunk plays, on the recording's clock, after max_idle has shortened any pauses
+    play_times: Vec<Duration>,
+    next_chunk: usize,
+    // How much of the next chunk's output we've already handed out
+    next_chunk_offset: usize,
+    // The size of the terminal the recording was made in, as (cols, rows), if we know it
+    size: Option<(usize, usize)>,
+    speed: f64,
+    max_idle: Option<Duration>,
+    paused: bool,
+    // How far through the recording we are
+    position: Duration,
+    last_tick: Instant,
+    events: VecDeque<ShellLayerEvent>,
+}
+
+struct ReplayChunk {
+    // On the recording's clock, as it was recorded
+    time: Duration,
+    kind: ReplayChunkKind,
+}
+
+enum ReplayChunkKind {
+    Output(Vec<u8>),
+    Resize { cols: usize, rows: usize },
+}
+
+impl ShellLayer for ReplayShellLayer {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.tick();
+        while self.next_chunk_is_due() {
+            let chunk = &self.chunks[self.next_chunk];
+            match &chunk.kind {
+                ReplayChunkKind::Output(data) => {
+                    // A chunk that doesn't fit is handed out over multiple reads
+                    let remaining = &data[self.next_chunk_offset..];
+                    let count = remaining.len().min(buffer.len());
+                    buffer[..count].copy_from_slice(&remaining[..count]);
+                    self.next_chunk_offset += count;
+                    if self.next_chunk_offset == data.len() {
+                        self.next_chunk += 1;
+                        self.next_chunk_offset = 0;
+                    }
+                    return Ok(count);
+                }
+                ReplayChunkKind::Resize { cols, rows } => {
+                    self.events.push_back(ShellLayerEvent::SizeChanged {
+                        rows: *rows,
+                        cols: *cols,
+                    });
+                    self.next_chunk += 1;
+                }
+            }
+        }
+        // Once the recording is over we leave the last frame on screen rather than "exiting",
+        // because that would usually close the window.
+        Err(ErrorKind::WouldBlock.into())
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        for b in data {
+            match b {
+                b' ' => {
+                    self.paused = !self.paused;
+                    println!("Replay {}", if self.paused { "paused" } else { "resumed" });
+                }
+                b'.' => self.step(),
+                b'+' | b'=' => self.set_speed(self.speed * 2.),
+                b'-' => self.set_speed(self.speed / 2.),
+                _ => {}
+            }
+        }
+    }
+
+    // The program that asked was answered when it was recorded
+    fn write_response(&mut self, _data: &[u8]) {}
+
+    fn resized(&mut self, _new_rows: usize, _new_cols: usize, _cell_pixel_size: CellPixelSize) {
+        // The recording was made at whatever size it was, so there's nobody to tell
+    }
+
+    fn next_event(&mut self) -> Option<ShellLayerEvent> {
+        self.events.pop_front()
+    }
+
+    fn has_data_ready(&mut self) -> bool {
+        self.tick();
+        self.next_chunk_is_due()
+    }
+
+    fn wait_for_data(&mut self, timeout: Duration) -> bool {
+        if self.has_data_ready() {
+            return true;
+        }
+        let wait = match self.play_times.get(self.next_chunk) {
+            Some(play_time) if !self.paused => {
+                play_time.saturating_sub(self.position).div_f64(self.speed)
+            }
+            // We're paused, or there's nothing left. Either way, only the user can change that.
+            _ => timeout,
+        };
+        thread::sleep(wait.min(timeout));
+        self.has_data_ready()
+    }
+}
+
+impl ReplayShellLayer {
+    // Works out what sort of recording it is. Typescripts from script(1) come with their
+    // timing in a separate file, so they're the only ones that take timing_path.
+    pub fn open(path: &Path, timing_path: Option<&Path>) -> io::Result<Self> {
+        let mut first_byte = [0];
+        File::open(path)?.read_exact(&mut first_byte)?;
+        let is_asciicast = first_byte[0] == b'{';
+        if is_asciicast && timing_path.is_none() {
+            Self::from_asciicast(path)
+        } else {
+            Self::from_typescript(path, timing_path)
+        }
+    }
+
+    pub fn from_asciicast(path: &Path) -> io::Result<Self> {
+        let recording = AsciicastRecording::read(path)?;
+
+        let mut chunks = vec![];
+        for event in recording.events {
+            // A time that's too big for a Duration (or infinite) can't be played, so we skip it
+            let Ok(time) = Duration::try_from_secs_f64(event.time.max(0.)) else {
+                continue;
+            };
+            let kind = match event.code.as_str() {
+                "o" => ReplayChunkKind::Output(event.data.into_bytes()),
+                "r" => {
+                    let Some((cols, rows)) = parse_size(&event.data) else {
+                        continue;
+                    };
+                    ReplayChunkKind::Resize { cols, rows }
+                }
+                // Input was already echoed into the output by the program, if it was going to be,
+                // and markers are only for players with a seek bar
+                _ => continue,
+            };
+            chunks.push(ReplayChunk { time, kind });
+        }
+
+        let mut layer = Self::new(chunks, Some((recording.cols, recording.rows)));
+        // A limit too big for a Duration is no limit at all
+        let max_idle = recording
+            .idle_time_limit
+            .and_then(|limit| Duration::try_from_secs_f64(limit.max(0.)).ok());
+        if let Some(max_idle) = max_idle {
+            layer = layer.max_idle(max_idle);
+        }
+        Ok(layer)
+    }
+
+    // Reads what script(1) wrote. Without its timing file (script -t or -T), we have no idea
+    // when anything happened, so we play a line at a time as fast as we can. That's still useful
+    // for stepping through.
+    pub fn from_typescript(path: &Path, timing_path: Option<&Path>) -> io::Result<Self> {
+        let contents = fs::read(path)?;
+        let mut typescript = &contents[..];
+
+        // script(1) puts a line at the start saying when it started, and newer versions record
+        // the terminal size in it, eg. 'Script started on ... [TERM="xterm" COLUMNS="80" LINES="24"]'
+        let mut size = None;
+        if typescript.starts_with(b"Script started on ") {
+            let header_end = typescript
+                .iter()
+                .position(|b| *b == b'\n')
+                .map(|i| i + 1)
+                .unwrap_or(typescript.len());
+            let header = String::from_utf8_lossy(&typescript[..header_end]);
+            if let (Some(cols), Some(rows)) = (
+                typescript_header_value(&header, "COLUMNS"),
+                typescript_header_value(&header, "LINES"),
+            ) {
+                size = Some((cols, rows));
+            }
+            typescript = &typescript[header_end..];
+        }
+        // ...and one at the end saying when it finished
+        let footer = b"\nScript done on ";
+        if let Some(footer_start) = typescript.windows(footer.len()).rposition(|w| w == footer) {
+            typescript = &typescript[..footer_start];
+        }
+
+        let chunks = match timing_path {
+            Some(timing_path) => {
+                let timing = fs::read_to_string(timing_path)?;
+                typescript_chunks_with_timing(typescript, &timing)
+            }
+            None => typescript
+                .split_inclusive(|b| *b == b'\n')
+                .map(|line| ReplayChunk {
+                    time: Duration::ZERO,
+                    kind: ReplayChunkKind::Output(line.to_vec()),
+                })
+                .collect(),
+        };
+
+        Ok(Self::new(chunks, size))
+    }
+
+    fn new(chunks: Vec<ReplayChunk>, size: Option<(usize, usize)>) -> Self {
+        let mut layer = ReplayShellLayer {
+            chunks,
+            play_times: vec![],
+            next_chunk: 0,
+            next_chunk_offset: 0,
+            size,
+            speed: 1.,
+            max_idle: None,
+            paused: false,
+            position: Duration::ZERO,
+            last_tick: Instant::now(),
+            events: VecDeque::new(),
+        };
+        layer.calculate_play_times();
+        layer
+    }
+
+    // Eg. 2.0 plays twice as fast as it was recorded
+    pub fn speed(mut self, speed: f64) -> Self {
+        self.set_speed(speed);
+        self
+    }
+
+    // Pauses longer than this are cut down to this, so that you're not left watching someone
+    // think (or go and make a cup of tea)
+    pub fn max_idle(mut self, max_idle: Duration) -> Self {
+        self.max_idle = Some(max_idle);
+        self.calculate_play_times();
+        self
+    }
+
+    // Starts off paused, so you can step through from the very beginning
+    pub fn start_paused(mut self) -> Self {
+        self.paused = true;
+        self
+    }
+
+    // The size of the terminal the recording was made in, as (cols, rows). Frontends can open
+    // their window at this size so that the recording looks right.
+    pub fn size(&self) -> Option<(usize, usize)> {
+        self.size
+    }
+
+    fn set_speed(&mut self, speed: f64) {
+        // Stop silly speeds from turning into an infinite (or negative) wait
+        self.speed = speed.clamp(1. / 64., 64.);
+        println!("Replaying at {}x speed", self.speed);
+    }
+
+    // Skips straight to the next chunk
+    fn step(&mut self) {
+        if let Some(play_time) = self.play_times.get(self.next_chunk) {
+            self.position = self.position.max(*play_time);
+        }
+    }
+
+    fn calculate_play_times(&mut self) {
+        let mut previous_time = Duration::ZERO;
+        let mut play_time = Duration::ZERO;
+        self.play_times = self
+            .chunks
+            .iter()
+            .map(|chunk| {
+                let mut gap = chunk.time.saturating_sub(previous_time);
+                if let Some(max_idle) = self.max_idle {
+                    gap = gap.min(max_idle);
+                }
+                previous_time = chunk.time;
+                play_time = play_time.saturating_add(gap);
+                play_time
+            })
+            .collect();
+    }
+
+    // Moves our position in the recording along by however long it's been since we last looked
+    fn tick(&mut self) {
+        let now = Instant::now();
+        if !self.paused {
+            self.position += (now - self.last_tick).mul_f64(self.speed);
+        }
+        self.last_tick = now;
+    }
+
+    fn next_chunk_is_due(&self) -> bool {
+        match self.play_times.get(self.next_chunk) {
+            Some(play_time) => *play_time <= self.position,
+            None => false,
+        }
+    }
+}
+
+// Eg. "80x24", which is how asciicast writes resizes
+fn parse_size(text: &str) -> Option<(usize, usize)> {
+    let (cols, rows) = text.split_once('x')?;
+    Some((cols.trim().parse().ok()?, rows.trim().parse().ok()?))
+}
+
+// Finds eg. COLUMNS="80" in script(1)'s header line
+fn typescript_header_value(header: &str, key: &str) -> Option<usize> {
+    let start = header.find(&format!("{}=\"", key))? + key.len() + 2;
+    let length = header[start..].find('"')?;
+    header[start..start + length].parse().ok()
+}
+
+// script(1) has two timing formats. The classic one has a line per chunk of output, with the
+// delay since the last chunk and how many bytes it was:
+//   0.003 42
+// The "advanced" one (script -T) puts the type of each entry first. Only "O" entries are in the
+// typescript. The others (eg. "I" for input, or "H" for header info) are just in the way.
+//   O 0.003 42
+fn typescript_chunks_with_timing(typescript: &[u8], timing: &str) -> Vec<ReplayChunk> {
+    let mut chunks = vec![];
+    let mut time = Duration::ZERO;
+    let mut offset: usize = 0;
+
+    for line in timing.lines() {
+        let mut fields: Vec<&str> = line.split_whitespace().collect();
+        let is_output = match fields.first() {
+            Some(first) if first.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+                let is_output = *first == "O";
+                fields.remove(0);
+                is_output
+            }
+            Some(_) => true,
+            None => continue,
+        };
+
+        let Some(delay) = fields
+            .first()
+            .and_then(|d| d.parse::<f64>().ok())
+            .and_then(|d| Duration::try_from_secs_f64(d.max(0.)).ok())
+        else {
+            continue;
+        };
+        time = time.saturating_add(delay);
+
+        if !is_output {
+            continue;
+        }
+        let Some(length) = fields.get(1).and_then(|l| l.parse::<usize>().ok()) else {
+            continue;
+        };
+        let end = offset.saturating_add(length).min(typescript.len());
+        chunks.push(ReplayChunk {
+            time,
+            kind: ReplayChunkKind::Output(typescript[offset..end].to_vec()),
+        });
+        offset = end;
+    }
+
+    chunks
+}
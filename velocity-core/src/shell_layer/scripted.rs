@@ -0,0 +1,103 @@
+use super::{CellPixelSize, ChildExitStatus, ShellLayer, ShellLayerEvent};
+
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind},
+    sync::{Arc, Mutex},
+};
+
+// Everything a TtyState has sent to a ScriptedShellLayer. The layer itself ends up boxed
+// inside the TtyState, so we share this with whoever made the layer to check afterwards.
+#[derive(Default)]
+pub struct ScriptedShellLayerRecording {
+    // Every call to write(), in order
+    pub writes: Vec<Vec<u8>>,
+    // Every call to resized(), as (rows, cols, cell pixel size)
+    pub resizes: Vec<(usize, usize, CellPixelSize)>,
+}
+
+impl ScriptedShellLayerRecording {
+    // All of the written bytes joined together, which is usually what you want to compare
+    pub fn written_bytes(&self) -> Vec<u8> {
+        self.writes.concat()
+    }
+}
+
+// A ShellLayer with no pty or child process behind it. Each read() hands out the next of a
+// list of predefined byte chunks, which makes TtyState's behaviour completely deterministic.
+pub struct ScriptedShellLayer {
+    chunks: VecDeque<Vec<u8>>,
+    // If set, the "child" exits with this status once every chunk has been read
+    exit_status: Option<ChildExitStatus>,
+    exit_reported: bool,
+    recording: Arc<Mutex<ScriptedShellLayerRecording>>,
+}
+
+impl ShellLayer for ScriptedShellLayer {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let Some(mut chunk) = self.chunks.pop_front() else {
+            // Once the script is over we either hang up or sit there quietly forever
+            return match self.exit_status {
+                Some(_) => Ok(0),
+                None => Err(ErrorKind::WouldBlock.into()),
+            };
+        };
+
+        // A chunk that doesn't fit is handed out over multiple reads, just like a real fd
+        if chunk.len() > buffer.len() {
+            let remainder = chunk.split_off(buffer.len());
+            self.chunks.push_front(remainder);
+        }
+
+        buffer[..chunk.len()].copy_from_slice(&chunk);
+        Ok(chunk.len())
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        self.recording.lock().unwrap().writes.push(data.to_vec());
+    }
+
+    fn resized(&mut self, new_rows: usize, new_cols: usize, cell_pixel_size: CellPixelSize) {
+        self.recording
+            .lock()
+            .unwrap()
+            .resizes
+            .push((new_rows, new_cols, cell_pixel_size));
+    }
+
+    fn next_event(&mut self) -> Option<ShellLayerEvent> {
+        if self.exit_reported || !self.chunks.is_empty() {
+            return None;
+        }
+        let status = self.exit_status?;
+        self.exit_reported = true;
+        Some(ShellLayerEvent::ChildExited { status })
+    }
+}
+
+impl ScriptedShellLayer {
+    pub fn new(chunks: Vec<Vec<u8>>) -> Self {
+        ScriptedShellLayer {
+            chunks: chunks.into(),
+            exit_status: None,
+            exit_reported: false,
+            recording: Arc::new(Mutex::new(ScriptedShellLayerRecording::default())),
+        }
+    }
+
+    // Convenience for scripts written as string literals, eg. "hello\r\n\x1b[2J"
+    pub fn from_strs(chunks: &[&str]) -> Self {
+        Self::new(chunks.iter().map(|c| c.as_bytes().to_vec()).collect())
+    }
+
+    // Makes the pretend child process exit once the script has run out
+    pub fn exit_when_finished(mut self, status: ChildExitStatus) -> Self {
+        self.exit_status = Some(status);
+        self
+    }
+
+    // Keep hold of this before handing the layer to a TtyState
+    pub fn recording(&self) -> Arc<Mutex<ScriptedShellLayerRecording>> {
+        self.recording.clone()
+    }
+}
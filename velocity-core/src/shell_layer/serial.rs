@@ -0,0 +1,334 @@
+use super::{CellPixelSize, ShellLayer, ShellLayerEvent};
+use crate::constants::*;
+
+use nix::{
+    errno::Errno,
+    fcntl::{open, OFlag},
+    poll::{poll, PollFd, PollFlags},
+    sys::stat::Mode,
+    sys::termios::{
+        cfmakeraw, cfsetspeed, tcgetattr, tcsetattr, BaudRate, ControlFlags, InputFlags, SetArg,
+    },
+    unistd::{close, read, write},
+};
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind},
+    os::fd::RawFd,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+// While the device is unplugged, how often we try to open it again
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+// How to talk to the device. The default is the very common "115200 8N1" with no flow control.
+#[derive(Debug, Clone, Copy)]
+pub struct SerialConfig {
+    pub baud_rate: u32,
+    // 5 to 8
+    pub data_bits: u8,
+    pub parity: Parity,
+    // 1 or 2
+    pub stop_bits: u8,
+    pub flow_control: FlowControl,
+}
+
+impl SerialConfig {
+    pub fn new() -> Self {
+        SerialConfig {
+            baud_rate: 115200,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1,
+            flow_control: FlowControl::None,
+        }
+    }
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlowControl {
+    None,
+    // RTS/CTS lines
+    Hardware,
+    // XON/XOFF characters
+    Software,
+}
+
+// A ShellLayer for a character device that already exists, like a serial port (/dev/ttyUSB0)
+// or one end of a pty pair made by socat. There's no child process - whatever is on the other
+// end of the wire is our "shell".
+//
+// Serial devices come and go (eg. a board being reset or unplugged). Instead of exiting when that
+// happens, we report ShellLayerEvent::Disconnected and keep trying to open the device again.
+pub struct SerialShellLayer {
+    path: PathBuf,
+    config: SerialConfig,
+    // None while the device is disconnected
+    fd: Option<RawFd>,
+    last_reconnect_attempt: Instant,
+    // Input that the device wasn't ready for yet. Serial ports are slow, so pastes can take a
+    // while to go out.
+    write_queue: VecDeque<u8>,
+    events: VecDeque<ShellLayerEvent>,
+}
+
+impl ShellLayer for SerialShellLayer {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let Some(fd) = self.fd else {
+            self.try_reconnect();
+            // Wait like we would have in poll() so that we don't spin between frames
+            thread::sleep(Duration::from_millis(FD_POLL_TIMEOUT_MS as u64));
+            return Err(ErrorKind::WouldBlock.into());
+        };
+
+        self.flush_pending_writes();
+
+        let mut poll_flags = PollFlags::POLLIN;
+        if !self.write_queue.is_empty() {
+            poll_flags |= PollFlags::POLLOUT;
+        }
+        let mut poll_fds = [PollFd::new(fd, poll_flags)];
+        if poll(&mut poll_fds, FD_POLL_TIMEOUT_MS)? == 0 {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+
+        let revents = poll_fds[0].revents().unwrap_or(PollFlags::empty());
+        if !revents.contains(PollFlags::POLLIN)
+            && revents.intersects(PollFlags::POLLHUP | PollFlags::POLLNVAL | PollFlags::POLLERR)
+        {
+            self.disconnect();
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        if revents.contains(PollFlags::POLLOUT) {
+            self.flush_pending_writes();
+        }
+        if !revents.contains(PollFlags::POLLIN) {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+
+        match read(fd, buffer) {
+            Ok(0) => {
+                // End of file. That's a pty whose other end has gone, or a USB adapter being
+                // unplugged on some systems. Either way, the device won't give us any more.
+                self.disconnect();
+                Err(ErrorKind::WouldBlock.into())
+            }
+            Ok(n) => Ok(n),
+            Err(Errno::EAGAIN) => Err(ErrorKind::WouldBlock.into()),
+            Err(Errno::EINTR) => Err(ErrorKind::Interrupted.into()),
+            Err(err) => {
+                // Usually EIO or ENXIO because the device was unplugged
+                println!("Failed to read from {}: {}", self.path.display(), err);
+                self.disconnect();
+                Err(ErrorKind::WouldBlock.into())
+            }
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        // There's nobody to send it to. We don't hold on to it, because typing into a
+        // disconnected device and having it all arrive later would be surprising.
+        if self.fd.is_none() {
+            return;
+        }
+        self.write_queue.extend(data);
+        self.flush_pending_writes();
+    }
+
+    fn flush_pending_writes(&mut self) {
+        let Some(fd) = self.fd else {
+            return;
+        };
+        while !self.write_queue.is_empty() {
+            let queued = self.write_queue.make_contiguous();
+            match write(fd, queued) {
+                Ok(written) => drop(self.write_queue.drain(..written)),
+                Err(Errno::EAGAIN) => return,
+                Err(Errno::EINTR) => continue,
+                Err(err) => {
+                    println!("Failed to write to {}: {}", self.path.display(), err);
+                    self.disconnect();
+                    return;
+                }
+            }
+        }
+    }
+
+    fn pending_write_bytes(&self) -> usize {
+        self.write_queue.len()
+    }
+
+    fn resized(&mut self, _new_rows: usize, _new_cols: usize, _cell_pixel_size: CellPixelSize) {
+        // A serial line has no idea how big our window is. Programs on the other end usually
+        // find out with `resize` or `stty rows N cols N` instead.
+    }
+
+    fn next_event(&mut self) -> Option<ShellLayerEvent> {
+        self.events.pop_front()
+    }
+}
+
+impl Drop for SerialShellLayer {
+    fn drop(&mut self) {
+        if let Some(fd) = self.fd.take() {
+            let _ = close(fd);
+        }
+    }
+}
+
+impl SerialShellLayer {
+    // Fails if the device can't be opened, or doesn't support the config (eg. a baud rate it
+    // doesn't know). After that, losing the device is reported with events instead.
+    pub fn new(path: &Path, config: SerialConfig) -> io::Result<Self> {
+        let fd = open_device(path, &config)?;
+        Ok(SerialShellLayer {
+            path: path.to_path_buf(),
+            config,
+            fd: Some(fd),
+            last_reconnect_attempt: Instant::now(),
+            write_queue: VecDeque::new(),
+            events: VecDeque::new(),
+        })
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.fd.is_some()
+    }
+
+    fn disconnect(&mut self) {
+        let Some(fd) = self.fd.take() else {
+            return;
+        };
+        let _ = close(fd);
+        self.write_queue.clear();
+        self.last_reconnect_attempt = Instant::now();
+        self.events.push_back(ShellLayerEvent::Disconnected);
+    }
+
+    fn try_reconnect(&mut self) {
+        if self.last_reconnect_attempt.elapsed() < RECONNECT_INTERVAL {
+            return;
+        }
+        self.last_reconnect_attempt = Instant::now();
+
+        // The device usually comes back with the same name (or the same symlink, with socat)
+        if let Ok(fd) = open_device(&self.path, &self.config) {
+            self.fd = Some(fd);
+            self.events.push_back(ShellLayerEvent::Reconnected);
+        }
+    }
+}
+
+fn open_device(path: &Path, config: &SerialConfig) -> io::Result<RawFd> {
+    // O_NOCTTY stops the device becoming *our* controlling terminal, which would mean that we
+    // get sent SIGHUP when it goes away. O_NONBLOCK is so that writing never freezes the window.
+    let flags = OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC;
+    let fd = open(path, flags, Mode::empty())?;
+
+    if let Err(err) = configure_device(fd, config) {
+        let _ = close(fd);
+        return Err(err);
+    }
+    Ok(fd)
+}
+
+fn configure_device(fd: RawFd, config: &SerialConfig) -> io::Result<()> {
+    let invalid = |message: String| io::Error::new(ErrorKind::InvalidInput, message);
+
+    let mut termios = tcgetattr(fd)?;
+
+    // No line editing, echoing or translating newlines on our side. Whatever is on the other end
+    // does all of that, just like a shell does on a pty.
+    cfmakeraw(&mut termios);
+
+    let baud_rate = baud_rate(config.baud_rate)
+        .ok_or_else(|| invalid(format!("{} isn't a supported baud rate", config.baud_rate)))?;
+    cfsetspeed(&mut termios, baud_rate)?;
+
+    let control_flags = &mut termios.control_flags;
+    control_flags.remove(ControlFlags::CSIZE);
+    control_flags.insert(match config.data_bits {
+        5 => ControlFlags::CS5,
+        6 => ControlFlags::CS6,
+        7 => ControlFlags::CS7,
+        8 => ControlFlags::CS8,
+        n => return Err(invalid(format!("{} data bits isn't possible", n))),
+    });
+
+    control_flags.remove(ControlFlags::PARENB | ControlFlags::PARODD);
+    match config.parity {
+        Parity::None => {}
+        Parity::Even => control_flags.insert(ControlFlags::PARENB),
+        Parity::Odd => control_flags.insert(ControlFlags::PARENB | ControlFlags::PARODD),
+    }
+
+    match config.stop_bits {
+        1 => control_flags.remove(ControlFlags::CSTOPB),
+        2 => control_flags.insert(ControlFlags::CSTOPB),
+        n => return Err(invalid(format!("{} stop bits isn't possible", n))),
+    }
+
+    // Ignore the modem control lines (we're not dialling anyone), and turn the receiver on
+    control_flags.insert(ControlFlags::CLOCAL | ControlFlags::CREAD);
+
+    control_flags.remove(ControlFlags::CRTSCTS);
+    termios
+        .input_flags
+        .remove(InputFlags::IXON | InputFlags::IXOFF | InputFlags::IXANY);
+    match config.flow_control {
+        FlowControl::None => {}
+        FlowControl::Hardware => termios.control_flags.insert(ControlFlags::CRTSCTS),
+        FlowControl::Software => termios
+            .input_flags
+            .insert(InputFlags::IXON | InputFlags::IXOFF),
+    }
+
+    tcsetattr(fd, SetArg::TCSANOW, &termios)?;
+    Ok(())
+}
+
+fn baud_rate(rate: u32) -> Option<BaudRate> {
+    Some(match rate {
+        300 => BaudRate::B300,
+        600 => BaudRate::B600,
+        1200 => BaudRate::B1200,
+        2400 => BaudRate::B2400,
+        4800 => BaudRate::B4800,
+        9600 => BaudRate::B9600,
+        19200 => BaudRate::B19200,
+        38400 => BaudRate::B38400,
+        57600 => BaudRate::B57600,
+        115200 => BaudRate::B115200,
+        230400 => BaudRate::B230400,
+        #[cfg(target_os = "linux")]
+        460800 => BaudRate::B460800,
+        #[cfg(target_os = "linux")]
+        921600 => BaudRate::B921600,
+        #[cfg(target_os = "linux")]
+        1000000 => BaudRate::B1000000,
+        #[cfg(target_os = "linux")]
+        1500000 => BaudRate::B1500000,
+        #[cfg(target_os = "linux")]
+        2000000 => BaudRate::B2000000,
+        #[cfg(target_os = "linux")]
+        3000000 => BaudRate::B3000000,
+        #[cfg(target_os = "linux")]
+        4000000 => BaudRate::B4000000,
+        _ => return None,
+    })
+}
@@ -0,0 +1,495 @@
+use crate::control::LISTEN_SOCKET_ENV_VAR;
+use crate::shell_integration::shell_integration;
+use crate::terminfo::term_name;
+use libc::{c_char, execve};
+use nix::{
+    errno::Errno,
+    unistd::{close, getuid, read, write, User},
+};
+#[cfg(not(target_os = "macos"))]
+use nix::{fcntl::OFlag, unistd::pipe2};
+#[cfg(target_os = "macos")]
+use nix::{
+    fcntl::{fcntl, FcntlArg, FdFlag},
+    unistd::pipe,
+};
+use std::{
+    env,
+    ffi::CString,
+    os::{fd::RawFd, unix::ffi::OsStringExt},
+    path::{Path, PathBuf},
+    ptr,
+};
+
+// Describes how a ShellLayer should launch the program on the other end of the pty.
+pub struct SpawnConfig {
+    // A login shell reads the extra startup files (eg. .zprofile or .bash_profile) which
+    // set up things like $PATH. Most terminal emulators spawn one by default.
+    pub login_shell: bool,
+    // Run this instead of the user's shell, eg. ["vim", "notes.txt"]. The program is looked up
+    // in $PATH unless it has a slash in it.
+    pub command: Option<Vec<String>>,
+    // Where the child starts off. If it's not set (or we can't go there), we use $HOME.
+    pub working_directory: Option<PathBuf>,
+    // The window's control socket (see control.rs), which we tell the child about so that
+    // scripts running in it can use velocity-ctl
+    pub listen_socket: Option<PathBuf>,
+    // Whether to get the user's shell to run our shell integration script (see
+    // shell_integration.rs). Commands are always run as they are.
+    pub shell_integration: bool,
+}
+
+impl SpawnConfig {
+    pub fn new() -> Self {
+        SpawnConfig {
+            login_shell: true,
+            command: None,
+            working_directory: None,
+            listen_socket: None,
+            shell_integration: true,
+        }
+    }
+
+    // The command, if there's actually one to run
+    pub(super) fn command(&self) -> Option<&[String]> {
+        self.command.as_deref().filter(|c| !c.is_empty())
+    }
+
+    // What we call the child in error messages
+    pub(super) fn program_description(&self) -> String {
+        match self.command() {
+            Some(command) => format!("'{}'", command[0]),
+            None => "a shell".to_string(),
+        }
+    }
+}
+
+impl Default for SpawnConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The shells we'll try to exec, in order of preference. This is roughly what xterm does:
+// $SHELL first, then the user's entry in /etc/passwd, then /bin/sh because POSIX promises
+// that it exists.
+fn user_shell_candidates() -> Vec<String> {
+    let mut candidates = vec![];
+
+    if let Ok(shell) = env::var("SHELL") {
+        if !shell.is_empty() {
+            candidates.push(shell);
+        }
+    }
+
+    if let Ok(Some(user)) = User::from_uid(getuid()) {
+        let passwd_shell = user.shell.to_string_lossy().to_string();
+        if !passwd_shell.is_empty() && !candidates.contains(&passwd_shell) {
+            candidates.push(passwd_shell);
+        }
+    }
+
+    if !candidates.iter().any(|c| c == "/bin/sh") {
+        candidates.push("/bin/sh".to_string());
+    }
+
+    candidates
+}
+
+// Like execvp() does, we try each directory in $PATH in turn. We can't use execvp() itself
+// because we need to pass our own environment, and execvpe() only exists on Linux.
+fn command_path_candidates(program: &str) -> Vec<String> {
+    if program.contains('/') {
+        return vec![program.to_string()];
+    }
+
+    let path = env::var("PATH").unwrap_or("/usr/local/bin:/usr/bin:/bin".to_string());
+    path.split(':')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| format!("{}/{}", dir, program))
+        .collect()
+}
+
+// Something we could exec() to become the child program
+pub(super) struct ExecCandidate {
+    pub path: CString,
+    pub argv: Vec<CString>,
+    // Variables just for this candidate, eg. for its shell integration. These win over the
+    // environment we'd otherwise give it.
+    pub env_vars: Vec<CString>,
+}
+
+// Everything we'll try to exec(), in order. Work this out before forking - it reads
+// /etc/passwd, which isn't something we want to be doing in a freshly forked child.
+pub(super) fn exec_candidates(spawn_config: &SpawnConfig, login_shell: bool) -> Vec<ExecCandidate> {
+    if let Some(command) = spawn_config.command() {
+        let argv: Vec<CString> = command
+            .iter()
+            .map(|arg| CString::new(arg.as_str()).unwrap())
+            .collect();
+        return command_path_candidates(&command[0])
+            .into_iter()
+            .map(|path| ExecCandidate {
+                path: CString::new(path).unwrap(),
+                argv: argv.clone(),
+                env_vars: vec![],
+            })
+            .collect();
+    }
+
+    user_shell_candidates()
+        .into_iter()
+        .map(|shell| {
+            let integration = spawn_config
+                .shell_integration
+                .then(|| shell_integration(&shell, login_shell))
+                .flatten();
+            let Some(integration) = integration else {
+                return ExecCandidate {
+                    argv: vec![shell_argv0(&shell, login_shell)],
+                    path: CString::new(shell).unwrap(),
+                    env_vars: vec![],
+                };
+            };
+            let mut argv = vec![shell_argv0(
+                &shell,
+                login_shell && !integration.emulates_login_shell,
+            )];
+            argv.extend(
+                integration
+                    .args
+                    .into_iter()
+                    .map(|arg| CString::new(arg).unwrap()),
+            );
+            ExecCandidate {
+                argv,
+                path: CString::new(shell).unwrap(),
+                env_vars: to_c_strings(integration.env_vars),
+            }
+        })
+        .collect()
+}
+
+// The child tries each of these in turn until one works. If someone asked for a directory that
+// doesn't exist, it's friendlier to open the window at home than not at all.
+pub(super) fn working_directory_candidates(spawn_config: &SpawnConfig) -> Vec<PathBuf> {
+    let mut candidates = vec![];
+    if let Some(working_directory) = &spawn_config.working_directory {
+        if !working_directory.is_dir() {
+            println!(
+                "{} isn't a directory, starting in $HOME instead",
+                working_directory.display()
+            );
+        }
+        candidates.push(working_directory.clone());
+    }
+    if let Ok(home) = env::var("HOME") {
+        candidates.push(PathBuf::from(home));
+    }
+    candidates.push(PathBuf::from("/"));
+    candidates
+}
+
+// Everything the child needs to exec(), worked out before we fork. We usually aren't the only
+// thread (there's the pty reader, the control server, and in velocity-server, every session),
+// and fork() only copies the thread that called it. If another one was holding malloc's lock at
+// the time, nobody will ever let go of it in the child. So between fork() and exec(), the child
+// mustn't allocate anything - no Vecs, CStrings or format!(), just system calls on what's
+// already here.
+pub(super) struct PreparedExec {
+    // The pointers below point into these, so they have to live as long as we do. Moving a
+    // CString doesn't move its bytes, so the pointers stay valid.
+    _candidates: Vec<ExecCandidate>,
+    _env_vars: Vec<CString>,
+    execs: Vec<PreparedCandidate>,
+    // The child tries each of these in turn until one works
+    working_directories: Vec<CString>,
+    // Eg. "velocity: couldn't run 'vim': ". The child adds why.
+    failure_message: Vec<u8>,
+}
+
+struct PreparedCandidate {
+    path: *const c_char,
+    // Both NULL-terminated
+    argv: Vec<*const c_char>,
+    env_vars: Vec<*const c_char>,
+}
+
+impl PreparedExec {
+    pub fn new(
+        candidates: Vec<ExecCandidate>,
+        env_vars: Vec<CString>,
+        working_directories: Vec<PathBuf>,
+        program_description: &str,
+    ) -> Self {
+        let execs = candidates
+            .iter()
+            .map(|candidate| {
+                let mut argv: Vec<*const c_char> =
+                    candidate.argv.iter().map(|a| a.as_ptr()).collect();
+                argv.push(ptr::null());
+
+                let mut candidate_env_vars: Vec<*const c_char> =
+                    candidate.env_vars.iter().map(|v| v.as_ptr()).collect();
+                candidate_env_vars.extend(
+                    env_vars
+                        .iter()
+                        .filter(|var| !candidate.env_vars.iter().any(|v| same_env_var_name(v, var)))
+                        .map(|var| var.as_ptr()),
+                );
+                candidate_env_vars.push(ptr::null());
+
+                PreparedCandidate {
+                    path: candidate.path.as_ptr(),
+                    argv,
+                    env_vars: candidate_env_vars,
+                }
+            })
+            .collect();
+
+        let working_directories = working_directories
+            .into_iter()
+            .filter_map(|directory| CString::new(directory.into_os_string().into_vec()).ok())
+            .collect();
+
+        PreparedExec {
+            _candidates: candidates,
+            _env_vars: env_vars,
+            execs,
+            working_directories,
+            failure_message: format!("velocity: couldn't run {}: ", program_description)
+                .into_bytes(),
+        }
+    }
+
+    // Called in the child once its stdio is the pty. Goes through each candidate execing it, and
+    // only returns (by exiting) if none of them worked.
+    pub unsafe fn exec(&self, exec_failure_pipe: &ExecFailurePipe) -> ! {
+        // Change to the requested directory (usually the user's home) before spawning the shell
+        for working_directory in &self.working_directories {
+            if libc::chdir(working_directory.as_ptr()) == 0 {
+                break;
+            }
+        }
+
+        let mut last_errno = Errno::ENOENT;
+        for exec in &self.execs {
+            // This replaces our executable with the image at exec.path. So we the child
+            // process *become* that program. It only returns if something went wrong.
+            execve(exec.path, exec.argv.as_ptr(), exec.env_vars.as_ptr());
+
+            // Like execvp(), "not found" is the least interesting reason something failed. If a
+            // program exists but we're not allowed to run it, that's what we'll report.
+            let errno = Errno::last();
+            if errno != Errno::ENOENT || last_errno == Errno::ENOENT {
+                last_errno = errno;
+            }
+        }
+        exec_failure_pipe.report_failure_and_exit(&self.failure_message, last_errno);
+    }
+}
+
+// Whether two "KEY=value" strings are setting the same variable
+fn same_env_var_name(a: &CString, b: &CString) -> bool {
+    let a = a.as_bytes();
+    let b = b.as_bytes();
+    match (
+        a.iter().position(|&c| c == b'='),
+        b.iter().position(|&c| c == b'='),
+    ) {
+        (Some(a_end), Some(b_end)) => a[..a_end] == b[..b_end],
+        _ => false,
+    }
+}
+
+// By convention, a program is a login shell if its argv[0] starts with a dash. This is what
+// login(1) does, and unlike "--login", every shell (even dash) understands it.
+pub(super) fn shell_argv0(shell_path: &str, login_shell: bool) -> CString {
+    let name = Path::new(shell_path)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| shell_path.to_string());
+
+    if login_shell {
+        CString::new(format!("-{}", name)).unwrap()
+    } else {
+        CString::new(name).unwrap()
+    }
+}
+
+// Variables that describe the terminal, so we don't pass on the values that were meant for
+// whatever launched us.
+// COLORTERM is how programs find out that they can use 24-bit colour ("truecolor"). We only have
+// the 16 colours in our terminfo entry, so we leave it unset.
+// TODO: Set COLORTERM=truecolor once TerminalColour has an RGB variant
+// VELOCITY_LISTEN_SOCKET belongs to whichever window set it, so we only pass on our own.
+const TERMINAL_ENV_VARS: [&str; 5] = [
+    "TERM",
+    "TERM_PROGRAM",
+    "TERM_PROGRAM_VERSION",
+    "COLORTERM",
+    LISTEN_SOCKET_ENV_VAR,
+];
+
+// Everything the child should have in its environment. Call this before forking, because it
+// might have to run tic (see terminfo::term_name).
+pub(super) fn child_env_vars(spawn_config: &SpawnConfig) -> Vec<CString> {
+    let mut env_vars = inherited_env_vars();
+    env_vars.extend(terminal_env_vars(spawn_config));
+    env_vars
+}
+
+// Copies our own environment for the child. This gives it the important default ones like PATH.
+// We skip the ones we set ourselves. Nothing says they have to be UTF-8, so they're copied as
+// bytes.
+fn inherited_env_vars() -> Vec<CString> {
+    env::vars_os()
+        .filter(|(key, _)| {
+            !key.to_str()
+                .is_some_and(|key| TERMINAL_ENV_VARS.contains(&key))
+        })
+        .filter_map(|(key, value)| {
+            let mut env_var = key.into_vec();
+            env_var.push(b'=');
+            env_var.extend(value.into_vec());
+            // They came to us as C strings, so there shouldn't be a NUL in them. If there is, the
+            // child can do without that one.
+            CString::new(env_var).ok()
+        })
+        .collect()
+}
+
+// The variables we set ourselves, to tell programs what they're running in
+pub(super) fn terminal_env_vars(spawn_config: &SpawnConfig) -> Vec<CString> {
+    let mut env_vars = vec![
+        // This is very important, otherwise the shell won't talk to us properly
+        format!("TERM={}", term_name()),
+        // This is just showing off :)
+        "TERM_PROGRAM=Velocity".to_string(),
+        format!("TERM_PROGRAM_VERSION={}", env!("CARGO_PKG_VERSION")),
+    ];
+    if let Some(listen_socket) = &spawn_config.listen_socket {
+        env_vars.push(format!(
+            "{}={}",
+            LISTEN_SOCKET_ENV_VAR,
+            listen_socket.display()
+        ));
+    }
+    to_c_strings(env_vars)
+}
+
+// login(1) picks the shell itself (from /etc/passwd), so all we can do is set the environment
+// for it. That's enough for zsh and fish, but bash's script needs its own arguments.
+// TODO: Start bash ourselves with --rcfile, like we do without login(1)
+#[cfg(target_os = "macos")]
+pub(super) fn login_shell_integration_env_vars(spawn_config: &SpawnConfig) -> Vec<CString> {
+    if !spawn_config.shell_integration {
+        return vec![];
+    }
+    let Ok(Some(user)) = User::from_uid(getuid()) else {
+        return vec![];
+    };
+    match shell_integration(&user.shell.to_string_lossy(), true) {
+        Some(integration) if integration.args.is_empty() => to_c_strings(integration.env_vars),
+        _ => vec![],
+    }
+}
+
+fn to_c_strings(strings: Vec<String>) -> Vec<CString> {
+    strings
+        .into_iter()
+        .map(|string| CString::new(string).unwrap())
+        .collect()
+}
+
+// After we fork, the parent can't otherwise tell if exec() failed in the child - the child
+// would just sit there with nothing on the other end of the pty. So we give the child a pipe
+// that is closed automatically when exec() succeeds. If it fails, the child writes errno into
+// it before exiting.
+pub(super) struct ExecFailurePipe {
+    read_fd: RawFd,
+    write_fd: RawFd,
+}
+
+impl ExecFailurePipe {
+    // Both ends are close-on-exec from the start. Otherwise, a child that another thread forks
+    // at the same time could inherit the write end, and we'd wait for that child to exec too.
+    #[cfg(not(target_os = "macos"))]
+    pub fn new() -> Self {
+        let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC).expect("pipe2() failed");
+        ExecFailurePipe { read_fd, write_fd }
+    }
+
+    // macOS doesn't have pipe2(), so there's a moment where another thread's fork() could still
+    // catch these
+    #[cfg(target_os = "macos")]
+    pub fn new() -> Self {
+        let (read_fd, write_fd) = pipe().expect("pipe() failed");
+        for fd in [read_fd, write_fd] {
+            fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).unwrap();
+        }
+        ExecFailurePipe { read_fd, write_fd }
+    }
+
+    // Called in the child straight after the fork
+    pub fn close_read_end(&self) {
+        close(self.read_fd).unwrap();
+    }
+
+    // Called in the child once we've given up on exec(). By now stderr is the pty, so the
+    // message appears in the window. Like the rest of the child, this can't allocate (see
+    // PreparedExec), which is why the message goes out in pieces. desc() is a static string.
+    fn report_failure_and_exit(&self, failure_message: &[u8], errno: Errno) -> ! {
+        let _ = write(libc::STDERR_FILENO, failure_message);
+        let _ = write(libc::STDERR_FILENO, errno.desc().as_bytes());
+        let _ = write(libc::STDERR_FILENO, b"\n");
+        let _ = write(self.write_fd, &(errno as i32).to_ne_bytes());
+        // 127 is what shells use for "command not found"
+        unsafe { libc::_exit(127) }
+    }
+
+    // Called in the parent. Blocks until the child has either exec'd or failed to.
+    pub fn wait_for_exec(self) -> Result<(), Errno> {
+        close(self.write_fd).unwrap();
+
+        let mut errno_bytes = [0u8; 4];
+        let mut total_read = 0;
+        while total_read < errno_bytes.len() {
+            match read(self.read_fd, &mut errno_bytes[total_read..]) {
+                Ok(0) => break,
+                Ok(n) => total_read += n,
+                Err(Errno::EINTR) => continue,
+                Err(_) => break,
+            }
+        }
+        close(self.read_fd).unwrap();
+
+        if total_read == errno_bytes.len() {
+            Err(Errno::from_i32(i32::from_ne_bytes(errno_bytes)))
+        } else {
+            // EOF without a message means the pipe was closed by a successful exec()
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsString;
+
+    #[test]
+    fn inherited_env_vars_dont_have_to_be_utf8() {
+        let name = format!("VELOCITY_TEST_NOT_UTF8_{}", std::process::id());
+        env::set_var(&name, OsString::from_vec(b"caf\xe9".to_vec()));
+
+        let env_vars = inherited_env_vars();
+        env::remove_var(&name);
+
+        let mut expected = name.as_bytes().to_vec();
+        expected.extend_from_slice(b"=caf\xe9");
+        assert!(env_vars
+            .iter()
+            .any(|env_var| env_var.as_bytes() == expected));
+    }
+}
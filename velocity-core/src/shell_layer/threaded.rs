@@ -0,0 +1,214 @@
+use super::{CellPixelSize, ShellLayer, ShellLayerEvent, Signal};
+use crate::constants::*;
+
+use nix::poll::{poll, PollFd, PollFlags};
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind, Read},
+    os::fd::AsRawFd,
+    path::PathBuf,
+    sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, TryRecvError},
+    thread,
+    time::Duration,
+};
+
+// How many FD_BUFFER_SIZE_BYTES chunks the reader thread can get ahead of us by. Once it's that
+// far ahead, it stops reading until we catch up, and the pty stops the child from writing any
+// more. That way, something like `yes` is slowed down to the speed we can draw it at, rather
+// than filling up our memory.
+const MAX_CHUNKS_AHEAD: usize = 16;
+
+// Wraps another ShellLayer and reads from it on a dedicated thread. The UI thread never has to
+// sit in poll() waiting for the child, and when a program prints a lot we can take as much of it
+// as has arrived each frame instead of one FD_BUFFER_SIZE_BYTES chunk at a time.
+pub struct ThreadedShellLayer {
+    inner: Box<dyn ShellLayer>,
+    receiver: Receiver<Vec<u8>>,
+    // Bytes the reader thread has given us that haven't been read() yet. We only take one chunk
+    // at a time, so this never holds more than that.
+    pending: VecDeque<u8>,
+    // The reader thread has finished because the child hung up. From then on we let the inner
+    // layer read so that it notices and reports the exit.
+    hung_up: bool,
+    // ...and it has. Nothing else is going to happen.
+    exited: bool,
+}
+
+impl ShellLayer for ThreadedShellLayer {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        // This runs every frame, so it's a good time to send any input that's been queued up
+        self.inner.flush_pending_writes();
+
+        self.receive_chunk();
+        if self.pending.is_empty() {
+            if self.hung_up {
+                return self.inner.read(buffer);
+            }
+            return Err(ErrorKind::WouldBlock.into());
+        }
+
+        let count = self.pending.len().min(buffer.len());
+        for (i, b) in self.pending.drain(..count).enumerate() {
+            buffer[i] = b;
+        }
+        Ok(count)
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        self.inner.write(data);
+    }
+
+    fn write_response(&mut self, data: &[u8]) {
+        self.inner.write_response(data);
+    }
+
+    fn resized(&mut self, new_rows: usize, new_cols: usize, cell_pixel_size: CellPixelSize) {
+        self.inner.resized(new_rows, new_cols, cell_pixel_size);
+    }
+
+    fn next_event(&mut self) -> Option<ShellLayerEvent> {
+        let event = self.inner.next_event();
+        if let Some(ShellLayerEvent::ChildExited { .. }) = event {
+            self.exited = true;
+        }
+        event
+    }
+
+    fn flush_pending_writes(&mut self) {
+        self.inner.flush_pending_writes();
+    }
+
+    fn pending_write_bytes(&self) -> usize {
+        self.inner.pending_write_bytes()
+    }
+
+    fn signal_foreground_process_group(&mut self, signal: Signal) -> nix::Result<()> {
+        self.inner.signal_foreground_process_group(signal)
+    }
+
+    fn shell_pid(&self) -> Option<i32> {
+        self.inner.shell_pid()
+    }
+
+    fn tty_path(&self) -> Option<PathBuf> {
+        self.inner.tty_path()
+    }
+
+    fn is_echo_disabled(&self) -> bool {
+        self.inner.is_echo_disabled()
+    }
+
+    fn foreground_process_name(&self) -> Option<String> {
+        self.inner.foreground_process_name()
+    }
+
+    fn foreground_process_argv(&self) -> Option<Vec<String>> {
+        self.inner.foreground_process_argv()
+    }
+
+    fn foreground_process_cwd(&self) -> Option<PathBuf> {
+        self.inner.foreground_process_cwd()
+    }
+
+    fn has_data_ready(&mut self) -> bool {
+        self.receive_chunk();
+        !self.pending.is_empty()
+    }
+
+    fn wait_for_data(&mut self, timeout: Duration) -> bool {
+        self.inner.flush_pending_writes();
+        if self.has_data_ready() {
+            return true;
+        }
+        if self.exited {
+            // There's never going to be anything to read, but frontends that use us to pace
+            // their frames (eg. with --hold) would spin if we didn't wait
+            thread::sleep(timeout);
+            return false;
+        }
+        if self.hung_up {
+            // The inner layer still has to read() to find out how the child exited
+            return true;
+        }
+
+        match self.receiver.recv_timeout(timeout) {
+            Ok(chunk) => {
+                self.pending.extend(chunk);
+                true
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => {
+                self.hung_up = true;
+                true
+            }
+        }
+    }
+}
+
+impl ThreadedShellLayer {
+    // on_data_ready is called from the reader thread every time it gets some bytes. Use it to
+    // wake up your event loop if it can be woken (eg. by pushing a custom event). Otherwise, just
+    // call TtyState::wait_for_output() before TtyState::read().
+    pub fn new(
+        mut inner: Box<dyn ShellLayer>,
+        on_data_ready: Option<Box<dyn Fn() + Send>>,
+    ) -> Self {
+        let mut reader = inner
+            .try_clone_reader()
+            .expect("This ShellLayer can't be read from another thread");
+        let (sender, receiver) = sync_channel(MAX_CHUNKS_AHEAD);
+
+        thread::Builder::new()
+            .name("velocity-pty-reader".to_string())
+            .spawn(move || {
+                let mut buffer = [0; FD_BUFFER_SIZE_BYTES];
+                loop {
+                    let read_count = match reader.read(&mut buffer) {
+                        Ok(0) => break,
+                        Ok(n) => n,
+                        Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                        // The fd might be non-blocking, in which case we do the waiting here
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                            let poll_fd = PollFd::new(reader.as_raw_fd(), PollFlags::POLLIN);
+                            let _ = poll(&mut [poll_fd], -1);
+                            continue;
+                        }
+                        // Usually EIO, which means the child hung up
+                        Err(_) => break,
+                    };
+                    // This waits while we're too far ahead. If the other end has gone, there's
+                    // nobody to read for.
+                    if sender.send(buffer[..read_count].to_vec()).is_err() {
+                        break;
+                    }
+                    if let Some(on_data_ready) = &on_data_ready {
+                        on_data_ready();
+                    }
+                }
+                // Dropping the sender here is how the UI thread finds out that we're done
+            })
+            .expect("Failed to spawn the pty reader thread");
+
+        ThreadedShellLayer {
+            inner,
+            receiver,
+            pending: VecDeque::new(),
+            hung_up: false,
+            exited: false,
+        }
+    }
+
+    // If we've handed out everything we had, takes the next chunk from the reader thread without
+    // waiting. Taking one at a time leaves the rest in the channel, which is what holds the
+    // reader thread back.
+    fn receive_chunk(&mut self) {
+        if !self.pending.is_empty() {
+            return;
+        }
+        match self.receiver.try_recv() {
+            Ok(chunk) => self.pending.extend(chunk),
+            Err(TryRecvError::Empty) => {}
+            Err(TryRecvError::Disconnected) => self.hung_up = true,
+        }
+    }
+}
@@ -0,0 +1,104 @@
+// Our terminfo entry, which tells programs exactly which escape sequences we understand, and
+// working out what to set $TERM to.
+use crate::constants::FALLBACK_TERM;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::OnceLock,
+};
+
+pub const TERMINFO_NAME: &str = "velocity";
+pub const TERMINFO_SOURCE: &str = include_str!("../terminfo/velocity.terminfo");
+
+// Where terminfo databases usually live, after the user's own ~/.terminfo
+const SYSTEM_TERMINFO_DIRECTORIES: [&str; 5] = [
+    "/etc/terminfo",
+    "/lib/terminfo",
+    "/usr/share/terminfo",
+    "/usr/lib/terminfo",
+    "/usr/share/lib/terminfo",
+];
+
+// What we tell programs we are with $TERM. That's our own entry if it's installed (or we can
+// install it), and FALLBACK_TERM otherwise. We only work this out once per run, because it might
+// mean running tic.
+pub fn term_name() -> &'static str {
+    static TERM_NAME: OnceLock<&'static str> = OnceLock::new();
+    TERM_NAME.get_or_init(|| {
+        if install_for_user() || is_installed_system_wide() {
+            TERMINFO_NAME
+        } else {
+            FALLBACK_TERM
+        }
+    })
+}
+
+// Compiles our entry into ~/.terminfo, which curses looks in before the system directories. We
+// keep a copy of the source next to it, so that we only run tic the first time (and again when
+// the entry changes in a new version of Velocity).
+fn install_for_user() -> bool {
+    let Some(home) = env::var_os("HOME") else {
+        return false;
+    };
+    let directory = PathBuf::from(home).join(".terminfo");
+    let source_path = directory.join("velocity.terminfo");
+
+    let up_to_date = fs::read_to_string(&source_path).ok().as_deref() == Some(TERMINFO_SOURCE);
+    if up_to_date && is_compiled_in(&directory) {
+        return true;
+    }
+
+    if let Err(err) = fs::create_dir_all(&directory) {
+        println!("Couldn't create {}: {}", directory.display(), err);
+        return false;
+    }
+    if let Err(err) = fs::write(&source_path, TERMINFO_SOURCE) {
+        println!("Couldn't write {}: {}", source_path.display(), err);
+        return false;
+    }
+
+    // -x keeps the extended capabilities, like the ones for bracketed paste
+    let tic_result = Command::new("tic")
+        .arg("-x")
+        .arg("-o")
+        .arg(&directory)
+        .arg(&source_path)
+        .stdout(Stdio::null())
+        .status();
+    match tic_result {
+        Ok(status) if status.success() => {
+            println!("Installed the {} terminfo entry", TERMINFO_NAME);
+            true
+        }
+        Ok(status) => {
+            println!("tic failed to compile our terminfo entry ({})", status);
+            // So that we try again next time
+            let _ = fs::remove_file(&source_path);
+            false
+        }
+        // Usually because tic isn't installed. There's nothing wrong with the fallback.
+        Err(err) => {
+            println!("Couldn't run tic to install our terminfo entry: {}", err);
+            let _ = fs::remove_file(&source_path);
+            false
+        }
+    }
+}
+
+// Eg. if a package manager has installed it for us
+fn is_installed_system_wide() -> bool {
+    SYSTEM_TERMINFO_DIRECTORIES
+        .iter()
+        .any(|directory| is_compiled_in(Path::new(directory)))
+}
+
+fn is_compiled_in(directory: &Path) -> bool {
+    // Entries are filed under their first letter. Most systems use the letter itself, but macOS
+    // uses it in hex, because its filesystem is case-insensitive.
+    let first_letter = &TERMINFO_NAME[..1];
+    let hex_first_letter = format!("{:x}", TERMINFO_NAME.as_bytes()[0]);
+    [first_letter, hex_first_letter.as_str()]
+        .iter()
+        .any(|subdirectory| directory.join(subdirectory).join(TERMINFO_NAME).exists())
+}
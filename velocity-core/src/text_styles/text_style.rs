@@ -115,6 +115,36 @@ impl TextStyle {
         }
     }
 
+    // The SGR sequence that sets a blank style to this one, eg. ESC[0;1;31m for bold red
+    pub fn to_escape_sequence(&self) -> String {
+        let mut codes = vec![SGRCode::ResetAllTextStyles as usize];
+        let flags = [
+            (self.bold, SGRCode::EnableBoldText),
+            (self.faint, SGRCode::EnableFaintText),
+            (self.italic, SGRCode::EnableItalicText),
+            (self.underlined, SGRCode::EnableUnderlinedText),
+            (self.reverse_video, SGRCode::EnableReverseVideoMode),
+            (self.invisible, SGRCode::EnableInvisibleText),
+            (self.strikethrough, SGRCode::EnableStrikethroughText),
+        ];
+        for (enabled, sgr) in flags {
+            if enabled {
+                codes.push(sgr as usize);
+            }
+        }
+        match self.blinking {
+            BlinkingMode::None => {}
+            BlinkingMode::Slow => codes.push(SGRCode::EnableSlowBlinkingText as usize),
+            BlinkingMode::Rapid => codes.push(SGRCode::EnableRapidBlinkingText as usize),
+        }
+        // The reverse of apply_basic_colour_setter
+        codes.push(colour_sgr_code(self.foreground, 30));
+        codes.push(colour_sgr_code(self.background, 40));
+
+        let codes: Vec<String> = codes.iter().map(|code| code.to_string()).collect();
+        format!("\x1b[{}m", codes.join(";"))
+    }
+
     pub fn new() -> TextStyle {
         TextStyle {
             bold: false,
@@ -138,3 +168,13 @@ fn sgr_code_is_a_basic_colour_setter(sgr: &SGRCode) -> bool {
         _ => false,
     }
 }
+
+// base is 30 for foreground colours, or 40 for background ones
+fn colour_sgr_code(colour: TerminalColour, base: usize) -> usize {
+    match colour as usize {
+        // Bright colours start at 10, and their codes start at 90 (or 100)
+        n if n >= 10 => base + 60 + n - 10,
+        // This includes Default, which is 9
+        n => base + n,
+    }
+}
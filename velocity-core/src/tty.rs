@@ -1,20 +1,40 @@
+use std::borrow::Cow;
 use std::cmp::{max, min};
 use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::io::{self, ErrorKind};
+use std::iter::repeat_n;
+use std::ops::Range;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
+use crate::asciicast::{default_recording_path, AsciicastRecorder};
+use crate::broadcast::{BroadcastMembership, DEFAULT_BROADCAST_GROUP};
 use crate::constants::{special_characters::*, *};
+use crate::control::{ControlReply, ControlServer};
 use crate::escape_sequence::parser::{EscapeSequenceParser, SequenceFinished};
 use crate::escape_sequence::sequence::{
     CharacterSet, EraseInDisplayType, EraseInLineType, EscapeSequence, SetCursorPositionArgs,
-    SetOrResetModeType,
+    SetOrResetModeType, SetScrollingRegionArgs, ShellIntegrationMark, TerminalColour,
+};
+use crate::local_echo::{LocalEcho, LocalEchoMode};
+use crate::session_log::{SessionLogConfig, SessionLogMode, SessionLogger};
+use crate::shell_layer::{
+    get_shell_layer, spawn_config::SpawnConfig, CellPixelSize, ChildExitStatus, ShellLayer,
+    ShellLayerEvent, Signal,
 };
-use crate::shell_layer::{get_shell_layer, ShellLayer};
 use crate::text_styles::decorated_char::DecoratedChar;
 use crate::text_styles::text_style::TextStyle;
+use nix::unistd::gethostname;
 
 // Max memory (roughly) in bytes that can be used by the scrollback buffer.
 // "Roughly" because it's actually measured in Unicode graphemes. If you're
 // using a lot of 4-byte chars, this could actually be more.
 const MAX_SCROLLBACK_BUFFER_CHARACTERS: usize = 200_000_000;
+// The longest we'll spend consuming output in one call to read() when the shell layer keeps
+// having more for us.
+const READ_TIME_BUDGET: Duration = Duration::from_millis(FD_POLL_TIMEOUT_MS as u64);
 
 pub struct TtySize {
     pub cols: usize,
@@ -41,8 +61,8 @@ enum InsertionMode {
     Insert,
 }
 
-type LineType = VecDeque<DecoratedChar>;
-type ScrollbackBufferType = VecDeque<LineType>;
+pub(crate) type LineType = VecDeque<DecoratedChar>;
+pub(crate) type ScrollbackBufferType = VecDeque<LineType>;
 
 pub struct TtyState {
     pub size: TtySize,
@@ -53,7 +73,6 @@ pub struct TtyState {
     pub bracketed_paste_mode: bool,
     character_set: CharacterSet,
     read_buffer: [u8; FD_BUFFER_SIZE_BYTES],
-    read_buffer_length: usize,
     shell_layer: Box<dyn ShellLayer>,
     // As we encounter unfinished unicode surrogates, we'll push them here.
     // When we find the end, the bytes will encode a char.
@@ -76,20 +95,48 @@ pub struct TtyState {
     // (and stomps at) the end of the screen. Programs can disable it with a private
     //  escape code.
     autowrap: bool,
+    // The scrolling region (DECSTBM), as the first and last rows of the screen that scroll.
+    // Lines outside of it stay where they are.
+    scroll_region_top: usize,
+    scroll_region_bottom: usize,
+    // (DECOM) While this is on, cursor positions count from the top of the scrolling region,
+    // and the cursor can't leave it
+    origin_mode: bool,
     // Cursor keys are either 'normal' or 'application'. This changes the byte we
     // send in the middle of cursor key inputs. Similar to bracketed paste, see
     // frontends like SFML for actual implementation.
     pub application_cursor_keys: bool,
+    // Set once the shell (or whatever program we're running) has exited. Frontends can close
+    // their window when they see this, or print it and stay open.
+    pub child_exit_status: Option<ChildExitStatus>,
+    // Set while we're recording the session, see start_recording()
+    recorder: Option<AsciicastRecorder>,
+    // Set while we're logging the session to disk, see start_logging()
+    logger: Option<SessionLogger>,
+    // How many lines at the top of the scrollback buffer have been written to a plain text log,
+    // so that lines that scroll back down (eg. when the window gets taller) aren't logged twice
+    logged_lines: usize,
+    // Keystrokes we've drawn before the program has echoed them, see local_echo.rs
+    local_echo: LocalEcho,
+    // Set while a full-screen program is using the alternate screen.
+    // TODO: Give the alternate screen its own buffer, so that the scrollback survives vim
+    alternate_screen: bool,
+    // Set while we're in a broadcast group, see join_broadcast_group()
+    broadcast: Option<BroadcastMembership>,
+    // What the shell has told us about itself, if it has shell integration
+    // (see shell_integration.rs)
+    shell_working_directory: Option<PathBuf>,
+    last_command_exit_status: Option<i32>,
 }
 
 impl TtyState {
     fn apply_escape_sequence(&mut self, seq: &EscapeSequence) {
         match seq {
             EscapeSequence::MoveCursorUp(n) => {
-                self.set_cursor_pos(self.cursor_pos.x, self.cursor_pos.y - n)
+                self.set_cursor_pos_within_margins(self.cursor_pos.x, self.cursor_pos.y - n)
             }
             EscapeSequence::MoveCursorDown(n) => {
-                self.set_cursor_pos(self.cursor_pos.x, self.cursor_pos.y + n)
+                self.set_cursor_pos_within_margins(self.cursor_pos.x, self.cursor_pos.y + n)
             }
             // TODO: Does this break over line boundaries?
             EscapeSequence::MoveCursorForward(n) => {
@@ -99,13 +146,14 @@ impl TtyState {
                 self.set_cursor_pos(self.cursor_pos.x - n, self.cursor_pos.y)
             }
             EscapeSequence::MoveCursorToNextLine(n) => {
-                self.set_cursor_pos(0, self.cursor_pos.y + n)
+                self.set_cursor_pos_within_margins(0, self.cursor_pos.y + n)
             }
             EscapeSequence::MoveCursorToPreviousLine(n) => {
-                self.set_cursor_pos(0, self.cursor_pos.y - n)
+                self.set_cursor_pos_within_margins(0, self.cursor_pos.y - n)
             }
+            // Columns and lines count from 1, and 0 means 1 too
             EscapeSequence::MoveCursorHorizontalAbsolute(n) => {
-                self.set_cursor_pos(*n, self.cursor_pos.y)
+                self.set_cursor_pos((*n).max(1) - 1, self.cursor_pos.y)
             }
             EscapeSequence::SetCursorPosition(p) => self.apply_sequence_set_cursor_position(p),
             EscapeSequence::EraseInDisplay(e) => self.apply_sequence_erase_in_display(e),
@@ -113,31 +161,85 @@ impl TtyState {
             EscapeSequence::SelectGraphicRendition(_) => self.text_style.apply_escape_sequence(seq),
             EscapeSequence::PrivateEnableBracketedPasteMode => self.bracketed_paste_mode = true,
             EscapeSequence::PrivateDisableBracketedPasteMode => self.bracketed_paste_mode = false,
+            EscapeSequence::PrivateEnableAlternateScreen => self.alternate_screen = true,
+            EscapeSequence::PrivateDisableAlternateScreen => self.alternate_screen = false,
+            EscapeSequence::EnableOriginMode => {
+                self.origin_mode = true;
+                self.set_cursor_pos_from_origin(0, 0);
+            }
+            EscapeSequence::DisableOriginMode => {
+                self.origin_mode = false;
+                self.set_cursor_pos_from_origin(0, 0);
+            }
+            EscapeSequence::InsertLines(n) => self.apply_sequence_insert_lines(*n),
+            EscapeSequence::DeleteLines(n) => self.apply_sequence_delete_lines(*n),
+            // 0 means 1, like it does for most counts
+            EscapeSequence::ScrollUp(n) => self.scroll_region_up((*n).max(1) as usize),
+            EscapeSequence::ScrollDown(n) => self.scroll_region_down((*n).max(1) as usize),
+            EscapeSequence::SetScrollingRegion(args) => {
+                self.apply_sequence_set_scrolling_region(args)
+            }
             EscapeSequence::EnableAutoWrapMode => self.autowrap = true,
             EscapeSequence::DisableAutoWrapMode => self.autowrap = false,
             EscapeSequence::FullReset => self.apply_sequence_full_reset(),
             EscapeSequence::SwitchToApplicationCursorKeys => self.application_cursor_keys = true,
             EscapeSequence::SwitchToNormalCursorKeys => self.application_cursor_keys = false,
-            EscapeSequence::MoveCursorUpScrollingIfNecessary => {
-                self.apply_sequence_move_cursor_up_scrolling_if_necessary()
-            }
-            EscapeSequence::MoveCursorDownScrollingIfNecessary => {
-                self.apply_sequence_move_cursor_down_scrolling_if_necessary()
-            }
+            EscapeSequence::MoveCursorUpScrollingIfNecessary => self.reverse_index(),
+            EscapeSequence::MoveCursorDownScrollingIfNecessary => self.line_feed(),
             EscapeSequence::MoveCursorNextLineScrollingIfNecessary => {
-                self.apply_sequence_move_cursor_down_scrolling_if_necessary();
+                self.line_feed();
                 self.set_cursor_pos(0, self.cursor_pos.y);
             }
             EscapeSequence::SetMode(m) => self.apply_sequence_set_mode(m),
             EscapeSequence::ResetMode(m) => self.apply_sequence_reset_mode(m),
             EscapeSequence::DeleteCharacters(n) => self.apply_sequence_delete_characters(*n),
+            EscapeSequence::InsertCharacters(n) => self.apply_sequence_insert_characters(*n),
+            EscapeSequence::EraseCharacters(n) => self.apply_sequence_erase_characters(*n),
             EscapeSequence::ShowCursor => self.cursor_visible = true,
             EscapeSequence::HideCursor => self.cursor_visible = false,
             EscapeSequence::DesignateG0CharacterSet(cs) => self.character_set = *cs,
             EscapeSequence::RepeatPreviousCharacter(n) => {
                 self.apply_sequence_repeat_previous_character(*n)
             }
-            EscapeSequence::SetLinePositionAbsolute(n) => self.set_cursor_pos(0, *n - 1),
+            // The column stays where it is
+            EscapeSequence::SetLinePositionAbsolute(n) => {
+                self.set_cursor_pos_from_origin(self.cursor_pos.x, (*n).max(1) - 1)
+            }
+            EscapeSequence::DeviceStatusReport => self.respond("\x1b[0n"),
+            EscapeSequence::ReportCursorPosition => {
+                let (row, col) = self.reported_cursor_position();
+                self.respond(&format!("\x1b[{};{}R", row, col));
+            }
+            EscapeSequence::PrivateReportCursorPosition => {
+                // We only have the one page
+                let (row, col) = self.reported_cursor_position();
+                self.respond(&format!("\x1b[?{};{};1R", row, col));
+            }
+            // A VT100 with the Advanced Video Option, which is what most terminals say they are
+            EscapeSequence::RequestPrimaryDeviceAttributes => self.respond("\x1b[?1;2c"),
+            EscapeSequence::RequestSecondaryDeviceAttributes => {
+                self.respond(&format!("\x1b[>0;{};0c", firmware_version()))
+            }
+            // We don't have a unit ID, so it's all zeroes
+            EscapeSequence::RequestTertiaryDeviceAttributes => {
+                self.respond("\x1bP!|00000000\x1b\\")
+            }
+            EscapeSequence::RequestTerminalVersion => self.respond(&format!(
+                "\x1bP>|Velocity {}\x1b\\",
+                env!("CARGO_PKG_VERSION")
+            )),
+            EscapeSequence::ReportWorkingDirectory(url) => {
+                // Eg. over SSH, it's a directory on another machine, which is no use to us
+                if let Some(path) = local_path_from_file_url(url) {
+                    self.shell_working_directory = Some(path);
+                }
+            }
+            EscapeSequence::ShellIntegrationMark(mark) => {
+                // TODO: Remember where each prompt is, so the frontends can jump between them
+                if let ShellIntegrationMark::CommandFinished(status) = mark {
+                    self.last_command_exit_status = *status;
+                }
+            }
             // As we go through the process of implementing these, we'll keep adding new
             // parsing code that then makes this match arm reachable.
             #[allow(unreachable_patterns)]
@@ -145,24 +247,107 @@ impl TtyState {
         }
     }
 
+    // Answers the program, eg. when it asks where the cursor is. Unlike write(), this isn't
+    // something the user typed, so it isn't recorded, predicted or broadcast.
+    fn respond(&mut self, response: &str) {
+        if self.child_exit_status.is_some() {
+            return;
+        }
+        self.shell_layer.write_response(response.as_bytes());
+    }
+
+    // The cursor's row and column for a CPR, counting from 1. While we're stomping, the cursor
+    // is still on the last column. In origin mode, the row is within the scrolling region.
+    fn reported_cursor_position(&self) -> (isize, isize) {
+        let col = self.cursor_pos.x.min(self.size.cols as isize - 1);
+        let mut row = self.cursor_pos.y;
+        if self.origin_mode {
+            row -= self.scroll_region_top as isize;
+        }
+        (row + 1, col + 1)
+    }
+
     fn apply_sequence_repeat_previous_character(&mut self, count: isize) {
         for _ in 0..count {
             self.standard_insert_char(self.previously_inserted_character);
         }
     }
 
+    // Deletes at and right of the cursor, and the cursor stays put. Blanks come in at the right
+    // hand edge of the screen.
     fn apply_sequence_delete_characters(&mut self, count: isize) {
-        if count < 0 {
-            println!("Asked to DeleteCharacters with N < 0. Ignoring");
-            return;
+        let x = self.cursor_pos.x as usize;
+        let cols = self.size.cols;
+        // Can't delete more than there is between the cursor and the edge of the screen
+        let count = (count.max(1) as usize).min(cols.saturating_sub(x));
+        let has_background = self.has_background_colour();
+        let blank = self.blank_char();
+        self.stomp = false;
+
+        let line = self.get_current_line_ref();
+        // The line might not reach the cursor, in which case there's nothing to delete
+        if x < line.len() {
+            let end = min(x + count, line.len());
+            drop(line.drain(x..end));
+        }
+        if has_background {
+            // The blanks have to be at the edge of the screen, not just the end of the line
+            while line.len() < cols - count {
+                line.push_back(DecoratedChar::new(' ', TextStyle::new()));
+            }
+            for _ in 0..count {
+                line.insert(cols - count, blank);
+            }
         }
+    }
 
-        let old_x = self.cursor_pos.x;
-        let line_buffer = self.get_current_line_ref();
-        for i in 0..count {
-            line_buffer.remove((old_x - i) as usize);
+    // Pushes the line right from the cursor to make room for N blanks. Whatever goes past the
+    // right hand edge of the screen is gone. The cursor stays put.
+    fn apply_sequence_insert_characters(&mut self, count: isize) {
+        let x = self.cursor_pos.x as usize;
+        let cols = self.size.cols;
+        let count = (count.max(1) as usize).min(cols.saturating_sub(x));
+        let has_background = self.has_background_colour();
+        let blank = self.blank_char();
+        self.stomp = false;
+
+        let line = self.get_current_line_ref();
+        if x >= line.len() {
+            // Pushing nothing right does nothing, unless the blanks are a colour
+            if !has_background {
+                return;
+            }
+            while line.len() < x {
+                line.push_back(DecoratedChar::new(' ', TextStyle::new()));
+            }
+        }
+        for _ in 0..count {
+            line.insert(x, blank);
+        }
+        line.truncate(cols);
+    }
+
+    // Replaces N characters from the cursor with blanks. Nothing moves, not even the cursor.
+    fn apply_sequence_erase_characters(&mut self, count: isize) {
+        let x = self.cursor_pos.x as usize;
+        let cols = self.size.cols;
+        let count = (count.max(1) as usize).min(cols.saturating_sub(x));
+        let has_background = self.has_background_colour();
+        let blank = self.blank_char();
+        let end = x + count;
+
+        let line = self.get_current_line_ref();
+        if !has_background && end >= line.len() {
+            // Everything from the cursor on would be blank, which is the same as not being there
+            line.truncate(x);
+            return;
+        }
+        while line.len() < end {
+            line.push_back(DecoratedChar::new(' ', TextStyle::new()));
+        }
+        for c in line.range_mut(x..end) {
+            *c = blank;
         }
-        self.cursor_pos.x -= count;
     }
 
     fn apply_sequence_set_mode(&mut self, mode: &SetOrResetModeType) {
@@ -182,6 +367,10 @@ impl TtyState {
     }
 
     fn apply_sequence_full_reset(&mut self) {
+        // The scrollback is about to be thrown away, but it should still be in the log
+        self.log_screen();
+        self.logged_lines = 0;
+
         // Here we don't reset things like unicode parser state.
         // Those are too low-level, we're only emulating a VT-100 reset.
         self.cursor_pos = CursorPosition { x: 0, y: 0 };
@@ -190,35 +379,191 @@ impl TtyState {
         self.bracketed_paste_mode = false;
         self.text_style = TextStyle::new();
         self.stomp = false;
-        self.autowrap = false;
+        self.autowrap = true;
+        self.alternate_screen = false;
+        self.cursor_visible = true;
+        self.application_cursor_keys = false;
+        self.insertion_mode = InsertionMode::Replace;
+        self.character_set = CharacterSet::UnitedStatesASCII;
+        self.scroll_region_top = 0;
+        self.scroll_region_bottom = self.size.rows - 1;
+        self.origin_mode = false;
+        self.local_echo.reset();
     }
 
-    fn apply_sequence_move_cursor_up_scrolling_if_necessary(&mut self) {
-        if self.cursor_pos.y == 0 {
-            if self.scrollback_start == 0 {
-                return;
-            }
+    // AKA Reverse Index. At the top of the scrolling region, the region scrolls down instead.
+    fn reverse_index(&mut self) {
+        self.stomp = false;
+        if self.cursor_pos.y as usize == self.scroll_region_top {
+            self.scroll_region_down(1);
+        } else if self.cursor_pos.y > 0 {
+            self.cursor_pos.y -= 1;
+        }
+    }
 
-            self.scrollback_start -= 1;
-            self.scrollback_buffer.pop_back();
+    // For newlines, wrapping and Index. At the bottom of the scrolling region, the region
+    // scrolls up instead. Below it, the cursor just stops at the bottom of the screen.
+    fn line_feed(&mut self) {
+        self.stomp = false;
+        if self.cursor_pos.y as usize == self.scroll_region_bottom {
+            self.scroll_region_up(1);
+        } else if (self.cursor_pos.y as usize) < self.size.rows - 1 {
+            self.cursor_pos.y += 1;
+        }
+    }
+
+    // Moves the lines in the scrolling region up by n, with blank lines coming in at the
+    // bottom. Lines only go into the scrollback when the region starts at the top of the
+    // screen. Otherwise, they're gone.
+    fn scroll_region_up(&mut self, n: usize) {
+        let top = self.scroll_region_top;
+        let bottom = self.scroll_region_bottom;
+        let n = n.min(bottom - top + 1);
+
+        if top == 0 {
+            self.ensure_backing_store_for_line(bottom);
+            // The lines below the region stay put, so the new lines go in above them. Then
+            // moving the screen down the buffer moves the top lines into the scrollback.
+            for _ in 0..n {
+                let below_region = self.scrollback_start + bottom + 1;
+                self.scrollback_buffer
+                    .insert(below_region, self.blank_line());
+                self.scrollback_start += 1;
+            }
+            self.log_scrolled_off_lines();
+            if bottom != self.size.rows - 1 {
+                // Predictions remember which line they're on, and the lines below have moved
+                self.local_echo.reset();
+            }
         } else {
-            self.set_cursor_pos(self.cursor_pos.x, self.cursor_pos.y - 1);
+            self.delete_lines_in_region(top, n);
+        }
+    }
+
+    // Moves the lines in the scrolling region down by n, with blank lines coming in at the top.
+    // Lines pushed off the bottom are gone.
+    fn scroll_region_down(&mut self, n: usize) {
+        self.insert_lines_in_region(self.scroll_region_top, n);
+    }
+
+    fn apply_sequence_insert_lines(&mut self, count: isize) {
+        let y = self.cursor_pos.y as usize;
+        // It only works inside the scrolling region
+        if y < self.scroll_region_top || y > self.scroll_region_bottom {
+            return;
         }
+        self.insert_lines_in_region(y, count.max(1) as usize);
+        self.set_cursor_pos(0, self.cursor_pos.y);
     }
 
-    fn apply_sequence_move_cursor_down_scrolling_if_necessary(&mut self) {
-        if self.cursor_pos.y == self.size.rows as isize - 1 {
+    fn apply_sequence_delete_lines(&mut self, count: isize) {
+        let y = self.cursor_pos.y as usize;
+        if y < self.scroll_region_top || y > self.scroll_region_bottom {
+            return;
+        }
+        self.delete_lines_in_region(y, count.max(1) as usize);
+        self.set_cursor_pos(0, self.cursor_pos.y);
+    }
+
+    // Puts n blank lines at row y, pushing the lines below it (down to the bottom of the
+    // scrolling region) down. The ones pushed past the bottom are gone.
+    fn insert_lines_in_region(&mut self, y: usize, n: usize) {
+        let bottom = self.scroll_region_bottom;
+        let n = n.min(bottom - y + 1);
+        self.ensure_backing_store_for_line(bottom);
+
+        for _ in 0..n {
             self.scrollback_buffer
-                .push_back(VecDeque::with_capacity(self.size.cols));
-            self.scrollback_start += 1;
-        } else {
-            self.set_cursor_pos(self.cursor_pos.x, self.cursor_pos.y + 1);
+                .remove(self.scrollback_start + bottom);
+            self.scrollback_buffer
+                .insert(self.scrollback_start + y, self.blank_line());
+        }
+        // Predictions remember which line they're on, and the lines have moved
+        self.local_echo.reset();
+    }
+
+    // Removes n lines from row y, pulling the lines below it (down to the bottom of the
+    // scrolling region) up. Blank lines come in at the bottom, and the removed ones are gone.
+    fn delete_lines_in_region(&mut self, y: usize, n: usize) {
+        let bottom = self.scroll_region_bottom;
+        let n = n.min(bottom - y + 1);
+        self.ensure_backing_store_for_line(bottom);
+
+        for _ in 0..n {
+            self.scrollback_buffer.remove(self.scrollback_start + y);
+            self.scrollback_buffer
+                .insert(self.scrollback_start + bottom, self.blank_line());
+        }
+        self.local_echo.reset();
+    }
+
+    // A new line for scrolling or inserting. Like in xterm, it's filled with the current
+    // background colour (if there is one).
+    fn blank_line(&self) -> LineType {
+        let mut line = VecDeque::with_capacity(self.size.cols);
+        if self.has_background_colour() {
+            line.extend(repeat_n(self.blank_char(), self.size.cols));
+        }
+        line
+    }
+
+    fn has_background_colour(&self) -> bool {
+        !matches!(self.text_style.background, TerminalColour::Default)
+    }
+
+    // A space in the current background colour, with none of the rest of the text style
+    fn blank_char(&self) -> DecoratedChar {
+        let mut style = TextStyle::new();
+        style.background = self.text_style.background;
+        DecoratedChar::new(' ', style)
+    }
+
+    fn apply_sequence_set_scrolling_region(&mut self, args: &SetScrollingRegionArgs) {
+        let top = args.top.max(1) - 1;
+        let bottom = match args.bottom {
+            0 => self.size.rows,
+            bottom => bottom.min(self.size.rows),
+        } - 1;
+        // A region has to be at least two lines tall
+        if top >= bottom {
+            println!(
+                "Ignoring scrolling region from {} to {}",
+                args.top, args.bottom
+            );
+            return;
         }
+        self.scroll_region_top = top;
+        self.scroll_region_bottom = bottom;
+        self.set_cursor_pos_from_origin(0, 0);
     }
 
     fn apply_sequence_set_cursor_position(&mut self, args: &SetCursorPositionArgs) {
         // These args are 1-indexed, but our cursor is 0-indexed.
-        self.set_cursor_pos(args.x as isize - 1, args.y as isize - 1)
+        self.set_cursor_pos_from_origin(args.x as isize - 1, args.y as isize - 1)
+    }
+
+    // For absolute positions from the program. In origin mode, rows count from the top of the
+    // scrolling region and can't go past the bottom of it.
+    fn set_cursor_pos_from_origin(&mut self, x: isize, y: isize) {
+        if self.origin_mode {
+            let top = self.scroll_region_top as isize;
+            let bottom = self.scroll_region_bottom as isize;
+            self.set_cursor_pos(x, (y + top).clamp(top, bottom))
+        } else {
+            self.set_cursor_pos(x, y)
+        }
+    }
+
+    // For relative movements, which stop at the scrolling region's margins (but only if the
+    // cursor started off inside them)
+    fn set_cursor_pos_within_margins(&mut self, x: isize, y: isize) {
+        let top = self.scroll_region_top as isize;
+        let bottom = self.scroll_region_bottom as isize;
+        if (top..=bottom).contains(&self.cursor_pos.y) {
+            self.set_cursor_pos(x, y.clamp(top, bottom))
+        } else {
+            self.set_cursor_pos(x, y)
+        }
     }
 
     fn set_cursor_pos(&mut self, x: isize, y: isize) {
@@ -258,8 +603,13 @@ impl TtyState {
         // TODO: iTerm has some kind of permissions system with an option to diasallow
         //   programs from doing this. We should offer the same.
         if *erase_type == EraseInDisplayType::EntireScreenAndScrollbackBuffer {
+            // Like a full reset, the log should still get everything we're throwing away
+            self.log_screen();
+            self.logged_lines = 0;
             self.scrollback_buffer.clear();
             self.scrollback_start = 0;
+            // Predictions point at lines that aren't there any more
+            self.local_echo.reset();
         }
     }
 
@@ -290,8 +640,13 @@ impl TtyState {
     }
 
     fn ensure_backing_store_for_current_line(&mut self) {
-        let cursor_line = self.scrollback_start + self.cursor_pos.y as usize;
-        while self.scrollback_buffer.len() <= cursor_line {
+        self.ensure_backing_store_for_line(self.cursor_pos.y as usize);
+    }
+
+    // y is a row on the screen
+    fn ensure_backing_store_for_line(&mut self, y: usize) {
+        let line = self.scrollback_start + y;
+        while self.scrollback_buffer.len() <= line {
             self.scrollback_buffer
                 .push_back(VecDeque::with_capacity(self.size.cols));
         }
@@ -399,21 +754,10 @@ impl TtyState {
 
         let cursor_needs_to_wrap = self.cursor_pos.x as usize >= self.size.cols - 1 && self.stomp;
         if c == NEWLINE || (cursor_needs_to_wrap && self.autowrap) {
-            self.stomp = false;
             self.cursor_pos.x = 0;
-            self.cursor_pos.y += 1;
-
-            // If we're pushed too low, scroll
-            if self.cursor_pos.y as usize >= self.size.rows {
-                self.cursor_pos.y -= 1;
-                self.scrollback_start += 1;
-            }
-
-            while self.scrollback_start + self.cursor_pos.y as usize >= self.scrollback_buffer.len()
-            {
-                self.scrollback_buffer
-                    .push_back(VecDeque::with_capacity(self.size.cols));
-            }
+            // If we're pushed too low, this scrolls
+            self.line_feed();
+            self.ensure_backing_store_for_current_line();
 
             line_buffer =
                 &mut self.scrollback_buffer[self.scrollback_start + self.cursor_pos.y as usize];
@@ -469,13 +813,28 @@ impl TtyState {
         }
         let to_remove =
             (buf_size.saturating_sub(MAX_SCROLLBACK_BUFFER_CHARACTERS) / self.size.cols) + 1;
+        // Predictions remember which line they're on, and the lines are about to move
+        self.local_echo.reset();
         for _ in 0..to_remove {
             self.scrollback_buffer.pop_front();
             self.scrollback_start -= 1;
+            self.logged_lines = self.logged_lines.saturating_sub(1);
         }
     }
 
-    pub fn resized(&mut self, new_rows: usize, new_cols: usize) {
+    // A window smaller than one cell (or a recording or client that asks for 0x0) still gets a
+    // one cell terminal, because so much of what we do needs somewhere to put the cursor
+    pub fn resized(&mut self, new_rows: usize, new_cols: usize, cell_pixel_size: CellPixelSize) {
+        let new_rows = new_rows.max(1);
+        let new_cols = new_cols.max(1);
+        self.set_size(new_rows, new_cols);
+        self.shell_layer
+            .resized(new_rows, new_cols, cell_pixel_size);
+    }
+
+    fn set_size(&mut self, new_rows: usize, new_cols: usize) {
+        let new_rows = new_rows.max(1);
+        let new_cols = new_cols.max(1);
         // TODO: Resize scrollback buffer if we got smaller?
         self.size = TtySize {
             rows: new_rows,
@@ -483,31 +842,585 @@ impl TtyState {
         };
         self.scrollback_start = self.scrollback_buffer.len().saturating_sub(new_rows);
         self.cursor_pos.y = self.cursor_pos.y.min(new_rows as isize - 1);
-        self.shell_layer.resized(new_rows, new_cols);
+        // Like xterm, we forget the scrolling region. Full-screen programs set it again when
+        // they redraw for the new size.
+        self.scroll_region_top = 0;
+        self.scroll_region_bottom = new_rows - 1;
+        self.local_echo.reset();
+        self.record(|recorder| recorder.resized(new_cols, new_rows));
+        self.log_scrolled_off_lines();
     }
 
     pub fn read(&mut self) {
-        self.shell_layer
-            .read(&mut self.read_buffer, &mut self.read_buffer_length);
+        let started_at = Instant::now();
+        loop {
+            if self.read_once() == 0 {
+                break;
+            }
 
-        if self.read_buffer_length == 0 {
-            return;
+            // Some layers (eg. ThreadedShellLayer) can give us more straight away. We take it all
+            // so that we're not limited to one buffer per frame, but we still give up after
+            // roughly a frame's worth of time so that a program spewing output can't stop us from
+            // drawing.
+            let out_of_time = started_at.elapsed() >= READ_TIME_BUDGET;
+            if out_of_time || !self.shell_layer.has_data_ready() {
+                break;
+            }
         }
 
-        for i in 0..self.read_buffer_length {
-            self.insert_byte(self.read_buffer[i])
+        self.check_predictions();
+        self.clean_up_buffer_memory_usage();
+    }
+
+    // For frontends that run their own event loop around a non-blocking shell layer (see
+    // LinuxShellLayer::set_nonblocking). Consumes everything that's ready without waiting for
+    // more, and returns how many bytes that was.
+    pub fn process_available(&mut self) -> usize {
+        let mut total_read = 0;
+        loop {
+            let read_count = self.read_once();
+            if read_count == 0 {
+                break;
+            }
+            total_read += read_count;
         }
 
+        self.check_predictions();
         self.clean_up_buffer_memory_usage();
+        total_read
+    }
+
+    // Reads one buffer's worth from the shell layer and applies it. Returns how many bytes that
+    // was, which is zero if there was nothing there.
+    fn read_once(&mut self) -> usize {
+        let read_result = self.shell_layer.read(&mut self.read_buffer);
+
+        while let Some(event) = self.shell_layer.next_event() {
+            self.handle_shell_layer_event(event);
+        }
+
+        let read_count = match read_result {
+            Ok(read_count) => read_count,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => 0,
+            Err(err) if err.kind() == ErrorKind::Interrupted => 0,
+            Err(err) => {
+                println!("Failed to read from the shell: {}", err);
+                0
+            }
+        };
+
+        if read_count > 0 && self.is_recording() {
+            let output = self.read_buffer[..read_count].to_vec();
+            self.record(|recorder| recorder.output(&output));
+        }
+        if read_count > 0 && self.is_logging() {
+            let output = self.read_buffer[..read_count].to_vec();
+            self.log(|logger| logger.output(&output));
+        }
+
+        for i in 0..read_count {
+            self.insert_byte(self.read_buffer[i])
+        }
+
+        read_count
+    }
+
+    // How much input (eg. from a big paste) is still waiting to be sent to the shell, in bytes
+    pub fn pending_input_bytes(&self) -> usize {
+        self.shell_layer.pending_write_bytes()
+    }
+
+    // The window's title, with what we're up to on the end, eg.
+    //   Velocity (recording) (broadcasting to servers: 3 more)
+    pub fn window_title(&self, base_title: &str) -> String {
+        let mut title = base_title.to_string();
+        if self.is_recording() {
+            title.push_str(" (recording)");
+        }
+        if let Some(membership) = &self.broadcast {
+            if membership.is_excluded() {
+                title.push_str(&format!(" (excluded from {})", membership.group()));
+            } else {
+                title.push_str(&format!(
+                    " (broadcasting to {}: {} more)",
+                    membership.group(),
+                    membership.receiving_member_count()
+                ));
+            }
+        }
+        // Let the user know that a big paste is still being sent to the shell
+        let pending = self.pending_input_bytes();
+        if pending > 0 {
+            title.push_str(&format!(" (pasting, {} KB left)", pending.div_ceil(1024)));
+        }
+        title
+    }
+
+    // Sends a signal to whatever is running in the foreground, eg. Signal::SIGINT to interrupt it
+    // like ^C does (but without going through the shell's line discipline)
+    pub fn signal_foreground_process_group(&mut self, signal: Signal) -> nix::Result<()> {
+        self.shell_layer.signal_foreground_process_group(signal)
+    }
+
+    // The process ID of the shell (or whatever program we launched), if the shell layer knows it
+    pub fn shell_pid(&self) -> Option<i32> {
+        self.shell_layer.shell_pid()
+    }
+
+    // The name of the program running in the foreground, eg. "vim"
+    pub fn foreground_process_name(&self) -> Option<String> {
+        self.shell_layer.foreground_process_name()
+    }
+
+    // The full command line of the program running in the foreground
+    pub fn foreground_process_argv(&self) -> Option<Vec<String>> {
+        self.shell_layer.foreground_process_argv()
+    }
+
+    // The working directory of the program running in the foreground. Frontends can use this
+    // to open a new window in the same place.
+    pub fn foreground_process_cwd(&self) -> Option<PathBuf> {
+        self.shell_layer.foreground_process_cwd()
+    }
+
+    // Sleeps until the shell layer has something for us, or the timeout passes. Frontends using a
+    // layer that doesn't block in read() (eg. ThreadedShellLayer) call this once per frame so that
+    // they don't spin when nothing is happening, but still wake up as soon as output arrives.
+    pub fn wait_for_output(&mut self, timeout: Duration) -> bool {
+        self.shell_layer.wait_for_data(timeout)
+    }
+
+    fn handle_shell_layer_event(&mut self, event: ShellLayerEvent) {
+        match event {
+            ShellLayerEvent::ChildExited { status } => {
+                self.child_exit_status = Some(status);
+                // Nothing else is going to happen, so the log may as well be finished now
+                self.stop_logging();
+            }
+            ShellLayerEvent::SizeChanged { rows, cols } => self.set_size(rows, cols),
+            ShellLayerEvent::Disconnected => self.print_local_text("\r\n[disconnected]\r\n"),
+            ShellLayerEvent::Reconnected => self.print_local_text("[reconnected]\r\n"),
+        }
     }
 
     pub fn write(&mut self, data: &[u8]) {
+        if self.child_exit_status.is_some() {
+            return;
+        }
+        self.shell_layer.write(data);
+        self.record(|recorder| recorder.input(data));
+        if let Some(broadcast) = &self.broadcast {
+            broadcast.send(data);
+        }
+
+        if self.local_echo_is_active() {
+            let cursor_line = self.scrollback_start + self.cursor_pos.y as usize;
+            let cursor_x = self.cursor_pos.x as usize;
+            self.local_echo
+                .typed(data, cursor_line, cursor_x, self.size.cols);
+        } else {
+            self.local_echo.reset();
+        }
+    }
+
+    // From now on, what's typed into us is typed into the rest of the group too, and theirs into
+    // us. Replaces any group we were already in. It needs our control socket, because that's how
+    // the rest of the group sends us their input.
+    pub fn join_broadcast_group(
+        &mut self,
+        group: &str,
+        control_server: Option<&ControlServer>,
+    ) -> ControlReply {
+        let Some(control_server) = control_server else {
+            return ControlReply::Error("Broadcast groups don't work with --no-listen".to_string());
+        };
+        match BroadcastMembership::join(group, control_server.path()) {
+            Ok(membership) => {
+                println!("Joined the broadcast group '{}'", group);
+                self.broadcast = Some(membership);
+                ControlReply::Done
+            }
+            Err(err) => ControlReply::Error(format!(
+                "Couldn't join the broadcast group '{}': {}",
+                group, err
+            )),
+        }
+    }
+
+    pub fn leave_broadcast_group(&mut self) {
+        self.broadcast = None;
+    }
+
+    pub fn broadcast_membership(&self) -> Option<&BroadcastMembership> {
+        self.broadcast.as_ref()
+    }
+
+    // Returns false if we aren't in a group
+    pub fn set_broadcast_excluded(&mut self, excluded: bool) -> bool {
+        match &mut self.broadcast {
+            Some(broadcast) => {
+                broadcast.set_excluded(excluded);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Bound to Ctrl+Shift+B in the frontends. Joins group (or the default one) if we aren't in a
+    // group, and leaves the one we're in if we are.
+    pub fn toggle_broadcast_group(
+        &mut self,
+        group: Option<&str>,
+        control_server: Option<&ControlServer>,
+    ) {
+        if let Some(membership) = &self.broadcast {
+            println!("Left the broadcast group '{}'", membership.group());
+            self.leave_broadcast_group();
+            return;
+        }
+        let group = group.unwrap_or(DEFAULT_BROADCAST_GROUP);
+        if let ControlReply::Error(message) = self.join_broadcast_group(group, control_server) {
+            println!("{}", message);
+        }
+    }
+
+    // Bound to Ctrl+Shift+X in the frontends
+    pub fn toggle_broadcast_excluded(&mut self) {
+        let excluded = self
+            .broadcast
+            .as_ref()
+            .is_some_and(|membership| membership.is_excluded());
+        if !self.set_broadcast_excluded(!excluded) {
+            println!("This window isn't in a broadcast group");
+        }
+    }
+
+    // Input typed into another member of our group. Unlike write(), this isn't sent on to the
+    // rest of the group, because they've had it already.
+    pub fn receive_broadcast_input(&mut self, data: &[u8]) {
+        let receiving = self
+            .broadcast
+            .as_ref()
+            .is_some_and(|broadcast| !broadcast.is_excluded());
+        if !receiving || self.child_exit_status.is_some() {
+            return;
+        }
         self.shell_layer.write(data);
+        self.record(|recorder| recorder.input(data));
+    }
+
+    // Off, Auto (the default) or Always. See local_echo.rs.
+    pub fn set_local_echo(&mut self, mode: LocalEchoMode) {
+        self.local_echo.set_mode(mode);
+    }
+
+    pub fn local_echo_mode(&self) -> LocalEchoMode {
+        self.local_echo.mode()
+    }
+
+    // Guessing what a full-screen program will do with a keystroke is hopeless, and a program
+    // that has turned echo off (eg. at a password prompt) won't echo anything to confirm.
+    fn local_echo_is_active(&self) -> bool {
+        self.local_echo.mode() != LocalEchoMode::Off
+            && !self.alternate_screen
+            && !self.shell_layer.is_echo_disabled()
+    }
+
+    fn check_predictions(&mut self) {
+        if self.alternate_screen {
+            self.local_echo.reset();
+            return;
+        }
+        let cursor_line = self.scrollback_start + self.cursor_pos.y as usize;
+        self.local_echo.check(
+            &self.scrollback_buffer,
+            cursor_line,
+            self.cursor_pos.x as usize,
+        );
+    }
+
+    // Row y of the screen as it should be drawn, with any predicted keystrokes (see
+    // local_echo.rs) underlined in it. None if nothing has been printed on that row yet.
+    pub fn screen_line(&self, y: usize) -> Option<Cow<'_, LineType>> {
+        let line_index = self.scrollback_start + y;
+        let line = self.scrollback_buffer.get(line_index);
+        let mut predictions = self.local_echo.visible_predictions(line_index).peekable();
+        if predictions.peek().is_none() {
+            return line.map(Cow::Borrowed);
+        }
+
+        let mut line = line.cloned().unwrap_or_default();
+        let mut style = self.text_style;
+        style.underlined = true;
+        for (x, c) in predictions {
+            while line.len() <= x {
+                line.push_back(DecoratedChar::new(' ', self.text_style));
+            }
+            line[x] = DecoratedChar::new(c, style);
+        }
+        Some(Cow::Owned(line))
+    }
+
+    // Where to draw the cursor, which is after any predicted keystrokes
+    pub fn displayed_cursor_pos(&self) -> CursorPosition {
+        self.local_echo
+            .predicted_cursor(self.scrollback_start)
+            .unwrap_or(CursorPosition {
+                x: self.cursor_pos.x,
+                y: self.cursor_pos.y,
+            })
+    }
+
+    // Starts writing everything that happens (output, input and resizes) to an asciicast file.
+    // Replaces any recording that's already going.
+    pub fn start_recording(&mut self, path: &Path) -> io::Result<()> {
+        self.recorder = Some(AsciicastRecorder::new(
+            path,
+            self.size.cols,
+            self.size.rows,
+        )?);
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) {
+        self.recorder = None;
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    // start_recording() for the frontends and velocity-ctl, which want to say how it went
+    pub fn start_recording_and_report(&mut self, path: &Path) -> ControlReply {
+        match self.start_recording(path) {
+            Ok(()) => {
+                println!("Recording the session to {}", path.display());
+                ControlReply::Done
+            }
+            Err(err) => {
+                ControlReply::Error(format!("Couldn't record to {}: {}", path.display(), err))
+            }
+        }
+    }
+
+    // Bound to Ctrl+Shift+R in the frontends
+    pub fn toggle_recording(&mut self) {
+        if self.is_recording() {
+            self.stop_recording();
+            println!("Stopped recording");
+        } else if let ControlReply::Error(message) =
+            self.start_recording_and_report(&default_recording_path())
+        {
+            println!("{}", message);
+        }
+    }
+
+    // A recording that can't be written to any more (eg. the disk is full) is stopped rather
+    // than interrupting the session
+    fn record(&mut self, event: impl FnOnce(&mut AsciicastRecorder) -> io::Result<()>) {
+        let Some(recorder) = &mut self.recorder else {
+            return;
+        };
+        if let Err(err) = event(recorder) {
+            println!("Failed to write to the recording, stopping it: {}", err);
+            self.recorder = None;
+        }
+    }
+
+    // Starts writing a transcript of the session to a file in config.directory, and returns the
+    // file's path. Replaces any log that's already going.
+    pub fn start_logging(&mut self, config: SessionLogConfig) -> io::Result<PathBuf> {
+        self.stop_logging();
+        let tty_path = self.shell_layer.tty_path();
+        let logger = SessionLogger::new(config, self.shell_pid(), tty_path.as_deref())?;
+        let path = logger.path().to_path_buf();
+        self.logger = Some(logger);
+        // Whatever has already scrolled off happened before we started
+        self.logged_lines = self.scrollback_start;
+        Ok(path)
+    }
+
+    // A plain text log only gets lines once they've scrolled off the screen, so this writes out
+    // what's still on it
+    pub fn stop_logging(&mut self) {
+        self.log_screen();
+        self.logger = None;
+    }
+
+    pub fn is_logging(&self) -> bool {
+        self.logger.is_some()
+    }
+
+    // Like record(), a log we can't write to is stopped rather than interrupting the session
+    fn log(&mut self, event: impl FnOnce(&mut SessionLogger) -> io::Result<()>) {
+        let Some(logger) = &mut self.logger else {
+            return;
+        };
+        if let Err(err) = event(logger) {
+            println!("Failed to write to the session log, stopping it: {}", err);
+            self.logger = None;
+        }
+    }
+
+    fn wants_plain_text_log(&self) -> bool {
+        self.logger
+            .as_ref()
+            .is_some_and(|logger| logger.mode() == SessionLogMode::PlainText)
+    }
+
+    // Writes the lines that have scrolled off the top of the screen since we last did
+    fn log_scrolled_off_lines(&mut self) {
+        if !self.wants_plain_text_log() {
+            return;
+        }
+        let end = self.scrollback_start.min(self.scrollback_buffer.len());
+        while self.logged_lines < end {
+            let text = self.line_text(self.logged_lines);
+            self.logged_lines += 1;
+            self.log(|logger| logger.line(&text));
+        }
+    }
+
+    // Writes everything that hasn't been logged yet, including what's on the screen. Blank lines
+    // at the bottom of the screen are left out, because they're just where nothing has been
+    // printed yet.
+    fn log_screen(&mut self) {
+        if !self.wants_plain_text_log() {
+            return;
+        }
+        self.log_scrolled_off_lines();
+        let mut end = self.scrollback_buffer.len();
+        while end > self.logged_lines && self.line_text(end - 1).is_empty() {
+            end -= 1;
+        }
+        while self.logged_lines < end {
+            let text = self.line_text(self.logged_lines);
+            self.logged_lines += 1;
+            self.log(|logger| logger.line(&text));
+        }
+    }
+
+    // What's on the screen as plain text, one line per row
+    pub fn screen_text(&self) -> String {
+        let end = min(
+            self.scrollback_start + self.size.rows,
+            self.scrollback_buffer.len(),
+        );
+        self.lines_text(self.scrollback_start..end)
+    }
+
+    // Everything we still have, including what has scrolled off the top
+    pub fn scrollback_text(&self) -> String {
+        self.lines_text(0..self.scrollback_buffer.len())
+    }
+
+    fn lines_text(&self, lines: Range<usize>) -> String {
+        lines
+            .map(|line| self.line_text(line))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    // A line's characters without their styles, or the trailing spaces that pad it out
+    fn line_text(&self, line: usize) -> String {
+        let text: String = self.scrollback_buffer[line]
+            .iter()
+            .map(|decorated_char| decorated_char.char)
+            .collect();
+        text.trim_end().to_string()
+    }
+
+    // Where the shell is, if it has told us (see shell_integration.rs)
+    pub fn shell_working_directory(&self) -> Option<&Path> {
+        self.shell_working_directory.as_deref()
+    }
+
+    // How the last command run at the shell's prompt exited, if the shell told us
+    pub fn last_command_exit_status(&self) -> Option<i32> {
+        self.last_command_exit_status
+    }
+
+    // The terminal device the shell is attached to, eg. /dev/pts/3
+    pub fn tty_path(&self) -> Option<PathBuf> {
+        self.shell_layer.tty_path()
+    }
+
+    // Draws text into the terminal as though the shell program had printed it. Frontends use
+    // this for messages of their own, like the exit status when the window is held open.
+    pub fn print_local_text(&mut self, text: &str) {
+        for b in text.bytes() {
+            self.insert_byte(b);
+        }
+    }
+
+    // The output that would draw the screen as it is right now onto a blank terminal of the same
+    // size, including the cursor and the modes programs have turned on. velocity-server sends
+    // this to windows when they attach, so that they don't start off empty.
+    pub fn repaint_sequence(&self) -> Vec<u8> {
+        let mut output = String::from("\x1b[0m\x1b[H\x1b[2J");
+
+        for y in 0..self.size.rows {
+            let Some(line) = self.scrollback_buffer.get(self.scrollback_start + y) else {
+                break;
+            };
+            if line.is_empty() {
+                continue;
+            }
+            output.push_str(&format!("\x1b[{};1H", y + 1));
+            // Only change the style when it's different from the last character's
+            let mut current_style = String::new();
+            for decorated_char in line.iter().take(self.size.cols) {
+                let style = decorated_char.style.to_escape_sequence();
+                if style != current_style {
+                    output.push_str(&style);
+                    current_style = style;
+                }
+                output.push(decorated_char.char);
+            }
+        }
+
+        output.push_str(&self.text_style.to_escape_sequence());
+        let flag = |enabled: bool| if enabled { 'h' } else { 'l' };
+        output.push_str(&format!("\x1b[?7{}", flag(self.autowrap)));
+        output.push_str(&format!("\x1b[?1{}", flag(self.application_cursor_keys)));
+        output.push_str(&format!("\x1b[?2004{}", flag(self.bracketed_paste_mode)));
+        output.push_str(&format!("\x1b[?25{}", flag(self.cursor_visible)));
+        // See apply_sequence_set_mode for why 4 means Insert
+        let insert = matches!(self.insertion_mode, InsertionMode::Insert);
+        output.push_str(&format!("\x1b[4{}", flag(insert)));
+        // Setting these moves the cursor, so they go before it's put back. In origin mode, the
+        // cursor's row counts from the top of the region.
+        output.push_str(&format!(
+            "\x1b[{};{}r",
+            self.scroll_region_top + 1,
+            self.scroll_region_bottom + 1
+        ));
+        output.push_str(&format!("\x1b[?6{}", flag(self.origin_mode)));
+        let (row, _) = self.reported_cursor_position();
+        output.push_str(&format!("\x1b[{};{}H", row, self.cursor_pos.x + 1));
+
+        output.into_bytes()
     }
 
-    pub fn new(cols: usize, rows: usize) -> Self {
+    pub fn new(
+        cols: usize,
+        rows: usize,
+        cell_pixel_size: CellPixelSize,
+        spawn_config: &SpawnConfig,
+    ) -> Self {
+        let cols = cols.max(1);
+        let rows = rows.max(1);
+        let shell_layer = get_shell_layer(rows, cols, cell_pixel_size, spawn_config);
+        Self::with_shell_layer(cols, rows, shell_layer)
+    }
+
+    // Lets you pick where the bytes come from, eg. a ScriptedShellLayer so you can test TtyState
+    // without a real shell.
+    pub fn with_shell_layer(cols: usize, rows: usize, shell_layer: Box<dyn ShellLayer>) -> Self {
+        // Like resized(), we can't be smaller than one cell
+        let cols = cols.max(1);
+        let rows = rows.max(1);
         let size = TtySize { cols, rows };
-        let shell_layer = get_shell_layer(size.rows, size.cols);
         TtyState {
             size,
             cursor_pos: CursorPosition { x: 0, y: 0 },
@@ -517,7 +1430,6 @@ impl TtyState {
             bracketed_paste_mode: false,
             character_set: CharacterSet::UnitedStatesASCII,
             read_buffer: [0; FD_BUFFER_SIZE_BYTES],
-            read_buffer_length: 0,
             shell_layer,
             current_unicode_scalar: vec![],
             remaining_unicode_scalar_bytes: 0,
@@ -529,6 +1441,364 @@ impl TtyState {
             stomp: false,
             autowrap: true,
             application_cursor_keys: false,
+            child_exit_status: None,
+            recorder: None,
+            logger: None,
+            logged_lines: 0,
+            local_echo: LocalEcho::new(),
+            alternate_screen: false,
+            scroll_region_top: 0,
+            scroll_region_bottom: rows - 1,
+            origin_mode: false,
+            broadcast: None,
+            shell_working_directory: None,
+            last_command_exit_status: None,
+        }
+    }
+}
+
+impl Drop for TtyState {
+    fn drop(&mut self) {
+        // So that a plain text log has the last screenful in it when the window is closed
+        self.stop_logging();
+    }
+}
+
+// Eg. file://my-laptop/home/adam/My%20Documents. None if it's on another machine.
+fn local_path_from_file_url(url: &str) -> Option<PathBuf> {
+    let rest = url.strip_prefix("file://")?;
+    let path_start = rest.find('/')?;
+    let (host, path) = rest.split_at(path_start);
+    if !host.is_empty() && host != "localhost" {
+        let our_host = gethostname().ok()?;
+        if our_host.to_str() != Some(host) {
+            return None;
+        }
+    }
+
+    let mut bytes = vec![];
+    let mut path = path.bytes();
+    while let Some(byte) = path.next() {
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+        let hex = [path.next()?, path.next()?];
+        bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+    }
+    Some(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+// DA2 reports our version as a single number, eg. 1.2.3 is 10203
+fn firmware_version() -> u32 {
+    let mut parts = env!("CARGO_PKG_VERSION")
+        .split('.')
+        .map(|part| part.parse::<u32>().unwrap_or(0));
+    let mut next = || parts.next().unwrap_or(0);
+    next() * 10000 + next() * 100 + next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shell_layer::scripted::{ScriptedShellLayer, ScriptedShellLayerRecording};
+    use std::sync::{Arc, Mutex};
+    use std::{env, fs};
+
+    // Runs the script through a 10x5 TtyState. The recording has everything the TtyState sent
+    // back to the "shell".
+    fn run(script: &str) -> (TtyState, Arc<Mutex<ScriptedShellLayerRecording>>) {
+        let shell_layer = ScriptedShellLayer::from_strs(&[script]);
+        let recording = shell_layer.recording();
+        let mut tty = TtyState::with_shell_layer(10, 5, Box::new(shell_layer));
+        tty.process_available();
+        (tty, recording)
+    }
+
+    fn buffer_text(tty: &TtyState) -> Vec<String> {
+        tty.scrollback_buffer
+            .iter()
+            .map(|line| {
+                line.iter()
+                    .map(|decorated_char| decorated_char.char)
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn responses_to(script: &str) -> String {
+        let (_, recording) = run(script);
+        let written = recording.lock().unwrap().written_bytes();
+        String::from_utf8(written).unwrap()
+    }
+
+    // Five numbered rows, which makes it easy to see what moved where
+    const NUMBERED_ROWS: &str = "1\r\n2\r\n3\r\n4\r\n5";
+
+    fn screen_after(script: &str) -> String {
+        run(script).0.screen_text()
+    }
+
+    #[test]
+    fn output_from_the_script_ends_up_in_the_buffer() {
+        let shell_layer = ScriptedShellLayer::from_strs(&["hello\r\n", "world"]);
+        let mut tty = TtyState::with_shell_layer(10, 5, Box::new(shell_layer));
+        tty.process_available();
+        assert_eq!(buffer_text(&tty), ["hello", "world"]);
+        assert_eq!((tty.cursor_pos.x, tty.cursor_pos.y), (5, 1));
+    }
+
+    #[test]
+    fn escape_sequences_can_be_split_across_reads() {
+        let shell_layer = ScriptedShellLayer::from_strs(&["\x1b[", "2;3", "HX"]);
+        let mut tty = TtyState::with_shell_layer(10, 5, Box::new(shell_layer));
+        tty.process_available();
+        assert_eq!(tty.screen_text(), "\n  X");
+    }
+
+    #[test]
+    fn chunks_bigger_than_the_read_buffer_take_more_than_one_read() {
+        let chunk = vec![b'a'; FD_BUFFER_SIZE_BYTES * 2 + 1];
+        let shell_layer = ScriptedShellLayer::new(vec![chunk]);
+        let mut tty = TtyState::with_shell_layer(80, 24, Box::new(shell_layer));
+        assert_eq!(tty.process_available(), FD_BUFFER_SIZE_BYTES * 2 + 1);
+    }
+
+    #[test]
+    fn writes_and_resizes_are_recorded() {
+        let (mut tty, recording) = run("");
+        let cell_pixel_size = CellPixelSize {
+            width: 8,
+            height: 16,
+        };
+        tty.write(b"ls");
+        tty.write(b"\r");
+        tty.resized(24, 80, cell_pixel_size);
+
+        let recording = recording.lock().unwrap();
+        assert_eq!(recording.writes, [b"ls".to_vec(), b"\r".to_vec()]);
+        assert_eq!(recording.written_bytes(), b"ls\r");
+        assert_eq!(recording.resizes, [(24, 80, cell_pixel_size)]);
+        assert_eq!((tty.size.rows, tty.size.cols), (24, 80));
+    }
+
+    #[test]
+    fn the_child_exits_once_the_script_has_run_out() {
+        let shell_layer =
+            ScriptedShellLayer::from_strs(&["bye"]).exit_when_finished(ChildExitStatus::Exited(3));
+        let recording = shell_layer.recording();
+        let mut tty = TtyState::with_shell_layer(10, 5, Box::new(shell_layer));
+        tty.process_available();
+        assert_eq!(tty.screen_text(), "bye");
+        assert_eq!(tty.child_exit_status, Some(ChildExitStatus::Exited(3)));
+
+        // Nothing is sent to a child that has gone
+        tty.write(b"ls\r");
+        assert!(recording.lock().unwrap().writes.is_empty());
+    }
+
+    #[test]
+    fn without_an_exit_status_the_child_just_goes_quiet() {
+        let (mut tty, _) = run("hi");
+        assert_eq!(tty.process_available(), 0);
+        assert_eq!(tty.child_exit_status, None);
+    }
+
+    #[test]
+    fn device_status_report() {
+        assert_eq!(responses_to("\x1b[5n"), "\x1b[0n");
+    }
+
+    #[test]
+    fn cursor_position_report() {
+        assert_eq!(responses_to("\x1b[3;4H\x1b[6n"), "\x1b[3;4R");
+        assert_eq!(responses_to("\x1b[3;4H\x1b[?6n"), "\x1b[?3;4;1R");
+        // While stomping, the cursor is still on the last column
+        assert_eq!(responses_to("abcdefghij\x1b[6n"), "\x1b[1;10R");
+    }
+
+    #[test]
+    fn responses_are_not_sent_once_the_child_has_exited() {
+        let shell_layer =
+            ScriptedShellLayer::from_strs(&[]).exit_when_finished(ChildExitStatus::Exited(0));
+        let recording = shell_layer.recording();
+        let mut tty = TtyState::with_shell_layer(10, 5, Box::new(shell_layer));
+        tty.process_available();
+        tty.apply_escape_sequence(&EscapeSequence::DeviceStatusReport);
+        assert!(recording.lock().unwrap().writes.is_empty());
+    }
+
+    #[test]
+    fn device_attributes() {
+        assert_eq!(responses_to("\x1b[c"), "\x1b[?1;2c");
+        assert!(responses_to("\x1b[>c").starts_with("\x1b[>0;"));
+        assert_eq!(responses_to("\x1b[=c"), "\x1bP!|00000000\x1b\\");
+    }
+
+    #[test]
+    fn terminal_version() {
+        let expected = format!("\x1bP>|Velocity {}\x1b\\", env!("CARGO_PKG_VERSION"));
+        assert_eq!(responses_to("\x1b[>q"), expected);
+    }
+
+    #[test]
+    fn asking_for_device_attributes_leaves_the_screen_alone() {
+        let (tty, _) = run("hello\x1b[c");
+        assert_eq!(tty.screen_text(), "hello");
+        assert_eq!(tty.cursor_pos.x, 5);
+    }
+
+    #[test]
+    fn esc_c_is_a_full_reset() {
+        let (tty, _) = run("hello\x1b[41m\x1bc");
+        assert_eq!(tty.screen_text(), "");
+        assert_eq!((tty.cursor_pos.x, tty.cursor_pos.y), (0, 0));
+        assert!(!tty.has_background_colour());
+    }
+
+    #[test]
+    fn line_feed_at_the_bottom_margin_only_scrolls_the_region() {
+        let (tty, _) = run(&format!("{}\x1b[2;4r\x1b[4;1H\n", NUMBERED_ROWS));
+        assert_eq!(tty.screen_text(), "1\n3\n4\n\n5");
+        // Nothing went into the scrollback, because the region doesn't start at the top
+        assert_eq!(tty.scrollback_text(), tty.screen_text());
+    }
+
+    #[test]
+    fn reverse_index_at_the_top_margin_only_scrolls_the_region() {
+        let screen = screen_after(&format!("{}\x1b[2;4r\x1b[2;1H\x1bM", NUMBERED_ROWS));
+        assert_eq!(screen, "1\n\n2\n3\n5");
+    }
+
+    #[test]
+    fn setting_the_scrolling_region_homes_the_cursor() {
+        let (tty, _) = run(&format!("{}\x1b[2;4r", NUMBERED_ROWS));
+        assert_eq!((tty.cursor_pos.x, tty.cursor_pos.y), (0, 0));
+    }
+
+    #[test]
+    fn origin_mode_positions_the_cursor_within_the_region() {
+        let screen = screen_after(&format!("{}\x1b[2;4r\x1b[?6h\x1b[1;1HX", NUMBERED_ROWS));
+        assert_eq!(screen, "1\nX\n3\n4\n5");
+
+        // And can't leave it
+        let (tty, _) = run(&format!("{}\x1b[2;4r\x1b[?6h\x1b[9;1H", NUMBERED_ROWS));
+        assert_eq!(tty.cursor_pos.y, 3);
+    }
+
+    #[test]
+    fn cursor_position_report_in_origin_mode_counts_from_the_top_of_the_region() {
+        let script = format!("{}\x1b[2;4r\x1b[?6h\x1b[2;3H\x1b[6n", NUMBERED_ROWS);
+        assert_eq!(responses_to(&script), "\x1b[2;3R");
+    }
+
+    #[test]
+    fn insert_lines_pushes_lines_out_of_the_bottom_of_the_region() {
+        let (tty, _) = run(&format!("{}\x1b[2;4r\x1b[3;5H\x1b[L", NUMBERED_ROWS));
+        assert_eq!(tty.screen_text(), "1\n2\n\n3\n5");
+        assert_eq!(tty.cursor_pos.x, 0);
+    }
+
+    #[test]
+    fn delete_lines_pulls_blank_lines_in_at_the_bottom_of_the_region() {
+        let screen = screen_after(&format!("{}\x1b[2;4r\x1b[2;1H\x1b[2M", NUMBERED_ROWS));
+        assert_eq!(screen, "1\n4\n\n\n5");
+    }
+
+    #[test]
+    fn insert_and_delete_lines_do_nothing_outside_the_region() {
+        for sequence in ["\x1b[L", "\x1b[M"] {
+            let screen = screen_after(&format!("{}\x1b[2;4r\x1b[5;1H{}", NUMBERED_ROWS, sequence));
+            assert_eq!(screen, "1\n2\n3\n4\n5");
         }
     }
+
+    #[test]
+    fn scroll_up_and_down_stay_within_the_region() {
+        let screen = screen_after(&format!("{}\x1b[2;4r\x1b[S", NUMBERED_ROWS));
+        assert_eq!(screen, "1\n3\n4\n\n5");
+        let screen = screen_after(&format!("{}\x1b[2;4r\x1b[T", NUMBERED_ROWS));
+        assert_eq!(screen, "1\n\n2\n3\n5");
+        // More than the region holds just clears it
+        let screen = screen_after(&format!("{}\x1b[2;4r\x1b[9S", NUMBERED_ROWS));
+        assert_eq!(screen, "1\n\n\n\n5");
+    }
+
+    #[test]
+    fn delete_characters_at_the_end_of_the_line() {
+        assert_eq!(screen_after("abcdefghij\x1b[1;9H\x1b[5P"), "abcdefgh");
+        assert_eq!(screen_after("abcdefghij\x1b[1;1H\x1b[20P"), "");
+        assert_eq!(screen_after("abcdefghij\x1b[1;1H\x1b[P"), "bcdefghij");
+    }
+
+    #[test]
+    fn delete_characters_fills_with_the_background_colour() {
+        let (tty, _) = run("abcdefghij\x1b[41m\x1b[1;9H\x1b[P");
+        assert_eq!(tty.screen_text(), "abcdefghj");
+        assert_eq!(tty.screen_line(0).unwrap().len(), 10);
+    }
+
+    #[test]
+    fn insert_characters_pushes_characters_off_the_end_of_the_line() {
+        assert_eq!(screen_after("abcdefghij\x1b[1;9H\x1b[5@"), "abcdefgh");
+        assert_eq!(screen_after("abc\x1b[1;1H\x1b[2@"), "  abc");
+        let (tty, _) = run("abcdefghij\x1b[1;1H\x1b[3@");
+        assert_eq!(tty.screen_text(), "   abcdefg");
+        assert_eq!(tty.screen_line(0).unwrap().len(), 10);
+    }
+
+    #[test]
+    fn erase_characters_stops_at_the_end_of_the_line() {
+        assert_eq!(screen_after("abcdefghij\x1b[1;9H\x1b[5X"), "abcdefgh");
+        let (tty, _) = run("abcdef\x1b[1;2H\x1b[2X");
+        assert_eq!(tty.screen_text(), "a  def");
+        // The cursor stays where it was
+        assert_eq!(tty.cursor_pos.x, 1);
+    }
+
+    #[test]
+    fn zero_sizes_are_treated_as_one_cell() {
+        let cell_pixel_size = CellPixelSize {
+            width: 8,
+            height: 16,
+        };
+        let (mut tty, recording) = run("hello\r\nworld");
+        tty.resized(0, 0, cell_pixel_size);
+        assert_eq!((tty.size.rows, tty.size.cols), (1, 1));
+        assert_eq!(recording.lock().unwrap().resizes, [(1, 1, cell_pixel_size)]);
+        tty.apply_escape_sequence(&EscapeSequence::FullReset);
+        assert_eq!(tty.size.rows, 1);
+
+        let shell_layer = ScriptedShellLayer::from_strs(&["hello\r\n\x1b[6n"]);
+        let recording = shell_layer.recording();
+        let mut tty = TtyState::with_shell_layer(0, 0, Box::new(shell_layer));
+        tty.process_available();
+        assert_eq!(tty.screen_text(), "");
+        assert_eq!(recording.lock().unwrap().written_bytes(), b"\x1b[1;1R");
+    }
+
+    #[test]
+    fn clearing_the_scrollback_doesnt_lose_lines_from_the_log() {
+        let directory = env::temp_dir().join(format!("velocity-test-log-{}", std::process::id()));
+        let numbers = |range: std::ops::RangeInclusive<usize>| {
+            range.map(|n| n.to_string()).collect::<Vec<String>>()
+        };
+        let script = format!(
+            "{}\x1b[3J\x1b[H{}",
+            numbers(1..=7).join("\r\n"),
+            numbers(8..=14).join("\r\n")
+        );
+
+        let shell_layer = ScriptedShellLayer::from_strs(&[&script]);
+        let mut tty = TtyState::with_shell_layer(10, 5, Box::new(shell_layer));
+        let path = tty
+            .start_logging(SessionLogConfig::new(directory.clone()))
+            .unwrap();
+        tty.process_available();
+        tty.stop_logging();
+
+        let log = fs::read_to_string(&path).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(log.lines().collect::<Vec<&str>>(), numbers(1..=14));
+    }
 }
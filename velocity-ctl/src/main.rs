@@ -0,0 +1,164 @@
+// velocity-ctl drives a Velocity window from a script, eg.
+//   velocity-ctl send-text 'make\r'
+//   velocity-ctl get-text > screen.txt
+// Run inside a window, it finds that window with $VELOCITY_LISTEN_SOCKET. See control.rs in
+// velocity-core for the protocol.
+use std::{env, path::PathBuf, process};
+
+use velocity_core::control::{
+    send_control_command, ControlCommand, ControlReply, LISTEN_SOCKET_ENV_VAR,
+};
+
+const USAGE: &str = "Usage: velocity-ctl [OPTIONS] COMMAND [ARGS...]
+
+Commands:
+  send-text TEXT            Type TEXT into the window. \\r, \\n, \\t, \\e, \\\\ and \\xNN are
+                            understood, so 'ls\\r' runs ls.
+  get-text [--scrollback]   Print what's on the screen, or everything including the scrollback
+  get-cursor                Print the cursor's column and row, counting from 0
+  resize COLS ROWS          Resize the window to fit COLS by ROWS characters
+  set-title TITLE           Change the window's title
+  start-recording [PATH]    Start recording the session to an asciicast file
+  stop-recording            Stop recording
+  broadcast join GROUP      Join a broadcast group, so that what's typed into the window goes to
+                            every window in GROUP, and the other way round
+  broadcast leave           Leave the window's broadcast group
+  broadcast exclude         Stop sending and receiving broadcast input for now
+  broadcast include         Start sending and receiving broadcast input again
+
+Options:
+  --socket PATH             Control the window listening on PATH instead of
+                            $VELOCITY_LISTEN_SOCKET
+  --json                    Print the window's reply as JSON
+  -h, --help                Show this message";
+
+fn main() {
+    let mut socket_path = env::var_os(LISTEN_SOCKET_ENV_VAR).map(PathBuf::from);
+    let mut json = false;
+
+    let mut args = env::args().skip(1).peekable();
+    while let Some(arg) = args.next_if(|arg| arg.starts_with('-')) {
+        match arg.as_str() {
+            "--socket" => match args.next() {
+                Some(path) => socket_path = Some(path.into()),
+                None => usage_error("--socket needs a value"),
+            },
+            "--json" => json = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => usage_error(&format!("Unknown option '{}'", arg)),
+        }
+    }
+
+    let args: Vec<String> = args.collect();
+    let command = match parse_command(&args) {
+        Ok(command) => command,
+        Err(message) => usage_error(&message),
+    };
+    let Some(socket_path) = socket_path else {
+        eprintln!(
+            "There's no ${} to tell us which window to control. Run this inside a Velocity \
+             window, or use --socket.",
+            LISTEN_SOCKET_ENV_VAR
+        );
+        process::exit(1);
+    };
+
+    let reply = match send_control_command(&socket_path, &command) {
+        Ok(reply) => reply,
+        Err(err) => {
+            eprintln!(
+                "Couldn't talk to the window at {}: {}",
+                socket_path.display(),
+                err
+            );
+            process::exit(1);
+        }
+    };
+
+    if json {
+        println!("{}", reply.to_json());
+    }
+    match reply {
+        ControlReply::Done => {}
+        ControlReply::Text(text) if !json => println!("{}", text),
+        ControlReply::Cursor { x, y, .. } if !json => println!("{} {}", x, y),
+        ControlReply::Text(_) | ControlReply::Cursor { .. } => {}
+        ControlReply::Error(message) => {
+            eprintln!("{}", message);
+            process::exit(1);
+        }
+    }
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    process::exit(2);
+}
+
+fn parse_command(args: &[String]) -> Result<ControlCommand, String> {
+    let Some((name, args)) = args.split_first() else {
+        return Err("Which command?".to_string());
+    };
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+    let size = |value: &str| match value.parse::<usize>() {
+        Ok(size) if size > 0 => Ok(size),
+        _ => Err(format!("'{}' isn't a size", value)),
+    };
+
+    Ok(match (name.as_str(), args.as_slice()) {
+        ("send-text", [text]) => ControlCommand::SendText(unescape(text)?),
+        ("get-text", []) => ControlCommand::GetText { scrollback: false },
+        ("get-text", ["--scrollback"]) => ControlCommand::GetText { scrollback: true },
+        ("get-cursor", []) => ControlCommand::GetCursor,
+        ("resize", [cols, rows]) => ControlCommand::Resize {
+            rows: size(rows)?,
+            cols: size(cols)?,
+        },
+        ("set-title", [title]) => ControlCommand::SetTitle(title.to_string()),
+        ("start-recording", []) => ControlCommand::StartRecording(None),
+        ("start-recording", [path]) => ControlCommand::StartRecording(Some(path.into())),
+        ("stop-recording", []) => ControlCommand::StopRecording,
+        ("broadcast", ["join", group]) => ControlCommand::JoinBroadcastGroup(group.to_string()),
+        ("broadcast", ["leave"]) => ControlCommand::LeaveBroadcastGroup,
+        ("broadcast", ["exclude"]) => ControlCommand::SetBroadcastExcluded(true),
+        ("broadcast", ["include"]) => ControlCommand::SetBroadcastExcluded(false),
+        (
+            "send-text" | "get-text" | "get-cursor" | "resize" | "set-title" | "start-recording"
+            | "stop-recording" | "broadcast",
+            _,
+        ) => return Err(format!("Wrong arguments for {}", name)),
+        _ => return Err(format!("Unknown command '{}'", name)),
+    })
+}
+
+// Shells make it awkward to type control characters, so we understand the usual escapes
+fn unescape(text: &str) -> Result<String, String> {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('e') => unescaped.push('\x1b'),
+            Some('\\') => unescaped.push('\\'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(byte) if byte < 0x80 => unescaped.push(byte as char),
+                    _ => return Err(format!("'\\x{}' should be \\x00 to \\x7f", hex)),
+                }
+            }
+            Some(other) => return Err(format!("Unknown escape '\\{}'", other)),
+            None => return Err("TEXT ends with a lone \\".to_string()),
+        }
+    }
+    Ok(unescaped)
+}
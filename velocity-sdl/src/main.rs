@@ -1,20 +1,25 @@
 use colours::{terminal_colour_to_sdl_colour, DefaultColourVersion};
 use phf::phf_map;
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, Mod};
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
 use sdl2::render::TextureQuery;
 use sdl2::ttf::FontStyle;
-use std::thread;
 use std::time::Duration;
+use std::{env, process, thread};
 
+use velocity_core::command_line::{CommandLineOptions, USAGE};
+use velocity_core::control::{ControlCommand, ControlReply};
+use velocity_core::shell_layer::threaded::ThreadedShellLayer;
+use velocity_core::shell_layer::{get_shell_layer, CellPixelSize, ShellLayer};
 use velocity_core::tty::TtyState;
 
 mod colours;
 
 const FONT_SIZE: u16 = 24;
 
+// The size of the window, unless we're told otherwise with --cols and --rows
 const COLUMNS: usize = 80;
 const ROWS: usize = 25;
 
@@ -33,19 +38,59 @@ macro_rules! rect(
 );
 
 pub fn main() {
+    let options = match CommandLineOptions::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+    if options.show_help {
+        println!("{}", USAGE);
+        return;
+    }
+    // We open a recording before the window, so that the window can be the size it was made at
+    let replay_shell_layer = match options.replay_shell_layer() {
+        Some(Ok(layer)) => Some(layer),
+        Some(Err(err)) => {
+            eprintln!("Couldn't open the recording: {}", err);
+            process::exit(1);
+        }
+        None => None,
+    };
+    let serial_shell_layer = match options.serial_shell_layer() {
+        Some(Ok(layer)) => Some(layer),
+        Some(Err(err)) => {
+            eprintln!("Couldn't open the serial device: {}", err);
+            process::exit(1);
+        }
+        None => None,
+    };
+    let recorded_size = replay_shell_layer.as_ref().and_then(|layer| layer.size());
+    let columns = options
+        .cols
+        .or(recorded_size.map(|(cols, _)| cols))
+        .unwrap_or(COLUMNS);
+    let rows = options
+        .rows
+        .or(recorded_size.map(|(_, rows)| rows))
+        .unwrap_or(ROWS);
+    let mut base_title = options.window_title();
+
     // TODO: Less font hardcoding. Eg, some Linux users might have their fonts in a different
     //   place. Can we ask the system where fonts are?
     #[cfg(target_os = "macos")]
-    let font_path = "/System/Library/Fonts/Monaco.ttf";
+    let default_font_path = "/System/Library/Fonts/Monaco.ttf";
     #[cfg(target_os = "linux")]
-    let font_path = "/usr/share/fonts/truetype/noto/NotoMono-Regular.ttf";
+    let default_font_path = "/usr/share/fonts/truetype/noto/NotoMono-Regular.ttf";
+    let font_path = options.font.clone().unwrap_or(default_font_path.into());
 
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let ttf_context = sdl2::ttf::init().unwrap();
 
     let window = video_subsystem
-        .window("Velocity", 800, 600)
+        .window(&base_title, 800, 600)
         .position_centered()
         .allow_highdpi()
         .opengl()
@@ -54,7 +99,13 @@ pub fn main() {
 
     let mut canvas = window.into_canvas().present_vsync().build().unwrap();
     let texture_creator = canvas.texture_creator();
-    let mut font = ttf_context.load_font(font_path, FONT_SIZE).unwrap();
+    let mut font = match ttf_context.load_font(&font_path, FONT_SIZE) {
+        Ok(font) => font,
+        Err(err) => {
+            eprintln!("Couldn't load a font from {}: {}", font_path.display(), err);
+            process::exit(1);
+        }
+    };
 
     let space_surface = font
         .render(" ")
@@ -69,8 +120,21 @@ pub fn main() {
         ..
     } = space_texture.query();
 
-    let window_width = space_width * COLUMNS as u32;
-    let window_height = space_height * ROWS as u32;
+    let cell_pixel_size = CellPixelSize {
+        width: space_width as u16,
+        height: space_height as u16,
+    };
+    let client_shell_layer = match options.client_shell_layer(rows, columns, cell_pixel_size) {
+        Some(Ok(layer)) => Some(layer),
+        Some(Err(err)) => {
+            eprintln!("Couldn't attach to the session: {}", err);
+            process::exit(1);
+        }
+        None => None,
+    };
+
+    let window_width = space_width * columns as u32;
+    let window_height = space_height * rows as u32;
 
     let (win_width, _) = canvas.window().size();
     let (drw_width, _) = canvas.window().drawable_size();
@@ -86,7 +150,53 @@ pub fn main() {
     canvas.present();
     let mut event_pump = sdl_context.event_pump().unwrap();
 
-    let mut tty = TtyState::new(COLUMNS, ROWS);
+    // Scripting the window is a nice extra, so we carry on without it if we have to
+    let control_server = match options.control_server() {
+        Some(Ok(server)) => Some(server),
+        Some(Err(err)) => {
+            println!("Couldn't listen for velocity-ctl: {}", err);
+            None
+        }
+        None => None,
+    };
+    let mut spawn_config = options.spawn_config();
+    spawn_config.listen_socket = control_server
+        .as_ref()
+        .map(|server| server.path().to_path_buf());
+
+    let shell_layer: Box<dyn ShellLayer> =
+        match (replay_shell_layer, serial_shell_layer, client_shell_layer) {
+            (Some(replay_shell_layer), _, _) => Box::new(replay_shell_layer),
+            (_, Some(serial_shell_layer), _) => Box::new(serial_shell_layer),
+            (_, _, Some(client_shell_layer)) => Box::new(client_shell_layer),
+            (None, None, None) => {
+                // Reading on a background thread means reading never blocks. vsync paces our frames.
+                let shell_layer = get_shell_layer(rows, columns, cell_pixel_size, &spawn_config);
+                Box::new(ThreadedShellLayer::new(shell_layer, None))
+            }
+        };
+    let mut tty = TtyState::with_shell_layer(columns, rows, shell_layer);
+    tty.set_local_echo(options.local_echo);
+    if let Some(record_path) = &options.record {
+        if let ControlReply::Error(message) = tty.start_recording_and_report(record_path) {
+            println!("{}", message);
+        }
+    }
+    if let Some(log_config) = options.session_log_config() {
+        match tty.start_logging(log_config) {
+            Ok(path) => println!("Logging the session to {}", path.display()),
+            Err(err) => println!("Couldn't start the session log: {}", err),
+        }
+    }
+    if let Some(group) = &options.broadcast_group {
+        if let ControlReply::Error(message) =
+            tty.join_broadcast_group(group, control_server.as_ref())
+        {
+            println!("{}", message);
+        }
+    }
+    let mut exit_status_printed = false;
+    let mut window_title = base_title.clone();
     'running: loop {
         canvas.set_draw_color(Color::RGB(0, 0, 0));
         canvas.clear();
@@ -109,6 +219,21 @@ pub fn main() {
                         "Keycode: {:?}, Scancode: {:?}, Keymod: {:?}, Repeat: {:?}",
                         keycode, scancode, keymod, repeat
                     );
+                    let ctrl = keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD);
+                    let shift = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+                    if ctrl && shift && keycode == Some(Keycode::R) {
+                        tty.toggle_recording();
+                        continue;
+                    }
+                    if ctrl && shift && keycode == Some(Keycode::B) {
+                        let group = options.broadcast_group.as_deref();
+                        tty.toggle_broadcast_group(group, control_server.as_ref());
+                        continue;
+                    }
+                    if ctrl && shift && keycode == Some(Keycode::X) {
+                        tty.toggle_broadcast_excluded();
+                        continue;
+                    }
                     if keycode == Some(Keycode::Backspace) {
                         tty.write(&[8]);
                     }
@@ -120,19 +245,58 @@ pub fn main() {
             }
         }
 
+        while let Some(request) = control_server
+            .as_ref()
+            .and_then(|server| server.next_request())
+        {
+            let reply = match &request.command {
+                ControlCommand::Resize { rows, cols } => {
+                    canvas
+                        .window_mut()
+                        .set_size(
+                            ((space_width * *cols as u32) as f32 / dpi_multiplier) as u32,
+                            ((space_height * *rows as u32) as f32 / dpi_multiplier) as u32,
+                        )
+                        .unwrap();
+                    tty.resized(*rows, *cols, cell_pixel_size);
+                    ControlReply::Done
+                }
+                ControlCommand::JoinBroadcastGroup(group) => {
+                    tty.join_broadcast_group(group, control_server.as_ref())
+                }
+                ControlCommand::SetTitle(title) => {
+                    base_title = title.clone();
+                    ControlReply::Done
+                }
+                command => command.apply_to_tty(&mut tty),
+            };
+            request.reply(reply);
+        }
+
         tty.read();
+        if let Some(exit_status) = tty.child_exit_status {
+            if !options.hold {
+                break 'running;
+            }
+            if !exit_status_printed {
+                tty.print_local_text(&format!("\r\n{}", exit_status));
+                exit_status_printed = true;
+            }
+        }
 
-        for i in 0..tty.size.rows {
-            let row_id = tty.scrollback_start + i;
+        let title = tty.window_title(&base_title);
+        if title != window_title {
+            canvas.window_mut().set_title(&title).unwrap();
+            window_title = title;
+        }
 
+        for i in 0..tty.size.rows {
             // This line is blank as of yet
-            if tty.scrollback_buffer.len() <= row_id {
+            let Some(line) = tty.screen_line(i) else {
                 continue;
-            }
-
-            for l in 0..tty.scrollback_buffer[row_id].len() {
-                let letter = tty.scrollback_buffer[row_id][l];
+            };
 
+            for (l, &letter) in line.iter().enumerate() {
                 let mut fg_colour = terminal_colour_to_sdl_colour(
                     letter.style.foreground,
                     DefaultColourVersion::Foreground,
@@ -194,9 +358,10 @@ pub fn main() {
             }
         }
 
+        let cursor_pos = tty.displayed_cursor_pos();
         let cursor_rect = rect!(
-            tty.cursor_pos.x as u32 * space_width,
-            tty.cursor_pos.y as u32 * space_height,
+            cursor_pos.x as u32 * space_width,
+            cursor_pos.y as u32 * space_height,
             space_width,
             space_height
         );
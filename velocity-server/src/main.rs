@@ -0,0 +1,231 @@
+// velocity-server owns shells on behalf of Velocity windows, so that a shell keeps running when
+// its window is closed (or crashes). Windows attach to a named session with
+//   velocity --attach work
+// and any number of them can have the same session open at once. See server_protocol in
+// velocity-core for how they talk to us.
+use std::{
+    collections::HashMap,
+    env,
+    io::{self, ErrorKind},
+    net::Shutdown,
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::channel,
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use nix::{
+    fcntl::{open, OFlag},
+    sys::stat::Mode,
+    unistd::{dup2, fork, setsid, ForkResult},
+};
+use session::{start_session, Client, SessionCommand, Sessions};
+use velocity_core::runtime_directory::bind_private_socket;
+use velocity_core::server_protocol::{default_socket_path, ClientMessage, ServerMessage};
+
+mod session;
+
+const USAGE: &str = "Usage: velocity-server [OPTIONS]
+
+Keeps shells running in the background for Velocity windows to attach to, with
+  velocity --attach NAME
+
+Options:
+  --socket PATH     Listen here instead of $VELOCITY_SERVER_SOCKET or
+                    $XDG_RUNTIME_DIR/velocity-server.sock
+  --foreground      Don't detach from the terminal, and log to it
+  -h, --help        Show this message";
+
+static NEXT_CLIENT_ID: AtomicUsize = AtomicUsize::new(1);
+
+fn main() {
+    let mut socket_path = None;
+    let mut foreground = false;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--socket" => match args.next() {
+                Some(path) => socket_path = Some(path.into()),
+                None => {
+                    eprintln!("--socket needs a value\n\n{}", USAGE);
+                    process::exit(2);
+                }
+            },
+            "--foreground" => foreground = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => {
+                eprintln!("Unknown option '{}'\n\n{}", arg, USAGE);
+                process::exit(2);
+            }
+        }
+    }
+
+    let socket_path = match socket_path.map(Ok).unwrap_or_else(default_socket_path) {
+        Ok(socket_path) => socket_path,
+        Err(err) => {
+            eprintln!("Couldn't find somewhere safe to listen: {}", err);
+            process::exit(1);
+        }
+    };
+
+    // We listen before detaching, so that problems are reported to whoever started us, and
+    // clients can connect as soon as we've returned
+    let listener = match listen(&socket_path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("Couldn't listen on {}: {}", socket_path.display(), err);
+            process::exit(1);
+        }
+    };
+
+    if foreground {
+        println!("Listening on {}", socket_path.display());
+    } else {
+        detach_from_terminal(&socket_path);
+    }
+
+    let sessions: Sessions = Arc::new(Mutex::new(HashMap::new()));
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let sessions = sessions.clone();
+                thread::spawn(move || handle_client(stream, sessions));
+            }
+            Err(err) => println!("Failed to accept a client: {}", err),
+        }
+    }
+}
+
+fn listen(path: &Path) -> io::Result<UnixListener> {
+    bind_private_socket(path).map_err(|err| match err.kind() {
+        ErrorKind::AddrInUse => {
+            io::Error::new(ErrorKind::AddrInUse, "velocity-server is already running")
+        }
+        _ => err,
+    })
+}
+
+// The usual daemon dance. We fork so that whoever started us (eg. a shell) gets control back, and
+// start a new session so that closing that terminal doesn't send us SIGHUP.
+fn detach_from_terminal(socket_path: &Path) {
+    // Safe because we haven't started any other threads yet
+    match unsafe { fork() }.expect("Failed to fork") {
+        ForkResult::Parent { child } => {
+            println!(
+                "velocity-server is running as process {}, listening on {}",
+                child,
+                socket_path.display()
+            );
+            process::exit(0);
+        }
+        ForkResult::Child => {}
+    }
+    setsid().expect("Failed to start a new session");
+
+    // There's no terminal to log to any more. println! would panic once it's gone.
+    let dev_null =
+        open("/dev/null", OFlag::O_RDWR, Mode::empty()).expect("Failed to open /dev/null");
+    for fd in 0..=2 {
+        dup2(dev_null, fd).expect("Failed to redirect stdio to /dev/null");
+    }
+}
+
+fn handle_client(mut stream: UnixStream, sessions: Sessions) {
+    let request = match ClientMessage::read_from(&mut stream) {
+        Ok(Some(ClientMessage::Attach(request))) => request,
+        // Every client has to start by attaching
+        Ok(_) => return,
+        Err(err) => {
+            println!("Failed to read a client's attach request: {}", err);
+            return;
+        }
+    };
+    let read_only = request.read_only;
+    let (rows, cols, cell_pixel_size) = (request.rows, request.cols, request.cell_pixel_size);
+
+    let session = {
+        let mut sessions_by_name = sessions.lock().unwrap();
+        match sessions_by_name.get(&request.session_name) {
+            Some(session) => session.clone(),
+            None if read_only => {
+                let message = format!("There's no session called '{}'", request.session_name);
+                let _ = ServerMessage::Error(message).write_to(&mut stream);
+                return;
+            }
+            None => {
+                let name = request.session_name.clone();
+                let session = start_session(request, sessions.clone());
+                sessions_by_name.insert(name, session.clone());
+                session
+            }
+        }
+    };
+
+    // Writing happens on its own thread, so that a client that isn't reading can't hold up the
+    // session. When the session is done with us, we hang up, which also ends the loop below.
+    let (sender, receiver) = channel::<ServerMessage>();
+    let mut writer = stream
+        .try_clone()
+        .expect("Failed to clone a client's socket");
+    thread::spawn(move || {
+        for message in receiver {
+            if message.write_to(&mut writer).is_err() {
+                break;
+            }
+        }
+        let _ = writer.shutdown(Shutdown::Both);
+    });
+
+    let id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+    let client = Client {
+        id,
+        read_only,
+        sender: sender.clone(),
+    };
+    // We don't keep a sender ourselves, or the writer thread would never finish
+    drop(sender);
+    let attach = SessionCommand::Attach {
+        client,
+        rows,
+        cols,
+        cell_pixel_size,
+    };
+    if session.send(attach).is_err() {
+        // The session ended just as we found it, and took the writer's sender with it
+        return;
+    }
+
+    loop {
+        let command = match ClientMessage::read_from(&mut stream) {
+            Ok(Some(ClientMessage::Input(data))) => SessionCommand::Input(data),
+            Ok(Some(ClientMessage::Resize {
+                rows,
+                cols,
+                cell_pixel_size,
+            })) => SessionCommand::Resize {
+                rows,
+                cols,
+                cell_pixel_size,
+            },
+            Ok(Some(ClientMessage::Attach(_))) => continue,
+            Ok(None) | Err(_) => break,
+        };
+        // Viewers can watch, but not type or resize
+        if read_only {
+            continue;
+        }
+        if session.send(command).is_err() {
+            break;
+        }
+    }
+    let _ = session.send(SessionCommand::Detach(id));
+}
@@ -0,0 +1,233 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    sync::{
+        mpsc::{channel, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use velocity_core::server_protocol::{AttachRequest, ServerMessage};
+use velocity_core::shell_layer::{
+    get_shell_layer, threaded::ThreadedShellLayer, CellPixelSize, ChildExitStatus, ShellLayer,
+    ShellLayerEvent, Signal,
+};
+use velocity_core::tty::TtyState;
+
+// We're woken up whenever the shell prints something, but not when it exits, so this is how
+// long it can take us to notice that
+const EXIT_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// Every running session, by name
+pub type Sessions = Arc<Mutex<HashMap<String, Sender<SessionCommand>>>>;
+
+// Each session runs on its own thread, which owns its TtyState. Everything else talks to it
+// with these.
+pub enum SessionCommand {
+    Attach {
+        client: Client,
+        rows: usize,
+        cols: usize,
+        cell_pixel_size: CellPixelSize,
+    },
+    Input(Vec<u8>),
+    Resize {
+        rows: usize,
+        cols: usize,
+        cell_pixel_size: CellPixelSize,
+    },
+    // The client with this ID has gone
+    Detach(usize),
+    // The shell has printed something
+    OutputReady,
+}
+
+pub struct Client {
+    pub id: usize,
+    pub read_only: bool,
+    // Messages sent here are written to the client's socket by its own thread, so a slow client
+    // can't hold the session up
+    pub sender: Sender<ServerMessage>,
+}
+
+// Launches the session's program at the size the first client asked for. The session removes
+// itself from sessions when the program exits.
+pub fn start_session(request: AttachRequest, sessions: Sessions) -> Sender<SessionCommand> {
+    let (sender, receiver) = channel();
+    let output_ready_sender = sender.clone();
+    let name = request.session_name.clone();
+
+    thread::Builder::new()
+        .name(format!("velocity-session-{}", name))
+        .spawn(move || {
+            let shell_layer = get_shell_layer(
+                request.rows,
+                request.cols,
+                request.cell_pixel_size,
+                &request.spawn_config,
+            );
+            let on_data_ready = move || {
+                let _ = output_ready_sender.send(SessionCommand::OutputReady);
+            };
+            let shell_layer = ThreadedShellLayer::new(shell_layer, Some(Box::new(on_data_ready)));
+            let output = Arc::new(Mutex::new(vec![]));
+            let shell_layer = TeeShellLayer {
+                inner: Box::new(shell_layer),
+                output: output.clone(),
+            };
+            let mut tty =
+                TtyState::with_shell_layer(request.cols, request.rows, Box::new(shell_layer));
+            let mut clients: Vec<Client> = vec![];
+            println!("Started session '{}'", name);
+
+            while tty.child_exit_status.is_none() {
+                match receiver.recv_timeout(EXIT_CHECK_INTERVAL) {
+                    Ok(SessionCommand::Attach {
+                        client,
+                        rows,
+                        cols,
+                        cell_pixel_size,
+                    }) => {
+                        // The newest window that can type decides how big the session is
+                        let size_changed = tty.size.rows != rows || tty.size.cols != cols;
+                        if !client.read_only && size_changed {
+                            tty.resized(rows, cols, cell_pixel_size);
+                        }
+                        // Everything that's been applied to tty has already been sent to the
+                        // other clients, so the repaint lines up with what comes after it
+                        let _ = client.sender.send(ServerMessage::Attached);
+                        let _ = client
+                            .sender
+                            .send(ServerMessage::Output(tty.repaint_sequence()));
+                        println!("Client {} attached to '{}'", client.id, name);
+                        clients.push(client);
+                    }
+                    Ok(SessionCommand::Input(data)) => tty.write(&data),
+                    Ok(SessionCommand::Resize {
+                        rows,
+                        cols,
+                        cell_pixel_size,
+                    }) => tty.resized(rows, cols, cell_pixel_size),
+                    Ok(SessionCommand::Detach(id)) => {
+                        println!("Client {} detached from '{}'", id, name);
+                        clients.retain(|client| client.id != id);
+                    }
+                    Ok(SessionCommand::OutputReady) => {}
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+
+                tty.read();
+                let output: Vec<u8> = output.lock().unwrap().drain(..).collect();
+                if !output.is_empty() {
+                    // Clients that have gone are forgotten about here, if they didn't detach
+                    clients.retain(|client| {
+                        client
+                            .sender
+                            .send(ServerMessage::Output(output.clone()))
+                            .is_ok()
+                    });
+                }
+            }
+
+            let status = tty.child_exit_status.unwrap_or(ChildExitStatus::Unknown);
+            println!("Session '{}' has ended: {}", name, status);
+            sessions.lock().unwrap().remove(&name);
+            for client in &clients {
+                let _ = client.sender.send(ServerMessage::Exited(status));
+            }
+            // Someone might have attached while we were finishing up
+            while let Ok(command) = receiver.try_recv() {
+                if let SessionCommand::Attach { client, .. } = command {
+                    let _ = client.sender.send(ServerMessage::Error(format!(
+                        "The session '{}' has just ended",
+                        name
+                    )));
+                }
+            }
+        })
+        .expect("Failed to spawn a session thread");
+
+    sender
+}
+
+// Passes everything through to the real shell layer, but keeps a copy of the output so that
+// the session can send it on to its clients
+struct TeeShellLayer {
+    inner: Box<dyn ShellLayer>,
+    output: Arc<Mutex<Vec<u8>>>,
+}
+
+impl ShellLayer for TeeShellLayer {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read_count = self.inner.read(buffer)?;
+        self.output
+            .lock()
+            .unwrap()
+            .extend_from_slice(&buffer[..read_count]);
+        Ok(read_count)
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        self.inner.write(data);
+    }
+
+    fn write_response(&mut self, data: &[u8]) {
+        self.inner.write_response(data);
+    }
+
+    fn resized(&mut self, new_rows: usize, new_cols: usize, cell_pixel_size: CellPixelSize) {
+        self.inner.resized(new_rows, new_cols, cell_pixel_size);
+    }
+
+    fn next_event(&mut self) -> Option<ShellLayerEvent> {
+        self.inner.next_event()
+    }
+
+    fn has_data_ready(&mut self) -> bool {
+        self.inner.has_data_ready()
+    }
+
+    fn wait_for_data(&mut self, timeout: Duration) -> bool {
+        self.inner.wait_for_data(timeout)
+    }
+
+    fn flush_pending_writes(&mut self) {
+        self.inner.flush_pending_writes();
+    }
+
+    fn pending_write_bytes(&self) -> usize {
+        self.inner.pending_write_bytes()
+    }
+
+    fn signal_foreground_process_group(&mut self, signal: Signal) -> nix::Result<()> {
+        self.inner.signal_foreground_process_group(signal)
+    }
+
+    fn shell_pid(&self) -> Option<i32> {
+        self.inner.shell_pid()
+    }
+
+    fn tty_path(&self) -> Option<PathBuf> {
+        self.inner.tty_path()
+    }
+
+    fn is_echo_disabled(&self) -> bool {
+        self.inner.is_echo_disabled()
+    }
+
+    fn foreground_process_name(&self) -> Option<String> {
+        self.inner.foreground_process_name()
+    }
+
+    fn foreground_process_argv(&self) -> Option<Vec<String>> {
+        self.inner.foreground_process_argv()
+    }
+
+    fn foreground_process_cwd(&self) -> Option<PathBuf> {
+        self.inner.foreground_process_cwd()
+    }
+}
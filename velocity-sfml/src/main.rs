@@ -1,8 +1,15 @@
 use std::ops::Div;
+use std::time::Duration;
+use std::{env, process};
 
 use colours::terminal_colour_to_sfml_colour;
 use colours::DefaultColourVersion;
+use velocity_core::command_line::{CommandLineOptions, USAGE};
 use velocity_core::constants::special_characters::ESCAPE;
+use velocity_core::constants::FD_POLL_TIMEOUT_MS;
+use velocity_core::control::{ControlCommand, ControlReply};
+use velocity_core::shell_layer::threaded::ThreadedShellLayer;
+use velocity_core::shell_layer::{get_shell_layer, CellPixelSize, ShellLayer};
 use velocity_core::tty::TtyState;
 
 use phf::phf_map;
@@ -14,6 +21,7 @@ mod colours;
 
 const FONT_SIZE: u32 = 24;
 
+// The size of the window, unless we're told otherwise with --cols and --rows
 const COLUMNS: usize = 80;
 const ROWS: usize = 25;
 
@@ -26,38 +34,142 @@ static SPECIAL_KEYS: phf::Map<u8, u8> = phf_map! {
 };
 
 fn main() {
+    let options = match CommandLineOptions::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+    if options.show_help {
+        println!("{}", USAGE);
+        return;
+    }
+    // We open a recording before the window, so that the window can be the size it was made at
+    let replay_shell_layer = match options.replay_shell_layer() {
+        Some(Ok(layer)) => Some(layer),
+        Some(Err(err)) => {
+            eprintln!("Couldn't open the recording: {}", err);
+            process::exit(1);
+        }
+        None => None,
+    };
+    let serial_shell_layer = match options.serial_shell_layer() {
+        Some(Ok(layer)) => Some(layer),
+        Some(Err(err)) => {
+            eprintln!("Couldn't open the serial device: {}", err);
+            process::exit(1);
+        }
+        None => None,
+    };
+    let recorded_size = replay_shell_layer.as_ref().and_then(|layer| layer.size());
+    let columns = options
+        .cols
+        .or(recorded_size.map(|(cols, _)| cols))
+        .unwrap_or(COLUMNS);
+    let rows = options
+        .rows
+        .or(recorded_size.map(|(_, rows)| rows))
+        .unwrap_or(ROWS);
+    let mut base_title = options.window_title();
+
     // TODO: Less font hardcoding. Eg, some Linux users might have their fonts in a different
     //   place. Can we ask the system where fonts are?
     #[cfg(target_os = "macos")]
-    let font_path = "/System/Library/Fonts/Monaco.ttf";
+    let default_font_path = "/System/Library/Fonts/Monaco.ttf";
     #[cfg(target_os = "linux")]
-    let font_path = "/usr/share/fonts/truetype/noto/NotoMono-Regular.ttf";
+    let default_font_path = "/usr/share/fonts/truetype/noto/NotoMono-Regular.ttf";
+    let font_path = options.font.clone().unwrap_or(default_font_path.into());
 
-    let font = Font::from_file(font_path).unwrap();
+    let Some(font) = Font::from_file(&font_path.to_string_lossy()) else {
+        eprintln!("Couldn't load a font from {}", font_path.display());
+        process::exit(1);
+    };
     // TODO: Loop through all the characters widths and choose the largest - or the
     //   space advance if that's bigger
     let font_width = font.glyph(32, FONT_SIZE, false, 0.).advance();
     // This doesn't seem to be defined anywhere concrete in SFML at all, but I've checked
     // a few fonts and this is coincidentally right for everything I've tested
     let font_height = FONT_SIZE as f32 * 1.2;
+    let cell_pixel_size = CellPixelSize {
+        width: font_width.round() as u16,
+        height: font_height.round() as u16,
+    };
+    let client_shell_layer = match options.client_shell_layer(rows, columns, cell_pixel_size) {
+        Some(Ok(layer)) => Some(layer),
+        Some(Err(err)) => {
+            eprintln!("Couldn't attach to the session: {}", err);
+            process::exit(1);
+        }
+        None => None,
+    };
 
-    let window_width = (font_width * COLUMNS as f32) as u32;
-    let window_height = (font_height * ROWS as f32) as u32;
+    let window_width = (font_width * columns as f32) as u32;
+    let window_height = (font_height * rows as f32) as u32;
 
     let style = Style::RESIZE | Style::TITLEBAR | Style::CLOSE;
     let mut window = RenderWindow::new(
         (window_width, window_height),
-        "Velocity",
+        &base_title,
         style,
         &Default::default(),
     );
+    // Waiting for output paces our frames while the shell is running, but once it's exited (eg.
+    // with --hold) there's nothing to wait for, and we'd draw as fast as we could
+    window.set_framerate_limit(60);
 
     // Running these two calls here shows our window basically immediately instead of
     // waiting for the shell program to spawn. Makes startup time feel more responsive.
     window.display();
     window.poll_event();
 
-    let mut tty = TtyState::new(COLUMNS, ROWS);
+    // Scripting the window is a nice extra, so we carry on without it if we have to
+    let control_server = match options.control_server() {
+        Some(Ok(server)) => Some(server),
+        Some(Err(err)) => {
+            println!("Couldn't listen for velocity-ctl: {}", err);
+            None
+        }
+        None => None,
+    };
+    let mut spawn_config = options.spawn_config();
+    spawn_config.listen_socket = control_server
+        .as_ref()
+        .map(|server| server.path().to_path_buf());
+
+    let shell_layer: Box<dyn ShellLayer> =
+        match (replay_shell_layer, serial_shell_layer, client_shell_layer) {
+            (Some(replay_shell_layer), _, _) => Box::new(replay_shell_layer),
+            (_, Some(serial_shell_layer), _) => Box::new(serial_shell_layer),
+            (_, _, Some(client_shell_layer)) => Box::new(client_shell_layer),
+            (None, None, None) => {
+                // Reading on a background thread means we don't have to block in poll() between frames
+                let shell_layer = get_shell_layer(rows, columns, cell_pixel_size, &spawn_config);
+                Box::new(ThreadedShellLayer::new(shell_layer, None))
+            }
+        };
+    let mut tty = TtyState::with_shell_layer(columns, rows, shell_layer);
+    tty.set_local_echo(options.local_echo);
+    if let Some(record_path) = &options.record {
+        if let ControlReply::Error(message) = tty.start_recording_and_report(record_path) {
+            println!("{}", message);
+        }
+    }
+    if let Some(log_config) = options.session_log_config() {
+        match tty.start_logging(log_config) {
+            Ok(path) => println!("Logging the session to {}", path.display()),
+            Err(err) => println!("Couldn't start the session log: {}", err),
+        }
+    }
+    if let Some(group) = &options.broadcast_group {
+        if let ControlReply::Error(message) =
+            tty.join_broadcast_group(group, control_server.as_ref())
+        {
+            println!("{}", message);
+        }
+    }
+    let mut exit_status_printed = false;
+    let mut window_title = base_title.clone();
     loop {
         while let Some(ev) = window.poll_event() {
             match ev {
@@ -74,16 +186,30 @@ fn main() {
                     let new_rows = (new_size.y / font_height) as usize;
                     let new_cols = (new_size.x / font_width) as usize;
                     println!("Window resized to {}x{}", new_cols, new_rows);
-                    tty.resized(new_rows, new_cols);
+                    tty.resized(new_rows, new_cols, cell_pixel_size);
                 }
                 // NOTE: "system" is the Super key
                 Event::KeyPressed {
                     code,
                     alt: _alt,
                     ctrl,
-                    shift: _shift,
+                    shift,
                     system,
                 } => {
+                    if ctrl && shift && code == Key::R {
+                        tty.toggle_recording();
+                        continue;
+                    }
+                    if ctrl && shift && code == Key::B {
+                        let group = options.broadcast_group.as_deref();
+                        tty.toggle_broadcast_group(group, control_server.as_ref());
+                        continue;
+                    }
+                    if ctrl && shift && code == Key::X {
+                        tty.toggle_broadcast_excluded();
+                        continue;
+                    }
+
                     let key_number = code as isize;
 
                     let middle_byte = if tty.application_cursor_keys {
@@ -129,22 +255,63 @@ fn main() {
             }
         }
 
+        while let Some(request) = control_server
+            .as_ref()
+            .and_then(|server| server.next_request())
+        {
+            let reply = match &request.command {
+                ControlCommand::Resize { rows, cols } => {
+                    let size = (
+                        (font_width * *cols as f32) as u32,
+                        (font_height * *rows as f32) as u32,
+                    );
+                    // This sends us a Resized event, which resizes the tty
+                    window.set_size(size);
+                    ControlReply::Done
+                }
+                ControlCommand::JoinBroadcastGroup(group) => {
+                    tty.join_broadcast_group(group, control_server.as_ref())
+                }
+                ControlCommand::SetTitle(title) => {
+                    base_title = title.clone();
+                    ControlReply::Done
+                }
+                command => command.apply_to_tty(&mut tty),
+            };
+            request.reply(reply);
+        }
+
         // TODO: If the text has a certain background colour, and then the screen is cleared,
         //   we should change the whole background colour.
         window.clear(Color::BLACK);
 
+        // Sleep until the shell prints something, or it's time for a frame anyway
+        tty.wait_for_output(Duration::from_millis(FD_POLL_TIMEOUT_MS as u64));
         tty.read();
-        for i in 0..tty.size.rows {
-            let row_id = tty.scrollback_start + i;
+        if let Some(exit_status) = tty.child_exit_status {
+            if !options.hold {
+                window.close();
+                return;
+            }
+            if !exit_status_printed {
+                tty.print_local_text(&format!("\r\n{}", exit_status));
+                exit_status_printed = true;
+            }
+        }
+
+        let title = tty.window_title(&base_title);
+        if title != window_title {
+            window.set_title(&title);
+            window_title = title;
+        }
 
+        for i in 0..tty.size.rows {
             // This line is blank as of yet
-            if tty.scrollback_buffer.len() <= row_id {
+            let Some(line) = tty.screen_line(i) else {
                 continue;
-            }
-
-            for l in 0..tty.scrollback_buffer[row_id].len() {
-                let letter = tty.scrollback_buffer[row_id][l];
+            };
 
+            for (l, &letter) in line.iter().enumerate() {
                 let char_pos = Vector2f::new(l as f32 * font_width, i as f32 * font_height);
 
                 let mut fg_colour = terminal_colour_to_sfml_colour(
@@ -200,12 +367,13 @@ fn main() {
         // TODO: Does text foreground colour colour the cursor?
         //   If it does, we can make TtyState's text_style public
         if tty.cursor_visible {
+            let cursor_pos = tty.displayed_cursor_pos();
             let mut cursor_block =
                 RectangleShape::with_size(Vector2f::new(font_width, font_height));
             cursor_block.set_fill_color(Color::WHITE);
             cursor_block.set_position(Vector2f::new(
-                tty.cursor_pos.x as f32 * font_width,
-                tty.cursor_pos.y as f32 * font_height,
+                cursor_pos.x as f32 * font_width,
+                cursor_pos.y as f32 * font_height,
             ));
             window.draw(&cursor_block);
         }